@@ -0,0 +1,60 @@
+use std::{
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+use tokio_util::sync::CancellationToken;
+
+/// Cancels in-flight requests cooperatively, e.g. once the caller of your service disconnects. Set
+/// it as [`crate::How::cancellation`] and cancel its token. The request then fails with
+/// [`crate::Error::Cancelled`].
+///
+/// Cancellations compare equal if they are clones of each other, so [`crate::How`] can stay
+/// comparable.
+#[derive(Clone, Debug)]
+pub struct Cancellation {
+    token: CancellationToken,
+    /// Identity of this cancellation, shared by its clones.
+    id: Arc<()>,
+}
+
+impl Cancellation {
+    /// Cancellation with a fresh token.
+    pub fn new() -> Self {
+        CancellationToken::new().into()
+    }
+
+    /// Token which cancels the request once it is cancelled. Clone it to cancel from elsewhere.
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+}
+
+impl Default for Cancellation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<CancellationToken> for Cancellation {
+    fn from(token: CancellationToken) -> Self {
+        Self {
+            token,
+            id: Arc::new(()),
+        }
+    }
+}
+
+impl PartialEq for Cancellation {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.id, &other.id)
+    }
+}
+
+impl Eq for Cancellation {}
+
+impl Hash for Cancellation {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.id).hash(state);
+    }
+}
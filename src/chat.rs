@@ -1,17 +1,23 @@
 use core::str;
-use std::borrow::Cow;
+use std::{borrow::Cow, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    code_block::{extract_code_blocks, CodeBlock},
+    json_schema,
     logprobs::{Logprob, Logprobs},
-    Stopping, StreamTask, Task,
+    prompt_template, Client, Error, FinishReason, How, Stopping, StreamTask, Task,
 };
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Message<'a> {
     pub role: Cow<'a, str>,
     pub content: Cow<'a, str>,
+    /// Id of the tool call this message answers. Only set for messages with the role `tool`, see
+    /// [`Message::tool_result`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<Cow<'a, str>>,
 }
 
 impl<'a> Message<'a> {
@@ -19,6 +25,7 @@ impl<'a> Message<'a> {
         Self {
             role: role.into(),
             content: content.into(),
+            tool_call_id: None,
         }
     }
     pub fn user(content: impl Into<Cow<'a, str>>) -> Self {
@@ -30,6 +37,42 @@ impl<'a> Message<'a> {
     pub fn system(content: impl Into<Cow<'a, str>>) -> Self {
         Self::new("system", content)
     }
+
+    /// Feeds the output of a tool back to the model. `tool_call_id` is the id of the tool call
+    /// the model has requested, `content` the output of the tool, e.g. serialized as JSON.
+    pub fn tool_result(
+        tool_call_id: impl Into<Cow<'a, str>>,
+        content: impl Into<Cow<'a, str>>,
+    ) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.into()),
+            ..Self::new("tool", content)
+        }
+    }
+
+    /// Copies borrowed contents, so the message no longer depends on the lifetime of its inputs.
+    pub fn into_owned(self) -> Message<'static> {
+        Message {
+            role: Cow::Owned(self.role.into_owned()),
+            content: Cow::Owned(self.content.into_owned()),
+            tool_call_id: self.tool_call_id.map(|id| Cow::Owned(id.into_owned())),
+        }
+    }
+
+    /// A message borrowing the contents of this one, without allocating.
+    pub fn borrow(&self) -> Message<'_> {
+        Message {
+            role: Cow::Borrowed(&self.role),
+            content: Cow::Borrowed(&self.content),
+            tool_call_id: self.tool_call_id.as_deref().map(Cow::Borrowed),
+        }
+    }
+
+    /// Length of the content in bytes. Useful to estimate the size of a chat prompt, e.g. before
+    /// tokenizing it.
+    pub fn content_len(&self) -> usize {
+        self.content.len()
+    }
 }
 
 pub struct TaskChat<'a> {
@@ -42,6 +85,118 @@ pub struct TaskChat<'a> {
     /// Use this to control the logarithmic probabilities you want to have returned. This is useful
     /// to figure out how likely it had been that this specific token had been sampled.
     pub logprobs: Logprobs,
+    /// Constrains the format of the message generated by the model, e.g. to JSON following a
+    /// schema.
+    pub response_format: Option<ResponseFormat>,
+    /// Opt in to validate the content of the response against the [`Self::response_format`]
+    /// locally. The content must be a JSON object for [`ResponseFormat::JsonObject`] and conform to
+    /// the schema for [`ResponseFormat::JsonSchema`]. Violations are reported as
+    /// [`Error::SchemaViolation`], for streams once the last chunk has been received.
+    pub validate_response: bool,
+    /// Identifies the end user on whose behalf the request is made, e.g. a hashed user name. Lets
+    /// the server attribute and monitor usage and abuse per end user. Sent as `user`.
+    pub user_id: Option<String>,
+    /// Name of the field [`Stopping::maximum_tokens`] is sent as. Newer OpenAI compatible servers
+    /// expect `max_completion_tokens` and may silently ignore `max_tokens`.
+    pub max_tokens_field: MaxTokensField,
+}
+
+/// Owned counterpart of [`TaskChat`], which can be deserialized. Use it to save the configuration
+/// of a request to disk and replay it later, e.g. for reproducible experiments. Missing fields are
+/// deserialized with their default values.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct OwnedTaskChat {
+    /// See [`TaskChat::messages`].
+    pub messages: Vec<Message<'static>>,
+    /// See [`Stopping::maximum_tokens`].
+    pub maximum_tokens: Option<u32>,
+    /// See [`Stopping::stop_sequences`].
+    pub stop_sequences: Vec<String>,
+    /// See [`TaskChat::sampling`].
+    pub sampling: ChatSampling,
+    /// See [`TaskChat::logprobs`].
+    pub logprobs: Logprobs,
+    /// See [`TaskChat::response_format`].
+    pub response_format: Option<ResponseFormat>,
+    /// See [`TaskChat::validate_response`].
+    pub validate_response: bool,
+    /// See [`TaskChat::user_id`].
+    pub user_id: Option<String>,
+    /// See [`TaskChat::max_tokens_field`].
+    pub max_tokens_field: MaxTokensField,
+}
+
+impl OwnedTaskChat {
+    /// The stop sequences borrowed as string slices, as required by [`Self::as_task`].
+    pub fn stop_sequences(&self) -> Vec<&str> {
+        self.stop_sequences.iter().map(String::as_str).collect()
+    }
+
+    /// Borrows a task from this one. `stop_sequences` are usually obtained from
+    /// [`Self::stop_sequences`], since [`Stopping`] expects a slice of string slices.
+    pub fn as_task<'a>(&'a self, stop_sequences: &'a [&'a str]) -> TaskChat<'a> {
+        TaskChat {
+            messages: self.messages.iter().map(Message::borrow).collect(),
+            stopping: Stopping {
+                maximum_tokens: self.maximum_tokens,
+                stop_sequences,
+            },
+            sampling: self.sampling.clone(),
+            logprobs: self.logprobs,
+            response_format: self.response_format.clone(),
+            validate_response: self.validate_response,
+            user_id: self.user_id.clone(),
+            max_tokens_field: self.max_tokens_field,
+        }
+    }
+}
+
+impl From<&TaskChat<'_>> for OwnedTaskChat {
+    fn from(task: &TaskChat<'_>) -> Self {
+        let TaskChat {
+            messages,
+            stopping:
+                Stopping {
+                    maximum_tokens,
+                    stop_sequences,
+                },
+            sampling,
+            logprobs,
+            response_format,
+            validate_response,
+            user_id,
+            max_tokens_field,
+        } = task;
+        Self {
+            messages: messages
+                .iter()
+                .map(|message| message.borrow().into_owned())
+                .collect(),
+            maximum_tokens: *maximum_tokens,
+            stop_sequences: stop_sequences.iter().map(|&s| s.to_owned()).collect(),
+            sampling: sampling.clone(),
+            logprobs: *logprobs,
+            response_format: response_format.clone(),
+            validate_response: *validate_response,
+            user_id: user_id.clone(),
+            max_tokens_field: *max_tokens_field,
+        }
+    }
+}
+
+/// Name of the field the maximum number of tokens of a chat is sent as. See
+/// [`TaskChat::max_tokens_field`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaxTokensField {
+    /// `max_tokens`, as understood by the Aleph Alpha inference API.
+    #[default]
+    MaxTokens,
+    /// `max_completion_tokens`, which replaces `max_tokens` in newer OpenAI compatible APIs.
+    MaxCompletionTokens,
+    /// Send both fields, for servers which may understand either of them.
+    Both,
 }
 
 impl<'a> TaskChat<'a> {
@@ -59,24 +214,208 @@ impl<'a> TaskChat<'a> {
             sampling: ChatSampling::default(),
             stopping: Stopping::default(),
             logprobs: Logprobs::No,
+            response_format: None,
+            validate_response: false,
+            user_id: None,
+            max_tokens_field: MaxTokensField::MaxTokens,
         }
     }
 
+    /// Creates a new TaskChat from any iterator of messages, e.g. a filtered or mapped chat
+    /// history, without collecting it into a `Vec` first. All optional TaskChat attributes are left
+    /// unset.
+    pub fn with_message_iter(messages: impl IntoIterator<Item = Message<'a>>) -> Self {
+        Self::with_messages(messages.into_iter().collect())
+    }
+
     /// Pushes a new Message to this TaskChat.
     pub fn push_message(mut self, message: Message<'a>) -> Self {
         self.messages.push(message);
         self
     }
 
+    /// Prepends the system prompt recommended for the model. It is taken from the
+    /// `default_system_message` set by the prompt template in the model's
+    /// [`crate::ModelSettings`]. The settings are fetched once and cached by the client. The task
+    /// is left unchanged if it already starts with a system message, or if the model has no
+    /// template or its template sets no default system message. A template which can not be
+    /// evaluated is reported as [`Error::InvalidPromptTemplate`].
+    pub async fn with_default_system(
+        mut self,
+        client: &Client,
+        model: &str,
+        how: &How,
+    ) -> Result<Self, Error> {
+        if self
+            .messages
+            .first()
+            .is_some_and(|message| message.role == "system")
+        {
+            return Ok(self);
+        }
+        let Some(template) = client
+            .model_settings(model, how)
+            .await?
+            .and_then(|settings| settings.prompt_template)
+        else {
+            return Ok(self);
+        };
+        let system_prompt = prompt_template::default_system_prompt(&template)
+            .map_err(|reason| Error::InvalidPromptTemplate { reason })?;
+        if let Some(system_prompt) = system_prompt {
+            self.messages.insert(0, Message::system(system_prompt));
+        }
+        Ok(self)
+    }
+
+    /// Same task, with the partial answer of the assistant appended as a message. Used to continue
+    /// an interrupted stream. `received_tokens` are the tokens of the partial answer, they count
+    /// against [`Stopping::maximum_tokens`].
+    pub(crate) fn continuation(
+        &self,
+        partial_answer: String,
+        received_tokens: u32,
+    ) -> TaskChat<'a> {
+        let mut messages = self.messages.clone();
+        messages.push(Message::assistant(partial_answer));
+        TaskChat {
+            messages,
+            stopping: Stopping {
+                maximum_tokens: self
+                    .stopping
+                    .maximum_tokens
+                    .map(|maximum| maximum.saturating_sub(received_tokens)),
+                stop_sequences: self.stopping.stop_sequences,
+            },
+            sampling: self.sampling.clone(),
+            logprobs: self.logprobs,
+            response_format: self.response_format.clone(),
+            validate_response: self.validate_response,
+            user_id: self.user_id.clone(),
+            max_tokens_field: self.max_tokens_field,
+        }
+    }
+
+    /// Some models reject conversations with two consecutive messages of the same role, e.g. two
+    /// user messages in a row. This method merges the content of any consecutive messages with the
+    /// same role by a given separator. You can use an empty string for the separator if you want
+    /// to just concatenate them. See also [`crate::Prompt::join_consecutive_text_items`]. Tool
+    /// results are never merged, as each of them answers a different tool call.
+    pub fn merge_consecutive_same_role(&mut self, separator: &str) {
+        let mut merged: Vec<Message<'a>> = Vec::with_capacity(self.messages.len());
+        for message in self.messages.drain(..) {
+            match merged.last_mut() {
+                Some(last)
+                    if last.role == message.role
+                        && last.tool_call_id.is_none()
+                        && message.tool_call_id.is_none() =>
+                {
+                    last.content.to_mut().push_str(separator);
+                    last.content.to_mut().push_str(&message.content);
+                }
+                _ => merged.push(message),
+            }
+        }
+        self.messages = merged;
+    }
+
     /// Sets the maximum token attribute of this TaskChat.
     pub fn with_maximum_tokens(mut self, maximum_tokens: u32) -> Self {
         self.stopping.maximum_tokens = Some(maximum_tokens);
         self
     }
+
+    /// Sets the name of the field the maximum tokens are sent as. See [`Self::max_tokens_field`].
+    pub fn with_max_tokens_field(mut self, max_tokens_field: MaxTokensField) -> Self {
+        self.max_tokens_field = max_tokens_field;
+        self
+    }
+
+    /// Sets the response format of this TaskChat.
+    pub fn with_response_format(mut self, response_format: ResponseFormat) -> Self {
+        self.response_format = Some(response_format);
+        self
+    }
+
+    /// Sets the logprobs to be returned. See [`Self::logprobs`].
+    pub fn with_logprobs(mut self, logprobs: Logprobs) -> Self {
+        self.logprobs = logprobs;
+        self
+    }
+
+    /// Sets the end user on whose behalf the request is made. See [`Self::user_id`].
+    pub fn with_user_id(mut self, user_id: impl Into<String>) -> Self {
+        self.user_id = Some(user_id.into());
+        self
+    }
+
+    /// Validate the content of the response against the response format. See
+    /// [`Self::validate_response`].
+    pub fn with_response_validation(mut self) -> Self {
+        self.validate_response = true;
+        self
+    }
+
+    /// The response format the content of the response is checked against, if
+    /// [`Self::validate_response`] is set.
+    pub(crate) fn format_to_validate(&self) -> Option<&ResponseFormat> {
+        self.response_format
+            .as_ref()
+            .filter(|_| self.validate_response)
+    }
+}
+
+/// Format of the message generated by the model.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Plain text. The default.
+    Text,
+    /// Any valid JSON object.
+    JsonObject,
+    /// JSON following the given schema.
+    JsonSchema { json_schema: JsonSchema },
+}
+
+impl ResponseFormat {
+    /// Checks that `content` is in this format.
+    pub(crate) fn check(&self, content: &str) -> Result<(), Error> {
+        let json_schema = match self {
+            ResponseFormat::Text => return Ok(()),
+            ResponseFormat::JsonObject => None,
+            ResponseFormat::JsonSchema { json_schema } => Some(json_schema),
+        };
+        let content: serde_json::Value =
+            serde_json::from_str(content).map_err(|e| Error::SchemaViolation {
+                reason: format!("Content is not valid JSON: {e}"),
+            })?;
+        match json_schema {
+            Some(json_schema) => json_schema::validate(&json_schema.schema, &content)
+                .map_err(|reason| Error::SchemaViolation { reason }),
+            None if content.is_object() => Ok(()),
+            None => Err(Error::SchemaViolation {
+                reason: format!("Content is not a JSON object: {content}"),
+            }),
+        }
+    }
+}
+
+/// A named JSON schema the response of the model should conform to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JsonSchema {
+    /// Name of the schema.
+    pub name: String,
+    /// The JSON schema itself.
+    pub schema: serde_json::Value,
+    /// Whether the model should strictly follow the schema.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
 }
 
 /// Sampling controls how the tokens ("words") are selected for the completion. This is different
 /// from [`crate::Sampling`], because it does **not** supprot the `top_k` parameter.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ChatSampling {
     /// A temperature encourages the model to produce less probable outputs ("be more creative").
     /// Values are expected to be between 0 and 1. Try high values for a more random ("creative")
@@ -135,6 +474,9 @@ pub struct ChatOutput {
     /// been set to [`crate::Logprobs::Sampled`] or [`crate::Logprobs::Top`].
     pub logprobs: Vec<Distribution>,
     pub usage: Usage,
+    /// Fields of the choice not known to this client, e.g. safety scores added by some
+    /// deployments.
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl ChatOutput {
@@ -149,15 +491,38 @@ impl ChatOutput {
             finish_reason,
             logprobs,
             usage,
+            extra: serde_json::Map::new(),
         }
     }
+
+    /// The reason why the model stopped generating new tokens, as typed enum.
+    pub fn typed_finish_reason(&self) -> FinishReason {
+        FinishReason::from(self.finish_reason.as_str())
+    }
+
+    /// `true` if the model stopped, because it reached [`crate::Stopping::maximum_tokens`] (or the
+    /// context size of the model). The message is likely cut off.
+    pub fn is_truncated(&self) -> bool {
+        self.typed_finish_reason() == FinishReason::Length
+    }
+
+    /// Code blocks fenced by triple backticks in the content of the message. See
+    /// [`crate::extract_code_blocks`].
+    pub fn extract_code_blocks(&self) -> Vec<CodeBlock> {
+        extract_code_blocks(&self.message.content)
+    }
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
 pub struct ResponseChoice {
+    #[serde(default)]
+    pub index: u32,
     pub message: Message<'static>,
     pub finish_reason: String,
     pub logprobs: Option<LogprobContent>,
+    /// Vendor specific fields, so they are not lost.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Deserialize, Debug, PartialEq, Default)]
@@ -166,7 +531,7 @@ pub struct LogprobContent {
 }
 
 /// Logprob information for a single token
-#[derive(Deserialize, Debug, PartialEq)]
+#[derive(Deserialize, Debug, PartialEq, Clone)]
 pub struct Distribution {
     // Logarithmic probability of the token returned in the completion
     #[serde(flatten)]
@@ -176,6 +541,25 @@ pub struct Distribution {
     pub top: Vec<Logprob>,
 }
 
+impl Distribution {
+    /// Logprob of the sampled token with single precision. See [`Logprob::logprob_f32`].
+    pub fn logprob_f32(&self) -> f32 {
+        self.sampled.logprob_f32()
+    }
+
+    /// The `n` most likely tokens, most likely first. Fewer if less than `n` top logprobs have been
+    /// requested.
+    pub fn top_n(&self, n: usize) -> &[Logprob] {
+        &self.top[..n.min(self.top.len())]
+    }
+
+    /// Whether the sampled token is among the [`Self::top`] tokens. `false` if no top logprobs
+    /// have been requested.
+    pub fn contains_sampled_in_top(&self) -> bool {
+        self.top.iter().any(|top| top.token == self.sampled.token)
+    }
+}
+
 #[derive(Deserialize, Debug, PartialEq)]
 pub struct ResponseChat {
     choices: Vec<ResponseChoice>,
@@ -191,6 +575,9 @@ struct ChatBody<'a> {
     /// Limits the number of tokens, which are generated for the completion.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub max_tokens: Option<u32>,
+    /// Same as `max_tokens`, under the name used by newer OpenAI compatible APIs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_completion_tokens: Option<u32>,
     #[serde(skip_serializing_if = "<[_]>::is_empty")]
     pub stop: &'a [&'a str],
     /// Controls the randomness of the model. Lower values will make the model more deterministic and higher values will make it more random.
@@ -213,6 +600,11 @@ struct ChatBody<'a> {
     pub logprobs: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub top_logprobs: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<&'a ResponseFormat>,
+    /// Identifies the end user for abuse monitoring.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<&'a str>,
 }
 
 impl<'a> ChatBody<'a> {
@@ -232,12 +624,22 @@ impl<'a> ChatBody<'a> {
                     presence_penalty,
                 },
             logprobs,
+            response_format,
+            validate_response: _,
+            user_id,
+            max_tokens_field,
         } = task;
+        let (max_tokens, max_completion_tokens) = match max_tokens_field {
+            MaxTokensField::MaxTokens => (*maximum_tokens, None),
+            MaxTokensField::MaxCompletionTokens => (None, *maximum_tokens),
+            MaxTokensField::Both => (*maximum_tokens, *maximum_tokens),
+        };
 
         Self {
             model,
             messages,
-            max_tokens: *maximum_tokens,
+            max_tokens,
+            max_completion_tokens,
             stop: stop_sequences,
             temperature: *temperature,
             top_p: *top_p,
@@ -246,6 +648,8 @@ impl<'a> ChatBody<'a> {
             stream: false,
             logprobs: logprobs.logprobs(),
             top_logprobs: logprobs.top_logprobs(),
+            response_format: response_format.as_ref(),
+            user: user_id.as_deref(),
         }
     }
 
@@ -270,22 +674,28 @@ impl Task for TaskChat<'_> {
         client.post(format!("{base}/chat/completions")).json(&body)
     }
 
-    fn body_to_output(&self, mut response: Self::ResponseBody) -> Self::Output {
+    fn body_to_output(&self, mut response: Self::ResponseBody) -> Result<Self::Output, Error> {
         let ResponseChoice {
+            index: _,
             message,
             finish_reason,
             logprobs,
-        } = response.choices.pop().unwrap();
-        ChatOutput::new(
+            extra,
+        } = response.choices.pop().ok_or(Error::EmptyResponse)?;
+        if let Some(format) = self.format_to_validate() {
+            format.check(&message.content)?;
+        }
+        Ok(ChatOutput {
             message,
             finish_reason,
-            logprobs.unwrap_or_default().content,
-            response.usage,
-        )
+            logprobs: logprobs.unwrap_or_default().content,
+            usage: response.usage,
+            extra,
+        })
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct StreamMessage {
     /// The role of the current chat completion. Will be assistant for the first chunk of every
     /// completion stream and missing for the remaining chunks.
@@ -296,24 +706,43 @@ pub struct StreamMessage {
 }
 
 /// One chunk of a chat completion stream.
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct ChatStreamChunk {
+    /// The index of the choice this chunk belongs to. This is relevant if multiple chat
+    /// completions are generated at once, to tell the concurrent generations apart. Defaults to
+    /// `0` if not reported by the server.
+    #[serde(default)]
+    pub index: u32,
     /// The reason the model stopped generating tokens.
     /// The value is only set in the last chunk of a completion and null otherwise.
     pub finish_reason: Option<String>,
     /// Chat completion chunk generated by the model when streaming is enabled.
     pub delta: StreamMessage,
+    /// Time passed since the previous chunk has been received, or since the request has been sent
+    /// for the first chunk. Only measured if [`crate::How::measure_token_timing`] is set.
+    #[serde(skip)]
+    pub time_since_previous: Option<Duration>,
+}
+
+impl ChatStreamChunk {
+    /// The reason the model stopped generating tokens, as typed enum. Only set in the last chunk
+    /// of a completion.
+    pub fn typed_finish_reason(&self) -> Option<FinishReason> {
+        self.finish_reason.as_deref().map(FinishReason::from)
+    }
 }
 
-/// Event received from a chat completion stream. As the crate does not support multiple
-/// chat completions, there will always exactly one choice item.
-#[derive(Deserialize)]
+/// Event received from a chat completion stream. Contains one choice item per chat completion
+/// generated. Use [`ChatStreamChunk::index`] to tell them apart. Events may come without any
+/// choice, e.g. if the content has been filtered. They do not add a chunk to the stream.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct ChatEvent {
     pub choices: Vec<ChatStreamChunk>,
 }
 
 impl StreamTask for TaskChat<'_> {
-    type Output = ChatStreamChunk;
+    /// One chunk for each choice of the event, see [`ChatEvent`].
+    type Output = Vec<ChatStreamChunk>;
 
     type ResponseBody = ChatEvent;
 
@@ -327,13 +756,8 @@ impl StreamTask for TaskChat<'_> {
         client.post(format!("{base}/chat/completions")).json(&body)
     }
 
-    fn body_to_output(mut response: Self::ResponseBody) -> Self::Output {
-        // We always expect there to be exactly one choice, as the `n` parameter is not
-        // supported by this crate.
-        response
-            .choices
-            .pop()
-            .expect("There must always be at least one choice")
+    fn body_to_output(response: Self::ResponseBody) -> Self::Output {
+        response.choices
     }
 }
 
@@ -354,3 +778,348 @@ impl Logprobs {
         }
     }
 }
+
+impl<'a> FromIterator<Message<'a>> for TaskChat<'a> {
+    fn from_iter<I: IntoIterator<Item = Message<'a>>>(messages: I) -> Self {
+        Self::with_message_iter(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ChatBody, ChatOutput, ChatSampling, MaxTokensField, Message, OwnedTaskChat, ResponseChat,
+        ResponseChoice, ResponseFormat, TaskChat, Usage,
+    };
+    use crate::{Error, Task};
+
+    #[test]
+    fn tool_result_is_serialized_with_call_id() {
+        // Given
+        let message = Message::tool_result("call_42", r#"{"temperature": 21}"#);
+
+        // When
+        let json = serde_json::to_value(&message).unwrap();
+
+        // Then
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "role": "tool",
+                "content": "{\"temperature\": 21}",
+                "tool_call_id": "call_42"
+            })
+        );
+        assert!(serde_json::to_value(Message::user("Hi"))
+            .unwrap()
+            .get("tool_call_id")
+            .is_none());
+    }
+
+    #[test]
+    fn consecutive_tool_results_are_not_merged() {
+        // Given results of two tool calls
+        let mut task = TaskChat::with_messages(vec![
+            Message::tool_result("call_1", "21"),
+            Message::tool_result("call_2", "23"),
+        ]);
+
+        // When
+        task.merge_consecutive_same_role("\n");
+
+        // Then
+        assert_eq!(task.messages.len(), 2);
+    }
+
+    #[test]
+    fn content_len_counts_bytes() {
+        // Given a message with multi byte characters
+        let message = Message::user("Grüße");
+
+        // Then
+        assert_eq!(message.content_len(), 7);
+        assert_eq!(Message::assistant("").content_len(), 0);
+    }
+
+    #[test]
+    fn task_is_built_from_message_iterator() {
+        // Given a history with an empty message
+        let history = ["Hello", "", "How are you?"];
+
+        // When
+        let task = TaskChat::with_message_iter(
+            history
+                .iter()
+                .filter(|content| !content.is_empty())
+                .map(|&content| Message::user(content)),
+        );
+        let collected: TaskChat = history.into_iter().map(Message::user).collect();
+
+        // Then
+        assert_eq!(
+            task.messages,
+            vec![Message::user("Hello"), Message::user("How are you?")]
+        );
+        assert_eq!(collected.messages.len(), 3);
+    }
+
+    #[test]
+    fn consecutive_user_messages_are_merged() {
+        // Given
+        let mut task = TaskChat::with_messages(vec![
+            Message::system("Be brief."),
+            Message::user("Hello"),
+            Message::user("How are you?"),
+            Message::assistant("Fine."),
+            Message::user("Great"),
+            Message::user("Bye"),
+            Message::user("See you"),
+        ]);
+
+        // When
+        task.merge_consecutive_same_role("\n");
+
+        // Then
+        assert_eq!(
+            task.messages,
+            vec![
+                Message::system("Be brief."),
+                Message::user("Hello\nHow are you?"),
+                Message::assistant("Fine."),
+                Message::user("Great\nBye\nSee you"),
+            ]
+        );
+    }
+
+    #[test]
+    fn alternating_roles_are_left_untouched() {
+        // Given
+        let mut task = TaskChat::with_messages(vec![
+            Message::user("Hello"),
+            Message::assistant("Hi"),
+            Message::user("Bye"),
+        ]);
+
+        // When
+        task.merge_consecutive_same_role(" ");
+
+        // Then
+        assert_eq!(
+            task.messages,
+            vec![
+                Message::user("Hello"),
+                Message::assistant("Hi"),
+                Message::user("Bye"),
+            ]
+        );
+    }
+
+    fn chat_output(finish_reason: &str) -> ChatOutput {
+        let usage = Usage {
+            prompt_tokens: 5,
+            completion_tokens: 2,
+        };
+        ChatOutput::new(
+            Message::assistant("An apple"),
+            finish_reason.to_owned(),
+            Vec::new(),
+            usage,
+        )
+    }
+
+    #[test]
+    fn chat_cut_off_by_length_is_truncated() {
+        assert!(chat_output("length").is_truncated());
+    }
+
+    #[test]
+    fn completed_chat_is_not_truncated() {
+        assert!(!chat_output("stop").is_truncated());
+        assert!(!chat_output("tool_calls").is_truncated());
+    }
+
+    #[test]
+    fn response_without_choices_is_an_error() {
+        // Given a response without any choice
+        let task = TaskChat::with_message(Message::user("Hello"));
+        let response: ResponseChat = serde_json::from_str(
+            r#"{"choices": [], "usage": {"prompt_tokens": 5, "completion_tokens": 0}}"#,
+        )
+        .unwrap();
+
+        // When
+        let result = task.body_to_output(response);
+
+        // Then
+        assert!(matches!(result, Err(Error::EmptyResponse)));
+    }
+
+    #[test]
+    fn chat_samplings_with_same_settings_are_equal() {
+        let sampling = ChatSampling {
+            top_p: Some(0.9),
+            ..ChatSampling::MOST_LIKELY
+        };
+
+        assert_eq!(sampling.clone(), sampling);
+        assert_ne!(sampling, ChatSampling::MOST_LIKELY);
+    }
+
+    #[test]
+    fn user_id_is_sent_as_user() {
+        let task = TaskChat::with_message(Message::user("Hello")).with_user_id("user-42");
+
+        let body = serde_json::to_value(ChatBody::new("pharia-1-llm-7b-control", &task)).unwrap();
+
+        assert_eq!(body["user"], "user-42");
+    }
+
+    #[test]
+    fn user_is_omitted_if_not_set() {
+        let task = TaskChat::with_message(Message::user("Hello"));
+
+        let body = serde_json::to_value(ChatBody::new("pharia-1-llm-7b-control", &task)).unwrap();
+
+        assert!(body.get("user").is_none());
+    }
+
+    #[test]
+    fn maximum_tokens_are_sent_as_max_tokens_by_default() {
+        let task = TaskChat::with_message(Message::user("Hello")).with_maximum_tokens(42);
+
+        let body = serde_json::to_value(ChatBody::new("pharia-1-llm-7b-control", &task)).unwrap();
+
+        assert_eq!(body["max_tokens"], 42);
+        assert!(body.get("max_completion_tokens").is_none());
+    }
+
+    #[test]
+    fn maximum_tokens_are_sent_as_max_completion_tokens() {
+        let task = TaskChat::with_message(Message::user("Hello"))
+            .with_maximum_tokens(42)
+            .with_max_tokens_field(MaxTokensField::MaxCompletionTokens);
+
+        let body = serde_json::to_value(ChatBody::new("pharia-1-llm-7b-control", &task)).unwrap();
+
+        assert_eq!(body["max_completion_tokens"], 42);
+        assert!(body.get("max_tokens").is_none());
+    }
+
+    #[test]
+    fn maximum_tokens_are_sent_under_both_names() {
+        let task = TaskChat::with_message(Message::user("Hello"))
+            .with_maximum_tokens(42)
+            .with_max_tokens_field(MaxTokensField::Both);
+
+        let body = serde_json::to_value(ChatBody::new("pharia-1-llm-7b-control", &task)).unwrap();
+
+        assert_eq!(body["max_tokens"], 42);
+        assert_eq!(body["max_completion_tokens"], 42);
+    }
+
+    #[test]
+    fn owned_task_chat_survives_json_round_trip() {
+        // Given a chat task saved to JSON
+        let task = TaskChat::with_messages(vec![
+            Message::system("Answer in JSON"),
+            Message::tool_result("call-1", "42"),
+        ])
+        .with_maximum_tokens(64)
+        .with_response_format(ResponseFormat::JsonObject)
+        .with_max_tokens_field(MaxTokensField::Both)
+        .with_user_id("user-42");
+        let json = serde_json::to_string(&OwnedTaskChat::from(&task)).unwrap();
+
+        // When loading it again
+        let owned: OwnedTaskChat = serde_json::from_str(&json).unwrap();
+        let stop_sequences = owned.stop_sequences();
+        let loaded = owned.as_task(&stop_sequences);
+
+        // Then the same request body is sent
+        let body = |task| serde_json::to_value(ChatBody::new("pharia-1-llm-7b-control", task));
+        assert_eq!(body(&loaded).unwrap(), body(&task).unwrap());
+        assert_eq!(OwnedTaskChat::from(&loaded), owned);
+    }
+
+    #[test]
+    fn owned_task_chat_defaults_missing_fields() {
+        let owned: OwnedTaskChat =
+            serde_json::from_str(r#"{"messages":[{"role":"user","content":"Hello"}]}"#).unwrap();
+
+        assert_eq!(owned.messages, [Message::user("Hello")]);
+        assert_eq!(owned.sampling, ChatSampling::MOST_LIKELY);
+        assert_eq!(owned.max_tokens_field, MaxTokensField::MaxTokens);
+    }
+
+    #[test]
+    fn json_object_format_rejects_other_json() {
+        let format = ResponseFormat::JsonObject;
+
+        assert!(format.check(r#"{"answer": 42}"#).is_ok());
+        assert!(matches!(
+            format.check("[42]"),
+            Err(Error::SchemaViolation { .. })
+        ));
+        assert!(matches!(
+            format.check("forty-two"),
+            Err(Error::SchemaViolation { .. })
+        ));
+    }
+
+    #[test]
+    fn response_is_only_validated_if_requested() {
+        // Given a response which is not a JSON object
+        let response = || ResponseChat {
+            choices: vec![ResponseChoice {
+                index: 0,
+                message: Message::assistant("Not JSON"),
+                finish_reason: "stop".to_owned(),
+                logprobs: None,
+                extra: Default::default(),
+            }],
+            usage: Usage {
+                prompt_tokens: 1,
+                completion_tokens: 2,
+            },
+        };
+        let task = TaskChat::with_message(Message::user("Hello"))
+            .with_response_format(ResponseFormat::JsonObject);
+
+        // When
+        let unvalidated = task.body_to_output(response());
+        let validated = task.with_response_validation().body_to_output(response());
+
+        // Then
+        assert!(unvalidated.is_ok());
+        assert!(matches!(validated, Err(Error::SchemaViolation { .. })));
+    }
+
+    #[test]
+    fn unknown_choice_fields_are_preserved() {
+        // Given a response with a vendor specific field
+        let task = TaskChat::with_message(Message::user("Hello"));
+        let response: ResponseChat = serde_json::from_str(
+            r#"{
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "Hi"},
+                    "finish_reason": "stop",
+                    "logprobs": null,
+                    "safety_scores": {"toxicity": 0.01}
+                }],
+                "usage": {"prompt_tokens": 1, "completion_tokens": 1}
+            }"#,
+        )
+        .unwrap();
+
+        // When
+        let output = task.body_to_output(response).unwrap();
+
+        // Then only the unknown field ends up in extra
+        assert_eq!(output.extra.len(), 1);
+        assert_eq!(
+            output.extra["safety_scores"],
+            serde_json::json!({"toxicity": 0.01})
+        );
+    }
+}
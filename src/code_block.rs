@@ -0,0 +1,92 @@
+/// A fenced code block in the text generated by a model. See [`extract_code_blocks`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeBlock {
+    /// Language given after the opening fence, e.g. `rust` for a block starting with ```` ```rust ````.
+    pub language: Option<String>,
+    /// Content of the block, without the fences.
+    pub code: String,
+}
+
+/// Extracts all code blocks fenced by triple backticks from a text, e.g. the completion of a model
+/// asked to write code. A block which is not closed, e.g. because the model ran out of tokens,
+/// extends to the end of the text.
+pub fn extract_code_blocks(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut current: Option<CodeBlock> = None;
+    for line in text.lines() {
+        let fence = line.trim_start().strip_prefix("```");
+        match (&mut current, fence) {
+            (None, Some(info)) => {
+                let language = info.split_whitespace().next().map(str::to_owned);
+                current = Some(CodeBlock {
+                    language,
+                    code: String::new(),
+                });
+            }
+            (Some(_), Some(rest)) if rest.trim().is_empty() => {
+                blocks.extend(current.take());
+            }
+            (Some(block), _) => {
+                block.code.push_str(line);
+                block.code.push('\n');
+            }
+            (None, None) => (),
+        }
+    }
+    blocks.extend(current);
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_without_code_blocks() {
+        let blocks = extract_code_blocks("Just some text with `inline` code.");
+
+        assert!(blocks.is_empty());
+    }
+
+    #[test]
+    fn single_code_block_with_language() {
+        // Given
+        let text = "Here you go:\n```rust\nfn main() {\n    println!(\"Hello\");\n}\n```\nEnjoy!";
+
+        // When
+        let blocks = extract_code_blocks(text);
+
+        // Then
+        assert_eq!(
+            blocks,
+            [CodeBlock {
+                language: Some("rust".to_owned()),
+                code: "fn main() {\n    println!(\"Hello\");\n}\n".to_owned()
+            }]
+        );
+    }
+
+    #[test]
+    fn multiple_code_blocks() {
+        // Given a block without language and one which has not been closed
+        let text = "```\nls -l\n```\nand\n```python\nprint(1)\n";
+
+        // When
+        let blocks = extract_code_blocks(text);
+
+        // Then
+        assert_eq!(
+            blocks,
+            [
+                CodeBlock {
+                    language: None,
+                    code: "ls -l\n".to_owned()
+                },
+                CodeBlock {
+                    language: Some("python".to_owned()),
+                    code: "print(1)\n".to_owned()
+                }
+            ]
+        );
+    }
+}
@@ -1,8 +1,12 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Range, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
-use crate::{http::Task, Distribution, Logprob, Logprobs, Prompt, StreamTask, Usage};
+use crate::{
+    code_block::{extract_code_blocks, CodeBlock},
+    http::Task,
+    Distribution, Error, FinishReason, Logprob, Logprobs, Prompt, StreamTask, Usage,
+};
 
 /// Completes a prompt. E.g. continues a text.
 pub struct TaskCompletion<'a> {
@@ -18,6 +22,20 @@ pub struct TaskCompletion<'a> {
     /// Wether you are interessted in the probabilities of the sampled tokens, or most likely
     /// tokens.
     pub logprobs: Logprobs,
+    /// Whether to return the ids of the completion tokens. Helps aligning the logprobs with your
+    /// own tokenization.
+    pub token_ids: bool,
+    /// Whether to return the prompt in front of the completion. Combined with [`Self::logprobs`]
+    /// this yields the logprobs of the prompt tokens, too. The first token of the prompt has no
+    /// logprob, as there is nothing in front of it to condition on. It is reported as `NaN`.
+    ///
+    /// When streaming, the echoed prompt is contained in the first [`StreamChunk`] of the
+    /// completion, in front of the first generated token.
+    pub echo: bool,
+    /// Whether to turn off optimizations the server applies to the prompt and the completion, e.g.
+    /// regarding whitespace at the boundaries. Unlike [`Self::special_tokens`], the completion
+    /// is still cleaned of special tokens. Helps reproducing the exact behaviour of the model.
+    pub disable_optimizations: bool,
 }
 
 impl<'a> TaskCompletion<'a> {
@@ -29,6 +47,9 @@ impl<'a> TaskCompletion<'a> {
             sampling: Sampling::MOST_LIKELY,
             special_tokens: false,
             logprobs: Logprobs::No,
+            token_ids: false,
+            echo: false,
+            disable_optimizations: false,
         }
     }
 
@@ -52,9 +73,129 @@ impl<'a> TaskCompletion<'a> {
         self.logprobs = logprobs;
         self
     }
+
+    /// Return the ids of the completion tokens in [`CompletionOutput::token_ids`]. Does not
+    /// require requesting [`Self::logprobs`], so it is a lightweight way to obtain the tokens.
+    pub fn with_token_ids(mut self) -> Self {
+        self.token_ids = true;
+        self
+    }
+
+    /// Return the prompt in front of the completion. See [`Self::echo`].
+    pub fn with_echo(mut self) -> Self {
+        self.echo = true;
+        self
+    }
+
+    /// Merge consecutive text items of the prompt into a single one, before sending it. Avoids
+    /// surprises caused by tokenizing each text item separately. See
+    /// [`Prompt::join_consecutive_text_items`].
+    pub fn with_joined_text(mut self) -> Self {
+        self.prompt.join_consecutive_text_items("");
+        self
+    }
+
+    /// Turn off optimizations of prompt and completion. See [`Self::disable_optimizations`].
+    pub fn with_disable_optimizations(mut self) -> Self {
+        self.disable_optimizations = true;
+        self
+    }
+}
+
+/// Owned counterpart of [`TaskCompletion`], which can be deserialized. Use it to save the
+/// configuration of a request to disk and replay it later, e.g. for reproducible experiments.
+/// Missing fields are deserialized with their default values.
+///
+/// ```
+/// use aleph_alpha_client::OwnedTaskCompletion;
+///
+/// let saved = r#"{"prompt":[{"type":"text","data":"An apple a day"}],"maximum_tokens":10}"#;
+/// let owned: OwnedTaskCompletion = serde_json::from_str(saved).unwrap();
+/// let stop_sequences = owned.stop_sequences();
+/// let task = owned.as_task(&stop_sequences);
+/// assert_eq!(task.stopping.maximum_tokens, Some(10));
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct OwnedTaskCompletion {
+    /// See [`TaskCompletion::prompt`].
+    pub prompt: Prompt<'static>,
+    /// See [`Stopping::maximum_tokens`].
+    pub maximum_tokens: Option<u32>,
+    /// See [`Stopping::stop_sequences`].
+    pub stop_sequences: Vec<String>,
+    /// See [`TaskCompletion::sampling`].
+    pub sampling: Sampling,
+    /// See [`TaskCompletion::special_tokens`].
+    pub special_tokens: bool,
+    /// See [`TaskCompletion::logprobs`].
+    pub logprobs: Logprobs,
+    /// See [`TaskCompletion::token_ids`].
+    pub token_ids: bool,
+    /// See [`TaskCompletion::echo`].
+    pub echo: bool,
+    /// See [`TaskCompletion::disable_optimizations`].
+    pub disable_optimizations: bool,
+}
+
+impl OwnedTaskCompletion {
+    /// The stop sequences borrowed as string slices, as required by [`Self::as_task`].
+    pub fn stop_sequences(&self) -> Vec<&str> {
+        self.stop_sequences.iter().map(String::as_str).collect()
+    }
+
+    /// Borrows a task from this one. `stop_sequences` are usually obtained from
+    /// [`Self::stop_sequences`], since [`Stopping`] expects a slice of string slices.
+    pub fn as_task<'a>(&'a self, stop_sequences: &'a [&'a str]) -> TaskCompletion<'a> {
+        TaskCompletion {
+            prompt: self.prompt.borrow(),
+            stopping: Stopping {
+                maximum_tokens: self.maximum_tokens,
+                stop_sequences,
+            },
+            sampling: self.sampling.clone(),
+            special_tokens: self.special_tokens,
+            logprobs: self.logprobs,
+            token_ids: self.token_ids,
+            echo: self.echo,
+            disable_optimizations: self.disable_optimizations,
+        }
+    }
+}
+
+impl From<&TaskCompletion<'_>> for OwnedTaskCompletion {
+    fn from(task: &TaskCompletion<'_>) -> Self {
+        let TaskCompletion {
+            prompt,
+            stopping:
+                Stopping {
+                    maximum_tokens,
+                    stop_sequences,
+                },
+            sampling,
+            special_tokens,
+            logprobs,
+            token_ids,
+            echo,
+            disable_optimizations,
+        } = task;
+        Self {
+            prompt: prompt.clone().into_owned(),
+            maximum_tokens: *maximum_tokens,
+            stop_sequences: stop_sequences.iter().map(|&s| s.to_owned()).collect(),
+            sampling: sampling.clone(),
+            special_tokens: *special_tokens,
+            logprobs: *logprobs,
+            token_ids: *token_ids,
+            echo: *echo,
+            disable_optimizations: *disable_optimizations,
+        }
+    }
 }
 
 /// Sampling controls how the tokens ("words") are selected for the completion.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Sampling {
     /// A temperature encourages the model to produce less probable outputs ("be more creative").
     /// Values are expected to be between 0 and 1. Try high values for a more random ("creative")
@@ -84,6 +225,19 @@ pub struct Sampling {
     /// where logits[t] is the logits for any given token. Note that the formula is independent
     /// of the number of times that a token appears.
     pub presence_penalty: Option<f64>,
+    /// Generates `best_of` candidates on the server and returns only the one with the highest
+    /// log probability per token. Improves the quality of the completion, at the cost of generating
+    /// more tokens. `best_of` must be at least as large as the number of completions requested
+    /// (`n`). This client always requests a single completion, so `best_of` must be at least one.
+    /// See [`crate::Client::completion`].
+    pub best_of: Option<u32>,
+    /// Adds the bias to the logits of the token with the given id before sampling. Positive values
+    /// make the token more likely, negative ones less likely. See
+    /// [`crate::Client::token_bias`] to bias words rather than token ids.
+    pub logit_bias: Option<HashMap<u32, f64>>,
+    /// Strings exempt from [`Self::frequency_penalty`] and [`Self::presence_penalty`], e.g. proper
+    /// nouns or the keys of a structured output, which must be repeated.
+    pub penalty_exceptions: Vec<String>,
 }
 
 impl Sampling {
@@ -95,9 +249,26 @@ impl Sampling {
         top_p: None,
         frequency_penalty: None,
         presence_penalty: None,
+        best_of: None,
+        logit_bias: None,
+        penalty_exceptions: Vec::new(),
     };
 }
 
+impl Sampling {
+    /// Checks that `best_of` is large enough for the number of completions we request.
+    pub(crate) fn check_best_of(&self) -> Result<(), Error> {
+        // This client does not support requesting more than one completion
+        let n = 1;
+        match self.best_of {
+            Some(best_of) if best_of < n => Err(Error::InvalidSampling {
+                reason: format!("best_of ({best_of}) must not be smaller than n ({n})"),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
 impl Default for Sampling {
     fn default() -> Self {
         Self::MOST_LIKELY
@@ -191,9 +362,24 @@ struct BodyCompletion<'a> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub presence_penalty: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub best_of: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<&'a HashMap<u32, f64>>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub penalty_exceptions: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub log_probs: Option<u8>,
     #[serde(skip_serializing_if = "std::ops::Not::not")]
     pub tokens: bool,
+    /// Return the ids of the completion tokens.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub token_ids: bool,
+    /// Return the prompt in front of the completion. Also for streaming, in the first chunk.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub echo: bool,
+    /// Do not optimize prompt and completion, e.g. regarding whitespace at the boundaries.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub disable_optimizations: bool,
 }
 
 impl<'a> BodyCompletion<'a> {
@@ -204,6 +390,9 @@ impl<'a> BodyCompletion<'a> {
             sampling,
             special_tokens,
             logprobs,
+            token_ids,
+            echo,
+            disable_optimizations,
         } = task;
         Self {
             model,
@@ -217,8 +406,14 @@ impl<'a> BodyCompletion<'a> {
             raw_completion: *special_tokens,
             frequency_penalty: sampling.frequency_penalty,
             presence_penalty: sampling.presence_penalty,
+            best_of: sampling.best_of,
+            logit_bias: sampling.logit_bias.as_ref(),
+            penalty_exceptions: &sampling.penalty_exceptions,
             log_probs: logprobs.to_logprobs_num(),
             tokens: logprobs.to_tokens(),
+            token_ids: *token_ids,
+            echo: *echo,
+            disable_optimizations: *disable_optimizations,
         }
     }
     pub fn with_streaming(mut self) -> Self {
@@ -244,6 +439,8 @@ struct DeserializedCompletion {
     log_probs: Vec<HashMap<String, f64>>,
     #[serde(default)]
     completion_tokens: Vec<String>,
+    #[serde(default)]
+    completion_token_ids: Vec<u32>,
 }
 
 /// Completion and metainformation returned by a completion task
@@ -252,9 +449,31 @@ pub struct CompletionOutput {
     pub completion: String,
     pub finish_reason: String,
     pub logprobs: Vec<Distribution>,
+    /// Ids of the completion tokens, given that [`TaskCompletion::token_ids`] has been set. Same
+    /// order as the [`Self::logprobs`].
+    pub token_ids: Vec<u32>,
     pub usage: Usage,
 }
 
+impl CompletionOutput {
+    /// The reason why the model stopped generating new tokens, as typed enum.
+    pub fn typed_finish_reason(&self) -> FinishReason {
+        FinishReason::from(self.finish_reason.as_str())
+    }
+
+    /// `true` if the model stopped, because it reached [`Stopping::maximum_tokens`] (or the
+    /// context size of the model). The completion is likely cut off.
+    pub fn is_truncated(&self) -> bool {
+        self.typed_finish_reason() == FinishReason::Length
+    }
+
+    /// Code blocks fenced by triple backticks in the completion. See
+    /// [`crate::extract_code_blocks`].
+    pub fn extract_code_blocks(&self) -> Vec<CodeBlock> {
+        extract_code_blocks(&self.completion)
+    }
+}
+
 impl Task for TaskCompletion<'_> {
     type Output = CompletionOutput;
 
@@ -270,21 +489,27 @@ impl Task for TaskCompletion<'_> {
         client.post(format!("{base}/complete")).json(&body)
     }
 
-    fn body_to_output(&self, mut response: Self::ResponseBody) -> Self::Output {
-        // We expect the API to return exactly one completion, despite them being modled as an array
+    fn validate(&self) -> Result<(), Error> {
+        self.sampling.check_best_of()
+    }
+
+    fn body_to_output(&self, mut response: Self::ResponseBody) -> Result<Self::Output, Error> {
+        // We expect the API to return exactly one completion, despite them being modled as an array.
+        // It may be empty though, e.g. if the completion has been filtered.
         let DeserializedCompletion {
             completion,
             finish_reason,
             raw_completion,
             log_probs,
             completion_tokens,
-        } = response.completions.pop().unwrap();
+            completion_token_ids,
+        } = response.completions.pop().ok_or(Error::EmptyResponse)?;
         let completion = if self.special_tokens {
             raw_completion.unwrap()
         } else {
             completion
         };
-        CompletionOutput {
+        Ok(CompletionOutput {
             completion,
             finish_reason,
             logprobs: completion_logprobs_to_canonical(
@@ -292,11 +517,134 @@ impl Task for TaskCompletion<'_> {
                 completion_tokens,
                 self.logprobs.top_logprobs().unwrap_or_default(),
             ),
+            token_ids: completion_token_ids,
             usage: Usage {
                 prompt_tokens: response.num_tokens_prompt_total,
                 completion_tokens: response.num_tokens_generated,
             },
+        })
+    }
+}
+
+/// Everything the API reports about a completion in one place, e.g. for tools analysing the output
+/// token by token. Returned by [`crate::Client::completion_details`].
+#[derive(Debug, PartialEq)]
+pub struct CompletionDetails {
+    /// The completion, possibly optimized by the API, e.g. regarding whitespace.
+    pub completion: String,
+    /// The completion as generated by the model, i.e. the concatenation of the bytes of the
+    /// sampled tokens in [`Self::logprobs`].
+    pub raw_completion: String,
+    pub finish_reason: String,
+    /// Ids of the completion tokens.
+    pub token_ids: Vec<u32>,
+    /// Completion tokens as text. Same order as [`Self::token_ids`].
+    pub tokens: Vec<String>,
+    /// Byte range of each token within [`Self::raw_completion`], derived from the bytes of the
+    /// sampled tokens. Same order as [`Self::tokens`].
+    pub offsets: Vec<Range<usize>>,
+    /// Log probability of each sampled token, plus the top alternatives if requested with
+    /// [`TaskCompletion::logprobs`]. Same order as [`Self::tokens`].
+    pub logprobs: Vec<Distribution>,
+    pub usage: Usage,
+}
+
+impl CompletionDetails {
+    fn new(
+        completion: DeserializedCompletion,
+        usage: Usage,
+        num_top_logprobs: u8,
+    ) -> Result<Self, Error> {
+        let DeserializedCompletion {
+            completion,
+            finish_reason,
+            raw_completion,
+            log_probs,
+            completion_tokens,
+            completion_token_ids,
+        } = completion;
+        let incomplete = |reason: String| Error::IncompleteResponse { reason };
+        let raw_completion =
+            raw_completion.ok_or_else(|| incomplete("raw completion is missing".to_owned()))?;
+        if log_probs.len() != completion_tokens.len() {
+            return Err(incomplete(format!(
+                "{} completion tokens, but logprobs for {}",
+                completion_tokens.len(),
+                log_probs.len()
+            )));
+        }
+        let logprobs = completion_logprobs_to_canonical(
+            log_probs,
+            completion_tokens.clone(),
+            num_top_logprobs,
+        );
+        // The bytes of the sampled tokens are the source of truth for their length
+        let mut start = 0;
+        let offsets: Vec<_> = logprobs
+            .iter()
+            .map(|distribution| {
+                let offset = start..start + distribution.sampled.token.len();
+                start = offset.end;
+                offset
+            })
+            .collect();
+        if start != raw_completion.len() {
+            return Err(incomplete(format!(
+                "tokens span {start} bytes, but the raw completion has {}",
+                raw_completion.len()
+            )));
         }
+        Ok(Self {
+            raw_completion,
+            completion,
+            finish_reason,
+            token_ids: completion_token_ids,
+            logprobs,
+            tokens: completion_tokens,
+            offsets,
+            usage,
+        })
+    }
+}
+
+/// Completion which requests tokens, token ids, logprobs and the raw completion in addition to
+/// whatever the wrapped task asks for.
+pub(crate) struct TaskCompletionDetails<'a>(pub &'a TaskCompletion<'a>);
+
+impl Task for TaskCompletionDetails<'_> {
+    type Output = CompletionDetails;
+
+    type ResponseBody = ResponseCompletion;
+
+    fn build_request(
+        &self,
+        client: &reqwest::Client,
+        base: &str,
+        model: &str,
+    ) -> reqwest::RequestBuilder {
+        let mut body = BodyCompletion::new(model, self.0);
+        body.raw_completion = true;
+        body.tokens = true;
+        body.token_ids = true;
+        body.log_probs = body.log_probs.or(Some(0));
+        client.post(format!("{base}/complete")).json(&body)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        self.0.sampling.check_best_of()
+    }
+
+    fn body_to_output(&self, mut response: Self::ResponseBody) -> Result<Self::Output, Error> {
+        let completion = response.completions.pop().ok_or(Error::EmptyResponse)?;
+        let usage = Usage {
+            prompt_tokens: response.num_tokens_prompt_total,
+            completion_tokens: response.num_tokens_generated,
+        };
+        CompletionDetails::new(
+            completion,
+            usage,
+            self.0.logprobs.top_logprobs().unwrap_or_default(),
+        )
     }
 }
 
@@ -336,13 +684,58 @@ fn completion_logprobs_to_canonical(
 }
 
 /// Describes a chunk of a completion stream
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct StreamChunk {
     /// The index of the stream that this chunk belongs to.
     /// This is relevant if multiple completion streams are requested (see parameter n).
     pub index: u32,
     /// The completion of the stream.
     pub completion: String,
+    /// Number of tokens generated so far, if reported by the server. Useful to display progress
+    /// relative to the maximum tokens.
+    #[serde(default)]
+    pub num_tokens_generated: Option<u32>,
+    /// Time passed since the previous chunk has been received, or since the request has been sent
+    /// for the first chunk. Only measured if [`crate::How::measure_token_timing`] is set.
+    #[serde(skip)]
+    pub time_since_previous: Option<Duration>,
+    /// `true` if the chunk contains the echoed prompt rather than generated text. See
+    /// [`TaskCompletion::echo`].
+    #[serde(skip)]
+    pub is_echo: bool,
+}
+
+impl StreamChunk {
+    /// Splits the first chunk of a stream echoing the prompt into a chunk with the prompt and one
+    /// with the generated text in front of it. `prompt_text` is `None` if the prompt is not
+    /// text-only. If the prompt can not be told apart from the generated text, the whole chunk is
+    /// marked as echo.
+    pub(crate) fn split_echo(mut self, prompt_text: Option<&str>) -> Vec<StreamChunk> {
+        let generated = prompt_text.and_then(|prompt| {
+            let generated = self.completion.strip_prefix(prompt)?.to_owned();
+            self.completion = prompt.to_owned();
+            Some(generated)
+        });
+        self.is_echo = true;
+        match generated {
+            Some(generated) if !generated.is_empty() => {
+                let echo = StreamChunk {
+                    index: self.index,
+                    completion: self.completion,
+                    num_tokens_generated: None,
+                    time_since_previous: None,
+                    is_echo: true,
+                };
+                let generated = StreamChunk {
+                    completion: generated,
+                    is_echo: false,
+                    ..self
+                };
+                vec![echo, generated]
+            }
+            _ => vec![self],
+        }
+    }
 }
 
 /// Denotes the end of a completion stream.
@@ -350,16 +743,21 @@ pub struct StreamChunk {
 /// The index of the stream that is being terminated is not deserialized.
 /// It is only relevant if multiple completion streams are requested, (see parameter n),
 /// which is not supported by this crate yet.
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct StreamSummary {
-    /// Model name and version (if any) of the used model for inference.
-    pub model_version: String,
+    /// Model name and version (if any) of the used model for inference. `None` for summaries the
+    /// client emits in place of the server, see [`crate::Client::stream_completion_until`].
+    pub model_version: Option<String>,
     /// The reason why the model stopped generating new tokens.
     pub finish_reason: String,
+    /// The stop sequence which caused the model to stop generating new tokens. Only set if the
+    /// stream finished on a stop sequence and the server reports which one matched.
+    #[serde(default)]
+    pub stop_sequence: Option<String>,
 }
 
 /// Denotes the end of all completion streams.
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct CompletionSummary {
     /// Number of tokens combined across all completion tasks.
     /// In particular, if you set best_of or n to a number larger than 1 then we report the
@@ -371,7 +769,7 @@ pub struct CompletionSummary {
     pub num_tokens_generated: u32,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum CompletionEvent {
@@ -395,6 +793,10 @@ impl StreamTask for TaskCompletion<'_> {
         client.post(format!("{base}/complete")).json(&body)
     }
 
+    fn validate(&self) -> Result<(), Error> {
+        self.sampling.check_best_of()
+    }
+
     fn body_to_output(response: Self::ResponseBody) -> Self::Output {
         response
     }
@@ -418,3 +820,432 @@ impl Logprobs {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http::HttpClient, Modality};
+
+    #[test]
+    fn token_ids_are_requested() {
+        let task = TaskCompletion::from_text("An apple a day").with_token_ids();
+
+        let body = serde_json::to_value(BodyCompletion::new("luminous-base", &task)).unwrap();
+
+        assert_eq!(body["token_ids"], true);
+    }
+
+    #[test]
+    fn best_of_is_requested() {
+        let mut task = TaskCompletion::from_text("An apple a day");
+        task.sampling.best_of = Some(3);
+
+        let body = serde_json::to_value(BodyCompletion::new("luminous-base", &task)).unwrap();
+
+        assert_eq!(body["best_of"], 3);
+        assert!(task.sampling.check_best_of().is_ok());
+    }
+
+    #[test]
+    fn samplings_with_same_settings_are_equal() {
+        let sampling = Sampling {
+            temperature: Some(0.5),
+            ..Sampling::MOST_LIKELY
+        };
+
+        assert_eq!(sampling.clone(), sampling);
+        assert_eq!(Sampling::default(), Sampling::MOST_LIKELY);
+        assert_ne!(sampling, Sampling::MOST_LIKELY);
+    }
+
+    #[test]
+    fn logit_bias_is_sent_by_token_id() {
+        // Given
+        let mut task = TaskCompletion::from_text("Is the sky blue?");
+        task.sampling.logit_bias = Some([(9642, 2.5)].into());
+
+        // When
+        let body = serde_json::to_value(BodyCompletion::new("luminous-base", &task)).unwrap();
+
+        // Then
+        assert_eq!(body["logit_bias"], serde_json::json!({"9642": 2.5}));
+    }
+
+    #[test]
+    fn penalty_exceptions_are_sent_if_present() {
+        // Given
+        let mut task = TaskCompletion::from_text("Alice and Bob");
+        let without_exceptions =
+            serde_json::to_value(BodyCompletion::new("luminous-base", &task)).unwrap();
+        task.sampling.penalty_exceptions = vec!["Alice".to_owned(), "Bob".to_owned()];
+
+        // When
+        let body = serde_json::to_value(BodyCompletion::new("luminous-base", &task)).unwrap();
+
+        // Then
+        assert_eq!(
+            body["penalty_exceptions"],
+            serde_json::json!(["Alice", "Bob"])
+        );
+        assert!(without_exceptions.get("penalty_exceptions").is_none());
+    }
+
+    #[test]
+    fn best_of_smaller_than_n_is_rejected() {
+        let sampling = Sampling {
+            best_of: Some(0),
+            ..Sampling::MOST_LIKELY
+        };
+
+        let result = sampling.check_best_of();
+
+        assert!(matches!(result, Err(Error::InvalidSampling { .. })));
+    }
+
+    #[test]
+    fn echoed_prompt_is_split_from_generated_text() {
+        // Given the first chunk of an echoed stream
+        let prompt = Prompt::from_text("An apple a day");
+        let chunk = StreamChunk {
+            index: 0,
+            completion: "An apple a day keeps".to_owned(),
+            num_tokens_generated: Some(1),
+            time_since_previous: None,
+            is_echo: false,
+        };
+
+        // When
+        let chunks = chunk.split_echo(prompt.text().as_deref());
+
+        // Then
+        let parts: Vec<_> = chunks
+            .iter()
+            .map(|chunk| (chunk.completion.as_str(), chunk.is_echo))
+            .collect();
+        assert_eq!(parts, [("An apple a day", true), (" keeps", false)]);
+        assert_eq!(chunks[1].num_tokens_generated, Some(1));
+    }
+
+    #[test]
+    fn echo_of_multimodal_prompt_is_not_split() {
+        // Given a prompt with an image, which is echoed as text
+        let prompt = Prompt::from_vec(vec![
+            Modality::Image {
+                data: "iVBORw0KGgo=".into(),
+            },
+            Modality::from_text("A picture of"),
+        ]);
+        let chunk = StreamChunk {
+            index: 0,
+            completion: "A picture of a cat".to_owned(),
+            num_tokens_generated: None,
+            time_since_previous: None,
+            is_echo: false,
+        };
+
+        // When
+        let chunks = chunk.split_echo(prompt.text().as_deref());
+
+        // Then
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].is_echo);
+        assert_eq!(chunks[0].completion, "A picture of a cat");
+    }
+
+    #[test]
+    fn owned_task_completion_survives_json_round_trip() {
+        // Given a task saved to JSON
+        let task = TaskCompletion::from_text("An apple a day")
+            .with_maximum_tokens(10)
+            .with_stop_sequences(&["\n"])
+            .with_logprobs(Logprobs::Top(3))
+            .with_echo();
+        let json = serde_json::to_string(&OwnedTaskCompletion::from(&task)).unwrap();
+
+        // When loading it again
+        let owned: OwnedTaskCompletion = serde_json::from_str(&json).unwrap();
+        let stop_sequences = owned.stop_sequences();
+        let loaded = owned.as_task(&stop_sequences);
+
+        // Then
+        assert_eq!(loaded.prompt, task.prompt);
+        assert_eq!(loaded.stopping.maximum_tokens, Some(10));
+        assert_eq!(loaded.stopping.stop_sequences, ["\n"]);
+        assert_eq!(loaded.sampling, task.sampling);
+        assert_eq!(loaded.logprobs, Logprobs::Top(3));
+        assert!(loaded.echo);
+        assert_eq!(OwnedTaskCompletion::from(&loaded), owned);
+    }
+
+    #[test]
+    fn owned_task_completion_defaults_missing_fields() {
+        let owned: OwnedTaskCompletion =
+            serde_json::from_str(r#"{"sampling":{"temperature":0.5}}"#).unwrap();
+
+        assert_eq!(owned.sampling.temperature, Some(0.5));
+        assert_eq!(owned.sampling.top_k, None);
+        assert_eq!(owned.logprobs, Logprobs::No);
+        assert!(owned.stop_sequences.is_empty());
+    }
+
+    #[test]
+    fn echo_is_requested_when_streaming() {
+        let task = TaskCompletion::from_text("An apple a day").with_echo();
+
+        let body =
+            serde_json::to_value(BodyCompletion::new("luminous-base", &task).with_streaming())
+                .unwrap();
+
+        assert_eq!(body["echo"], true);
+        assert_eq!(body["stream"], true);
+    }
+
+    #[test]
+    fn disable_optimizations_is_requested() {
+        let task = TaskCompletion::from_text("An apple a day").with_disable_optimizations();
+
+        let body = serde_json::to_value(BodyCompletion::new("luminous-base", &task)).unwrap();
+
+        assert_eq!(body["disable_optimizations"], true);
+        // Optimizations are independent of the raw completion
+        assert!(body.get("raw_completion").is_none());
+    }
+
+    #[test]
+    fn optimizations_are_enabled_by_default() {
+        let task = TaskCompletion::from_text("An apple a day");
+
+        let body = serde_json::to_value(BodyCompletion::new("luminous-base", &task)).unwrap();
+
+        assert!(body.get("disable_optimizations").is_none());
+    }
+
+    #[test]
+    fn joined_text_is_sent_as_single_item() {
+        // Given a prompt assembled from several text items
+        let mut task = TaskCompletion::from_text("An apple");
+        task.prompt.push_text(" a day");
+        task.prompt.push_text(" keeps");
+
+        // When
+        let body = serde_json::to_value(BodyCompletion::new(
+            "luminous-base",
+            &task.with_joined_text(),
+        ))
+        .unwrap();
+
+        // Then
+        assert_eq!(
+            body["prompt"],
+            serde_json::json!([{"type": "text", "data": "An apple a day keeps"}])
+        );
+    }
+
+    #[test]
+    fn completion_token_ids_are_parsed_without_logprobs() {
+        // Given a response to a task requesting only the token ids
+        let task = TaskCompletion::from_text("An apple a day").with_token_ids();
+        let response: ResponseCompletion = serde_json::from_str(
+            r#"{
+                "model_version": "2021-12",
+                "completions": [{
+                    "completion": " keeps the",
+                    "finish_reason": "maximum_tokens",
+                    "completion_token_ids": [48741, 247]
+                }],
+                "num_tokens_prompt_total": 5,
+                "num_tokens_generated": 2
+            }"#,
+        )
+        .unwrap();
+
+        // When
+        let output = task.body_to_output(response).unwrap();
+
+        // Then
+        assert_eq!(output.token_ids, [48741, 247]);
+        assert!(output.logprobs.is_empty());
+    }
+
+    #[test]
+    fn completion_token_ids_are_parsed() {
+        // Given a response with token ids
+        let task = TaskCompletion::from_text("An apple a day")
+            .with_logprobs(Logprobs::Sampled)
+            .with_token_ids();
+        let response: ResponseCompletion = serde_json::from_str(
+            r#"{
+                "model_version": "2021-12",
+                "completions": [{
+                    "completion": " keeps the",
+                    "finish_reason": "maximum_tokens",
+                    "log_probs": [{" keeps": -0.5}, {" the": -0.1}],
+                    "completion_tokens": [" keeps", " the"],
+                    "completion_token_ids": [48741, 247]
+                }],
+                "num_tokens_prompt_total": 5,
+                "num_tokens_generated": 2
+            }"#,
+        )
+        .unwrap();
+
+        // When
+        let output = task.body_to_output(response).unwrap();
+
+        // Then
+        assert_eq!(output.token_ids, [48741, 247]);
+        assert_eq!(output.logprobs.len(), 2);
+    }
+
+    #[test]
+    fn completion_details_are_parsed() {
+        // Given a response with everything the details request
+        let task = TaskCompletion::from_text("An apple a day").with_logprobs(Logprobs::Top(1));
+        let details_task = TaskCompletionDetails(&task);
+        let response: ResponseCompletion = serde_json::from_str(
+            r#"{
+                "model_version": "2021-12",
+                "completions": [{
+                    "completion": "keeps the",
+                    "raw_completion": " keeps the",
+                    "finish_reason": "maximum_tokens",
+                    "log_probs": [{" keeps": -0.5, " is": -1.5}, {" the": -0.1}],
+                    "completion_tokens": [" keeps", " the"],
+                    "completion_token_ids": [48741, 247]
+                }],
+                "num_tokens_prompt_total": 5,
+                "num_tokens_generated": 2
+            }"#,
+        )
+        .unwrap();
+
+        // When
+        let details = details_task.body_to_output(response).unwrap();
+
+        // Then
+        assert_eq!(details.completion, "keeps the");
+        assert_eq!(details.raw_completion, " keeps the");
+        assert_eq!(details.finish_reason, "maximum_tokens");
+        assert_eq!(details.token_ids, [48741, 247]);
+        assert_eq!(details.tokens, [" keeps", " the"]);
+        assert_eq!(details.offsets, [0..6, 6..10]);
+        assert_eq!(&details.raw_completion[details.offsets[1].clone()], " the");
+        assert_eq!(details.logprobs[0].sampled.logprob, -0.5);
+        assert_eq!(details.logprobs[0].top.len(), 1);
+        assert_eq!(details.usage.completion_tokens, 2);
+    }
+
+    #[test]
+    fn completion_details_without_raw_completion_are_rejected() {
+        // Given a response by a server ignoring the request for the raw completion
+        let task = TaskCompletion::from_text("An apple a day");
+        let response: ResponseCompletion = serde_json::from_str(
+            r#"{
+                "model_version": "2021-12",
+                "completions": [{
+                    "completion": "keeps the",
+                    "finish_reason": "maximum_tokens",
+                    "log_probs": [{" keeps": -0.5}, {" the": -0.1}],
+                    "completion_tokens": [" keeps", " the"]
+                }],
+                "num_tokens_prompt_total": 5,
+                "num_tokens_generated": 2
+            }"#,
+        )
+        .unwrap();
+
+        // When
+        let result = TaskCompletionDetails(&task).body_to_output(response);
+
+        // Then
+        assert!(matches!(result, Err(Error::IncompleteResponse { .. })));
+    }
+
+    #[test]
+    fn completion_details_with_tokens_not_spanning_raw_completion_are_rejected() {
+        // Given tokens which do not add up to the raw completion
+        let task = TaskCompletion::from_text("An apple a day");
+        let response: ResponseCompletion = serde_json::from_str(
+            r#"{
+                "model_version": "2021-12",
+                "completions": [{
+                    "completion": "keeps the doctor",
+                    "raw_completion": " keeps the doctor",
+                    "finish_reason": "maximum_tokens",
+                    "log_probs": [{" keeps": -0.5}, {" the": -0.1}],
+                    "completion_tokens": [" keeps", " the"]
+                }],
+                "num_tokens_prompt_total": 5,
+                "num_tokens_generated": 2
+            }"#,
+        )
+        .unwrap();
+
+        // When
+        let result = TaskCompletionDetails(&task).body_to_output(response);
+
+        // Then
+        assert!(matches!(result, Err(Error::IncompleteResponse { .. })));
+    }
+
+    #[test]
+    fn completion_details_request_tokens_and_logprobs() {
+        let task = TaskCompletion::from_text("An apple a day");
+
+        let client = HttpClient::new("http://localhost:8080".to_owned(), None).unwrap();
+
+        let body = client
+            .request_json(&TaskCompletionDetails(&task).with_model("luminous-base"))
+            .unwrap();
+
+        assert_eq!(body["tokens"], true);
+        assert_eq!(body["token_ids"], true);
+        assert_eq!(body["raw_completion"], true);
+        assert_eq!(body["log_probs"], 0);
+    }
+
+    fn completion_output(finish_reason: &str) -> CompletionOutput {
+        CompletionOutput {
+            completion: "An apple".to_owned(),
+            finish_reason: finish_reason.to_owned(),
+            logprobs: Vec::new(),
+            token_ids: Vec::new(),
+            usage: Usage {
+                prompt_tokens: 5,
+                completion_tokens: 2,
+            },
+        }
+    }
+
+    #[test]
+    fn completion_cut_off_by_maximum_tokens_is_truncated() {
+        assert!(completion_output("maximum_tokens").is_truncated());
+    }
+
+    #[test]
+    fn completed_completion_is_not_truncated() {
+        assert!(!completion_output("end_of_text").is_truncated());
+        assert!(!completion_output("stop_sequence_reached").is_truncated());
+    }
+
+    #[test]
+    fn response_without_completions_is_an_error() {
+        // Given a response without any completion, e.g. because it has been filtered
+        let task = TaskCompletion::from_text("An apple a day");
+        let response: ResponseCompletion = serde_json::from_str(
+            r#"{
+                "model_version": "2021-12",
+                "completions": [],
+                "num_tokens_prompt_total": 5,
+                "num_tokens_generated": 0
+            }"#,
+        )
+        .unwrap();
+
+        // When
+        let result = task.body_to_output(response);
+
+        // Then
+        assert!(matches!(result, Err(Error::EmptyResponse)));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::Task;
+use crate::{Error, Task};
 use serde::{Deserialize, Serialize};
 
 /// Input for a [crate::Client::detokenize] request.
@@ -7,6 +7,21 @@ pub struct TaskDetokenization<'a> {
     pub token_ids: &'a [u32],
 }
 
+/// Input for a [crate::Client::batch_detokenize] request.
+pub struct TaskBatchDetokenization<'a> {
+    /// Sequences of token ids, each of which should be detokenized into its own text.
+    pub sequences: Vec<&'a [u32]>,
+}
+
+impl<'a> TaskBatchDetokenization<'a> {
+    /// Individual detokenization task for each of the sequences
+    pub(crate) fn tasks(&self) -> impl Iterator<Item = TaskDetokenization<'a>> + '_ {
+        self.sequences
+            .iter()
+            .map(|&token_ids| TaskDetokenization { token_ids })
+    }
+}
+
 /// Body send to the Aleph Alpha API on the POST `/detokenize` route
 #[derive(Serialize, Debug)]
 struct BodyDetokenization<'a> {
@@ -51,7 +66,7 @@ impl Task for TaskDetokenization<'_> {
         client.post(format!("{base}/detokenize")).json(&body)
     }
 
-    fn body_to_output(&self, response: Self::ResponseBody) -> Self::Output {
-        DetokenizationOutput::from(response)
+    fn body_to_output(&self, response: Self::ResponseBody) -> Result<Self::Output, Error> {
+        Ok(DetokenizationOutput::from(response))
     }
 }
@@ -0,0 +1,160 @@
+use std::cmp::Ordering;
+
+use crate::cosine_similarity;
+
+/// A tiny in-memory vector store for prototypes. Holds embeddings, e.g. from
+/// [`crate::Client::semantic_embedding`], together with an id and finds the ones most similar to a
+/// query by cosine similarity. Search is exhaustive, so consider a dedicated vector database for
+/// larger collections.
+///
+/// ```
+/// use aleph_alpha_client::EmbeddingIndex;
+///
+/// let mut index = EmbeddingIndex::new();
+/// index.insert("pizza", vec![0.9, 0.1]);
+/// index.insert("robot", vec![0.1, 0.9]);
+///
+/// let hits = index.search(&[1.0, 0.0], 1);
+/// assert_eq!(hits[0].0, &"pizza");
+/// ```
+#[derive(Debug, Clone)]
+pub struct EmbeddingIndex<Id> {
+    entries: Vec<(Id, Vec<f32>)>,
+    /// Whether the embeddings are normalized on insert
+    normalized: bool,
+}
+
+impl<Id> EmbeddingIndex<Id> {
+    /// An empty index, storing embeddings as they are inserted.
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            normalized: false,
+        }
+    }
+
+    /// An empty index, normalizing embeddings to unit length on insert. The cosine similarity is
+    /// then just a dot product, which speeds up searches at the expense of inserts.
+    pub fn normalized() -> Self {
+        Self {
+            entries: Vec::new(),
+            normalized: true,
+        }
+    }
+
+    /// Adds an embedding to the index.
+    pub fn insert(&mut self, id: Id, mut embedding: Vec<f32>) {
+        if self.normalized {
+            normalize(&mut embedding);
+        }
+        self.entries.push((id, embedding));
+    }
+
+    /// Number of embeddings in the index.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// `true` if no embedding has been inserted yet.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The `k` embeddings most similar to the query, together with their cosine similarity. Most
+    /// similar first.
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(&Id, f32)> {
+        // Normalizing the query once spares us from computing its length for every embedding
+        let normalized_query = self.normalized.then(|| {
+            let mut query = query.to_vec();
+            normalize(&mut query);
+            query
+        });
+        let similarity = |embedding: &[f32]| match &normalized_query {
+            Some(query) => dot(query, embedding),
+            None => cosine_similarity(query, embedding),
+        };
+        let mut hits: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(id, embedding)| (id, similarity(embedding)))
+            .collect();
+        hits.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+        hits.truncate(k);
+        hits
+    }
+}
+
+impl<Id> Default for EmbeddingIndex<Id> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+/// Scales the vector to unit length. Zero vectors are left untouched.
+fn normalize(vector: &mut [f32]) {
+    let length = dot(vector, vector).sqrt();
+    if length > 0.0 {
+        vector.iter_mut().for_each(|value| *value /= length);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EmbeddingIndex;
+
+    fn index_of(mut index: EmbeddingIndex<&'static str>) -> EmbeddingIndex<&'static str> {
+        index.insert("north", vec![0.0, 2.0]);
+        index.insert("north east", vec![1.0, 1.0]);
+        index.insert("east", vec![3.0, 0.0]);
+        index.insert("south", vec![0.0, -1.0]);
+        index
+    }
+
+    #[test]
+    fn top_k_ordered_by_similarity() {
+        // Given
+        let index = index_of(EmbeddingIndex::new());
+
+        // When
+        let hits = index.search(&[1.0, 0.2], 3);
+
+        // Then
+        let ids: Vec<_> = hits.iter().map(|(id, _)| **id).collect();
+        assert_eq!(ids, ["east", "north east", "north"]);
+        assert!(hits.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+    }
+
+    #[test]
+    fn normalized_index_yields_same_similarities() {
+        // Given
+        let plain = index_of(EmbeddingIndex::new());
+        let normalized = index_of(EmbeddingIndex::normalized());
+
+        // When
+        let plain_hits = plain.search(&[1.0, 0.2], 4);
+        let normalized_hits = normalized.search(&[1.0, 0.2], 4);
+
+        // Then
+        for ((plain_id, plain), (normalized_id, normalized)) in
+            plain_hits.iter().zip(&normalized_hits)
+        {
+            assert_eq!(plain_id, normalized_id);
+            assert!((plain - normalized).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn k_larger_than_index() {
+        let index = index_of(EmbeddingIndex::new());
+
+        let hits = index.search(&[0.0, 1.0], 10);
+
+        assert_eq!(hits.len(), index.len());
+        assert_eq!(hits[0].0, &"north");
+        assert_eq!(hits[3].0, &"south");
+    }
+}
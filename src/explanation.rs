@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{Prompt, Task};
+use crate::{image_preprocessing, Error, ImageRegion, Prompt, Task};
 
 /// Input for a [crate::Client::explanation] request.
 pub struct TaskExplanation<'a> {
@@ -13,12 +13,38 @@ pub struct TaskExplanation<'a> {
     pub granularity: Granularity,
 }
 
+/// Input for a [crate::Client::batch_explanation] request. Explains several targets for the same
+/// prompt, e.g. to compare which parts of the prompt favour one answer over another.
+pub struct TaskBatchExplanation<'a> {
+    /// The prompt that typically was the input of a previous completion request
+    pub prompt: Prompt<'a>,
+    /// The target strings that should be explained. Each target is explained independently of the
+    /// others.
+    pub targets: Vec<&'a str>,
+    /// Granularity parameters for the explanations
+    pub granularity: Granularity,
+}
+
+impl<'a> TaskBatchExplanation<'a> {
+    /// Individual explanation task for each of the targets
+    pub(crate) fn tasks(&self) -> impl Iterator<Item = TaskExplanation<'_>> + '_ {
+        self.targets.iter().map(|&target| TaskExplanation {
+            prompt: self.prompt.borrow(),
+            target,
+            granularity: self.granularity,
+        })
+    }
+}
+
 /// Granularity parameters for the [TaskExplanation]
-#[derive(Default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
 pub struct Granularity {
     /// The granularity of the parts of the prompt for which a single
     /// score is computed.
     prompt: PromptGranularity,
+    /// The granularity of the parts of the target for which a single
+    /// score is computed.
+    target: TargetGranularity,
 }
 
 impl Granularity {
@@ -27,6 +53,16 @@ impl Granularity {
     pub fn with_prompt_granularity(self, prompt_granularity: PromptGranularity) -> Self {
         Self {
             prompt: prompt_granularity,
+            ..self
+        }
+    }
+
+    /// Returns a new [Granularity] based on the given one with the [Granularity::target]
+    /// being set to `target_granularity`.
+    pub fn with_target_granularity(self, target_granularity: TargetGranularity) -> Self {
+        Self {
+            target: target_granularity,
+            ..self
         }
     }
 }
@@ -37,7 +73,7 @@ impl Granularity {
 /// The default is [PromptGranularity::Auto] which means we will try to find the granularity that
 /// brings you closest to around 30 explanations. For large prompts, this would likely
 /// be sentences. For short prompts this might be individual words or even tokens.
-#[derive(Serialize, Copy, Clone, PartialEq, Default)]
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Default)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum PromptGranularity {
     /// Let the system decide which granularity is most suitable for the given input.
@@ -55,6 +91,25 @@ impl PromptGranularity {
     }
 }
 
+/// How many explanations should be returned in the output.
+/// [TargetGranularity::Complete] explains the target as a whole, while
+/// [TargetGranularity::Token] returns one explanation for each token of the target.
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetGranularity {
+    /// Return one explanation for the entire target. This is the default of the API.
+    #[default]
+    Complete,
+    /// Return one explanation for each token in the target.
+    Token,
+}
+
+impl TargetGranularity {
+    fn is_complete(&self) -> bool {
+        self == &TargetGranularity::Complete
+    }
+}
+
 /// Body sent to the Aleph Alpha API for an explanation request
 #[derive(Serialize)]
 struct BodyExplanation<'a> {
@@ -62,6 +117,8 @@ struct BodyExplanation<'a> {
     target: &'a str,
     #[serde(skip_serializing_if = "PromptGranularity::is_auto")]
     prompt_granularity: PromptGranularity,
+    #[serde(skip_serializing_if = "TargetGranularity::is_complete")]
+    target_granularity: TargetGranularity,
     model: &'a str,
 }
 
@@ -76,14 +133,15 @@ pub struct ResponseExplanation {
 /// The result of an explanation request.
 #[derive(Debug, PartialEq)]
 pub struct ExplanationOutput {
-    /// Explanation scores for different parts of the prompt or target.
-    pub items: Vec<ItemExplanation>,
+    /// One explanation for each part of the target being explained. Contains at least one
+    /// element.
+    pub explanations: Vec<Explanation>,
 }
 
 impl ExplanationOutput {
-    fn from(mut response: ResponseExplanation) -> ExplanationOutput {
+    fn from(response: ResponseExplanation) -> ExplanationOutput {
         ExplanationOutput {
-            items: response.explanations.pop().unwrap().items,
+            explanations: response.explanations,
         }
     }
 }
@@ -142,6 +200,39 @@ pub struct ImageScore {
     pub score: f32,
 }
 
+impl ImageScore {
+    /// The rectangle of this score in pixel coordinates of the original image, i.e. the image
+    /// before it has been center cropped and resized by [`crate::Modality::from_image`] or
+    /// [`crate::Modality::from_image_path`]. The coordinates of the score itself are relative to
+    /// the cropped image. Useful for drawing the explanation on top of the original image.
+    pub fn to_original_pixels(&self, original_width: u32, original_height: u32) -> PixelRect {
+        self.to_original_pixels_in_region(ImageRegion::new(0, 0, original_width, original_height))
+    }
+
+    /// Like [`Self::to_original_pixels`], for images created with
+    /// [`crate::Modality::from_image_region`]. `region` is the region the model has been shown.
+    /// The offset of the region within the original image is taken into account.
+    pub fn to_original_pixels_in_region(&self, region: ImageRegion) -> PixelRect {
+        let (x, y, size) = image_preprocessing::center_crop(region.width, region.height);
+        let size = size as f32;
+        PixelRect {
+            left: (region.left + x) as f32 + self.left * size,
+            top: (region.top + y) as f32 + self.top * size,
+            width: self.width * size,
+            height: self.height * size,
+        }
+    }
+}
+
+/// A rectangle within an image in pixel coordinates.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PixelRect {
+    pub left: f32,
+    pub top: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
 impl From<ImageScoreWithRect> for ImageScore {
     fn from(value: ImageScoreWithRect) -> Self {
         Self {
@@ -170,11 +261,193 @@ impl Task for TaskExplanation<'_> {
             prompt: self.prompt.borrow(),
             target: self.target,
             prompt_granularity: self.granularity.prompt,
+            target_granularity: self.granularity.target,
         };
         client.post(format!("{base}/explain")).json(&body)
     }
 
-    fn body_to_output(&self, response: Self::ResponseBody) -> Self::Output {
-        ExplanationOutput::from(response)
+    fn body_to_output(&self, response: Self::ResponseBody) -> Result<Self::Output, Error> {
+        if response.explanations.is_empty() {
+            return Err(Error::EmptyResponse);
+        }
+        Ok(ExplanationOutput::from(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn granularity_builder_serializes_prompt_and_target_granularity() {
+        // Given a granularity with both prompt and target granularity set
+        let granularity = Granularity::default()
+            .with_prompt_granularity(PromptGranularity::Sentence)
+            .with_target_granularity(TargetGranularity::Token);
+
+        // When it is serialized as part of an explanation body
+        let body = BodyExplanation {
+            prompt: Prompt::from_text("An apple a day"),
+            target: " keeps the doctor away",
+            prompt_granularity: granularity.prompt,
+            target_granularity: granularity.target,
+            model: "luminous-base",
+        };
+        let json = serde_json::to_value(&body).unwrap();
+
+        // Then both granularities are part of the body
+        assert_eq!(
+            json["prompt_granularity"],
+            serde_json::json!({"type": "sentence"})
+        );
+        assert_eq!(json["target_granularity"], "token");
+    }
+
+    #[test]
+    fn image_score_is_mapped_to_original_pixels() {
+        // Given a score relative to the center cropped square of a 600x400 image. The crop cuts
+        // 100 pixels from the left and the right.
+        let score = ImageScore {
+            left: 0.5,
+            top: 0.25,
+            width: 0.25,
+            height: 0.5,
+            score: 1.0,
+        };
+
+        // When mapping it to the original image
+        let rect = score.to_original_pixels(600, 400);
+
+        // Then the crop offset and the scale are reverted
+        assert_eq!(
+            rect,
+            PixelRect {
+                left: 300.0,
+                top: 100.0,
+                width: 100.0,
+                height: 200.0
+            }
+        );
+    }
+
+    #[test]
+    fn image_score_of_region_is_mapped_to_original_pixels() {
+        // Given a score relative to a 300x200 region at (50, 20) of an image. Center cropping the
+        // region cuts 50 pixels from its left and right.
+        let score = ImageScore {
+            left: 0.5,
+            top: 0.25,
+            width: 0.25,
+            height: 0.5,
+            score: 1.0,
+        };
+
+        // When mapping it to the original image
+        let rect = score.to_original_pixels_in_region(ImageRegion::new(50, 20, 300, 200));
+
+        // Then the offsets of the region and of the crop are both added
+        assert_eq!(
+            rect,
+            PixelRect {
+                left: 200.0,
+                top: 70.0,
+                width: 50.0,
+                height: 100.0
+            }
+        );
+    }
+
+    #[test]
+    fn default_granularity_is_omitted_from_body() {
+        // Given the default granularity
+        let granularity = Granularity::default();
+
+        // When it is serialized as part of an explanation body
+        let body = BodyExplanation {
+            prompt: Prompt::from_text("An apple a day"),
+            target: " keeps the doctor away",
+            prompt_granularity: granularity.prompt,
+            target_granularity: granularity.target,
+            model: "luminous-base",
+        };
+        let json = serde_json::to_value(&body).unwrap();
+
+        // Then the server defaults are used
+        assert!(json.get("prompt_granularity").is_none());
+        assert!(json.get("target_granularity").is_none());
+    }
+
+    #[test]
+    fn batch_is_split_into_one_task_per_target() {
+        let task = TaskBatchExplanation {
+            prompt: Prompt::from_text("An apple a day"),
+            targets: vec![" keeps the doctor away", " is healthy"],
+            granularity: Granularity::default().with_target_granularity(TargetGranularity::Token),
+        };
+
+        let tasks: Vec<_> = task.tasks().collect();
+
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[0].target, " keeps the doctor away");
+        assert_eq!(tasks[1].target, " is healthy");
+        assert!(tasks
+            .iter()
+            .all(|t| t.prompt == task.prompt && t.granularity == task.granularity));
+    }
+
+    /// Response body with one target explanation for each of the given scores
+    fn response_with_scores(scores: &[f32]) -> String {
+        let explanations = scores
+            .iter()
+            .map(|score| {
+                serde_json::json!({
+                    "items": [{"type": "target", "scores": [{"start": 0, "length": 6, "score": score}]}]
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({ "explanations": explanations }).to_string()
+    }
+
+    #[test]
+    fn response_without_explanations_is_empty() {
+        let task = TaskExplanation {
+            prompt: Prompt::from_text("An apple a day"),
+            target: " keeps the doctor away",
+            granularity: Granularity::default(),
+        };
+        let response = serde_json::from_str(&response_with_scores(&[])).unwrap();
+
+        let result = task.body_to_output(response);
+
+        assert!(matches!(result, Err(Error::EmptyResponse)));
+    }
+
+    #[test]
+    fn single_explanation_is_preserved() {
+        let response = serde_json::from_str(&response_with_scores(&[0.5])).unwrap();
+
+        let output = ExplanationOutput::from(response);
+
+        assert_eq!(output.explanations.len(), 1);
+    }
+
+    #[test]
+    fn all_explanations_are_preserved() {
+        // Given a response with several explanations
+        let response = serde_json::from_str(&response_with_scores(&[0.5, 0.25, 0.125])).unwrap();
+
+        // When
+        let output = ExplanationOutput::from(response);
+
+        // Then none of them is dropped and the order is kept
+        let scores = output
+            .explanations
+            .iter()
+            .map(|explanation| match &explanation.items[..] {
+                [ItemExplanation::Target { scores }] => scores[0].score,
+                _ => panic!("Expected a single target explanation"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(scores, [0.5, 0.25, 0.125]);
     }
 }
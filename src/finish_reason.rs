@@ -0,0 +1,74 @@
+use serde::Deserialize;
+
+/// The reason why the model stopped generating new tokens. The completion and the chat endpoint
+/// report these with different strings, which are unified by this type.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(from = "String")]
+pub enum FinishReason {
+    /// The model generated an end of text token or one of the stop sequences.
+    Stop,
+    /// The maximum number of tokens has been reached.
+    Length,
+    /// The content has been omitted by a content filter.
+    ContentFilter,
+    /// The model called a tool.
+    ToolCalls,
+    /// A reason not known to this version of the client. Contains the string reported by the API.
+    Unknown(String),
+}
+
+impl From<&str> for FinishReason {
+    fn from(reason: &str) -> Self {
+        match reason {
+            // Chat endpoint
+            "stop" => FinishReason::Stop,
+            "length" => FinishReason::Length,
+            "content_filter" => FinishReason::ContentFilter,
+            "tool_calls" => FinishReason::ToolCalls,
+            // Completion endpoint
+            "end_of_text" | "stop_sequence_reached" => FinishReason::Stop,
+            "maximum_tokens" => FinishReason::Length,
+            other => FinishReason::Unknown(other.to_owned()),
+        }
+    }
+}
+
+impl From<String> for FinishReason {
+    fn from(reason: String) -> Self {
+        FinishReason::from(reason.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chat_finish_reasons_are_mapped() {
+        assert_eq!(FinishReason::from("stop"), FinishReason::Stop);
+        assert_eq!(FinishReason::from("length"), FinishReason::Length);
+        assert_eq!(
+            FinishReason::from("content_filter"),
+            FinishReason::ContentFilter
+        );
+        assert_eq!(FinishReason::from("tool_calls"), FinishReason::ToolCalls);
+    }
+
+    #[test]
+    fn completion_finish_reasons_are_mapped() {
+        assert_eq!(FinishReason::from("end_of_text"), FinishReason::Stop);
+        assert_eq!(
+            FinishReason::from("stop_sequence_reached"),
+            FinishReason::Stop
+        );
+        assert_eq!(FinishReason::from("maximum_tokens"), FinishReason::Length);
+    }
+
+    #[test]
+    fn unknown_finish_reason_is_preserved() {
+        assert_eq!(
+            FinishReason::from("something_new"),
+            FinishReason::Unknown("something_new".to_owned())
+        );
+    }
+}
@@ -1,12 +1,23 @@
-use std::{borrow::Cow, pin::Pin, time::Duration};
+use std::{
+    borrow::Cow,
+    future::Future,
+    io::Write,
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+use flate2::{write::GzEncoder, Compression};
 
 use futures_util::{stream::StreamExt, Stream};
-use reqwest::{header, ClientBuilder, RequestBuilder, Response, StatusCode};
-use serde::Deserialize;
+use reqwest::{
+    header::{self, HeaderMap},
+    ClientBuilder, RequestBuilder, Response, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize};
 use thiserror::Error as ThisError;
 use tokenizers::Tokenizer;
 
-use crate::{How, StreamJob};
+use crate::{ClientTimeout, How, Priority, StreamJob, DEFAULT_CLIENT_TIMEOUT};
 use async_stream::stream;
 
 /// A job send to the Aleph Alpha Api using the http client. A job wraps all the knowledge required
@@ -27,8 +38,15 @@ pub trait Job {
     /// already set.
     fn build_request(&self, client: &reqwest::Client, base: &str) -> RequestBuilder;
 
-    /// Parses the response of the server into higher level structs for the user.
-    fn body_to_output(&self, response: Self::ResponseBody) -> Self::Output;
+    /// Parses the response of the server into higher level structs for the user. Fails if the
+    /// response lacks content the output can not do without, see [`Error::EmptyResponse`].
+    fn body_to_output(&self, response: Self::ResponseBody) -> Result<Self::Output, Error>;
+
+    /// Checks the job for settings the API would reject, before it is sent. Defaults to accepting
+    /// every job.
+    fn validate(&self) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 /// A task send to the Aleph Alpha Api using the http client. Requires to specify a model before it
@@ -44,8 +62,15 @@ pub trait Task {
     /// already set.
     fn build_request(&self, client: &reqwest::Client, base: &str, model: &str) -> RequestBuilder;
 
-    /// Parses the response of the server into higher level structs for the user.
-    fn body_to_output(&self, response: Self::ResponseBody) -> Self::Output;
+    /// Parses the response of the server into higher level structs for the user. Fails if the
+    /// response lacks content the output can not do without, see [`Error::EmptyResponse`].
+    fn body_to_output(&self, response: Self::ResponseBody) -> Result<Self::Output, Error>;
+
+    /// Checks the task for settings the API would reject, before it is sent. Defaults to accepting
+    /// every task.
+    fn validate(&self) -> Result<(), Error> {
+        Ok(())
+    }
 
     /// Turn your task into [`Job`] by annotating it with a model name.
     fn with_model<'a>(&'a self, model: &'a str) -> MethodJob<'a, Self>
@@ -77,9 +102,13 @@ where
         self.task.build_request(client, base, self.model)
     }
 
-    fn body_to_output(&self, response: T::ResponseBody) -> T::Output {
+    fn body_to_output(&self, response: T::ResponseBody) -> Result<T::Output, Error> {
         self.task.body_to_output(response)
     }
+
+    fn validate(&self) -> Result<(), Error> {
+        self.task.validate()
+    }
 }
 
 /// Sends HTTP request to the Aleph Alpha API
@@ -87,49 +116,189 @@ pub struct HttpClient {
     base: String,
     http: reqwest::Client,
     api_token: Option<String>,
+    /// Timeout used for requests which do not override [`How::client_timeout`]. `None` waits for
+    /// the server as long as it takes.
+    default_timeout: Option<Duration>,
+    /// Whether to accept invalid TLS certificates. Only intended for testing.
+    accept_invalid_certs: bool,
+    /// Maximum number of idle connections kept alive per host. `None` keeps all of them.
+    pool_max_idle_per_host: Option<usize>,
+    /// Whether to speak HTTP/2 right away, without negotiating it first.
+    http2_prior_knowledge: bool,
+}
+
+/// Rejects base URLs which would only fail later, on the first request.
+fn validate_base_url(host: &str) -> Result<(), Error> {
+    let invalid = |reason: String| Error::InvalidBaseUrl {
+        url: host.to_owned(),
+        reason,
+    };
+    let url = reqwest::Url::parse(host).map_err(|e| invalid(e.to_string()))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid(format!(
+            "unsupported scheme '{}', expected 'http' or 'https'",
+            url.scheme()
+        )));
+    }
+    if url.host_str().is_none() {
+        return Err(invalid("missing host".to_owned()));
+    }
+    Ok(())
 }
 
 impl HttpClient {
     /// In production you typically would want set this to <https://inference-api.pharia.your-company.com>.
     /// Yet you may want to use a different instance for testing.
     pub fn new(host: String, api_token: Option<String>) -> Result<Self, Error> {
+        validate_base_url(&host)?;
         let http = ClientBuilder::new().build()?;
 
         Ok(Self {
             base: host,
             http,
             api_token,
+            default_timeout: Some(DEFAULT_CLIENT_TIMEOUT),
+            accept_invalid_certs: false,
+            pool_max_idle_per_host: None,
+            http2_prior_knowledge: false,
         })
     }
 
+    /// Rebuilds the underlying HTTP client, so it accepts (or rejects) invalid TLS certificates.
+    pub fn accept_invalid_certs(self, accept_invalid_certs: bool) -> Result<Self, Error> {
+        Self {
+            accept_invalid_certs,
+            ..self
+        }
+        .rebuild_http()
+    }
+
+    /// Rebuilds the underlying HTTP client, limiting the number of idle connections kept alive.
+    pub fn with_pool_max_idle_per_host(self, max_idle: usize) -> Result<Self, Error> {
+        Self {
+            pool_max_idle_per_host: Some(max_idle),
+            ..self
+        }
+        .rebuild_http()
+    }
+
+    /// Rebuilds the underlying HTTP client, so it only speaks HTTP/2.
+    pub fn with_http2_prior_knowledge(self) -> Result<Self, Error> {
+        Self {
+            http2_prior_knowledge: true,
+            ..self
+        }
+        .rebuild_http()
+    }
+
+    /// Applies the connection options to a freshly built HTTP client.
+    fn rebuild_http(self) -> Result<Self, Error> {
+        let mut builder =
+            ClientBuilder::new().danger_accept_invalid_certs(self.accept_invalid_certs);
+        if let Some(max_idle) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        let http = builder.build()?;
+        Ok(Self { http, ..self })
+    }
+
+    /// Timeout used for all requests which leave [`How::client_timeout`] at
+    /// [`ClientTimeout::Default`]. `None` disables the timeout.
+    pub fn with_timeout(self, default_timeout: Option<Duration>) -> Self {
+        Self {
+            default_timeout,
+            ..self
+        }
+    }
+
+    /// The timeout of the request. Explicitly set timeouts take precedence over the default timeout
+    /// of the client. A [`How::deadline`] shortens the timeout to the time left until then.
+    /// `None` if the timeout has been disabled with [`ClientTimeout::None`] and there is no
+    /// deadline.
+    /// Fails with [`Error::ClientTimeout`] if the deadline has already passed.
+    fn timeout(&self, how: &How) -> Result<Option<Duration>, Error> {
+        let timeout = match how.client_timeout {
+            ClientTimeout::Default => self.default_timeout,
+            ClientTimeout::Fixed(timeout) => Some(timeout),
+            ClientTimeout::None => None,
+        };
+        let Some(deadline) = how.deadline else {
+            return Ok(timeout);
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(Error::ClientTimeout(Duration::ZERO));
+        }
+        Ok(Some(
+            timeout.map_or(remaining, |timeout| timeout.min(remaining)),
+        ))
+    }
+
+    /// The JSON body which would be sent to the API for `task`, built like the body of an actual
+    /// request, but not sent. `Null` if the request has no body.
+    pub fn request_json<T: Job>(&self, task: &T) -> Result<serde_json::Value, Error> {
+        let request = task.build_request(&self.http, &self.base).build()?;
+        let Some(bytes) = request.body().and_then(|body| body.as_bytes()) else {
+            return Ok(serde_json::Value::Null);
+        };
+        serde_json::from_slice(bytes).map_err(|e| Error::Decode {
+            deserialization_error: e.to_string(),
+            body: String::from_utf8_lossy(bytes).into_owned(),
+        })
+    }
+
+    /// Drops the connection pool. Idle keep-alive connections are closed immediately.
+    pub fn shutdown(self) {
+        drop(self.http);
+    }
+
     /// Construct and execute a request building on top of a `RequestBuilder`
     async fn response(&self, builder: RequestBuilder, how: &How) -> Result<Response, Error> {
-        let query = if how.be_nice {
-            [("nice", "true")].as_slice()
-        } else {
-            // nice=false is default, so we just omit it.
-            [].as_slice()
-        };
+        let query = how.effective_priority().query();
 
-        let api_token = how
-            .api_token
-            .as_ref()
-            .or(self.api_token.as_ref())
-            .expect("API token needs to be set on client construction or per request");
-        let response = builder
-            .query(query)
-            .header(header::AUTHORIZATION, Self::header_from_token(api_token))
-            .timeout(how.client_timeout)
-            .send()
+        let timeout = self.timeout(how)?;
+        let mut builder = self.authenticate(builder.query(query).query(&how.query_params), how);
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(request_id) = &how.request_id {
+            builder = builder.header(REQUEST_ID_HEADER, request_id);
+        }
+        if let Some(cache_key) = &how.cache_key {
+            builder = builder.header(CACHE_KEY_HEADER, cache_key);
+        }
+        if let Some(trace_context) = &how.trace_context {
+            builder = builder.header("traceparent", trace_context.traceparent());
+            if let Some(state) = &trace_context.state {
+                builder = builder.header("tracestate", state);
+            }
+        }
+        let (client, request) = builder.build_split();
+        let mut request = request?;
+        let size = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map_or(0, <[u8]>::len);
+        if size > how.max_request_body_size {
+            return Err(Error::RequestTooLarge {
+                size,
+                limit: how.max_request_body_size,
+            });
+        }
+        if how.compress_request {
+            gzip_body(&mut request);
+        }
+        let response = client
+            .execute(request)
             .await
-            .map_err(|reqwest_error| {
-                if reqwest_error.is_timeout() {
-                    Error::ClientTimeout(how.client_timeout)
-                } else {
-                    reqwest_error.into()
-                }
+            .map_err(|reqwest_error| match timeout {
+                Some(timeout) if reqwest_error.is_timeout() => Error::ClientTimeout(timeout),
+                _ => reqwest_error.into(),
             })?;
-        translate_http_error(response).await
+        translate_http_error(response, how.effective_priority() == Priority::Low).await
     }
 
     /// Execute a task with the aleph alpha API and fetch its result.
@@ -158,11 +327,59 @@ impl HttpClient {
     /// }
     /// ```
     pub async fn output_of<T: Job>(&self, task: &T, how: &How) -> Result<T::Output, Error> {
-        let builder = task.build_request(&self.http, &self.base);
-        let response = self.response(builder, how).await?;
-        let response_body: T::ResponseBody = response.json().await?;
-        let answer = task.body_to_output(response_body);
-        Ok(answer)
+        cancellable(how, async {
+            task.validate()?;
+            let builder = task.build_request(&self.http, &self.base);
+            let response = self.response(builder, how).await?;
+            let response_body: T::ResponseBody = parse_body(&response.text().await?)?;
+            task.body_to_output(response_body)
+        })
+        .await
+    }
+
+    /// Like [`Self::output_of`], but additionally returns the headers of the response.
+    pub async fn output_of_with_headers<T: Job>(
+        &self,
+        task: &T,
+        how: &How,
+    ) -> Result<(T::Output, HeaderMap), Error> {
+        cancellable(how, async {
+            task.validate()?;
+            let builder = task.build_request(&self.http, &self.base);
+            let response = self.response(builder, how).await?;
+            let headers = response.headers().clone();
+            let response_body: T::ResponseBody = parse_body(&response.text().await?)?;
+            let answer = task.body_to_output(response_body)?;
+            Ok((answer, headers))
+        })
+        .await
+    }
+
+    /// Like [`Self::output_of`], but additionally returns the raw response.
+    pub async fn output_of_with_raw<T: Job>(
+        &self,
+        task: &T,
+        how: &How,
+    ) -> Result<(T::Output, RawResponse), Error> {
+        cancellable(how, async {
+            task.validate()?;
+            let builder = task.build_request(&self.http, &self.base);
+            let response = self.response(builder, how).await?;
+            let request_id = response
+                .headers()
+                .get(REQUEST_ID_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned);
+            let body: serde_json::Value = parse_body(&response.text().await?)?;
+            let response_body =
+                T::ResponseBody::deserialize(&body).map_err(|e| Error::InvalidResponse {
+                    deserialization_error: e.to_string(),
+                    body: body.clone(),
+                })?;
+            let answer = task.body_to_output(response_body)?;
+            Ok((answer, RawResponse { body, request_id }))
+        })
+        .await
     }
 
     pub async fn stream_output_of<T: StreamJob>(
@@ -173,17 +390,56 @@ impl HttpClient {
     where
         T::Output: 'static,
     {
+        task.validate()?;
         let builder = task.build_request(&self.http, &self.base);
         let response = self.response(builder, how).await?;
         let mut stream = response.bytes_stream();
 
+        let idle_timeout = how.stream_idle_timeout;
+        let max_event_size = how.max_stream_event_size;
+
         Ok(Box::pin(stream! {
-            while let Some(item) = stream.next().await {
+            // Events may be split across several chunks of bytes. Bytes are collected until the
+            // event is complete.
+            let mut buffer = Vec::new();
+            loop {
+                let next = match idle_timeout {
+                    Some(idle_timeout) => {
+                        match tokio::time::timeout(idle_timeout, stream.next()).await {
+                            Ok(next) => next,
+                            Err(_) => {
+                                yield Err(Error::StreamIdleTimeout(idle_timeout));
+                                break;
+                            }
+                        }
+                    }
+                    None => stream.next().await,
+                };
+                let Some(item) = next else {
+                    // The last event may lack a trailing boundary
+                    let events = Self::parse_stream_event::<T::ResponseBody>(&buffer);
+                    for event in events {
+                        yield event.map(|b| T::body_to_output(b));
+                    }
+                    break;
+                };
                 match item {
                     Ok(bytes) => {
-                        let events = Self::parse_stream_event::<T::ResponseBody>(bytes.as_ref());
-                        for event in events {
-                            yield event.map(|b| T::body_to_output(b));
+                        buffer.extend_from_slice(&bytes);
+                        while let Some(end) = event_end(&buffer) {
+                            let event = buffer.drain(..end).collect::<Vec<_>>();
+                            let events = Self::parse_stream_event::<T::ResponseBody>(&event);
+                            for event in events {
+                                yield event.map(|b| T::body_to_output(b));
+                            }
+                        }
+                        if buffer.len() > max_event_size {
+                            yield Err(Error::InvalidStream {
+                                deserialization_error: format!(
+                                    "Stream event exceeds {max_event_size} bytes without ending"
+                                ),
+                            });
+                            break;
                         }
                     }
                     Err(e) => {
@@ -196,6 +452,9 @@ impl HttpClient {
 
     /// Take a byte slice (of a SSE) and parse it into a provided response body.
     /// Each SSE event is expected to contain one or multiple JSON bodies prefixed by `data: `.
+    /// The `[DONE]` sentinel some servers send at the end of a stream carries no body and is
+    /// skipped. Bodies around it are still parsed, and streams without it are fine too, as the
+    /// stream ends once the server closes it anyway.
     fn parse_stream_event<StreamBody>(bytes: &[u8]) -> Vec<Result<StreamBody, Error>>
     where
         StreamBody: for<'de> Deserialize<'de>,
@@ -203,6 +462,7 @@ impl HttpClient {
         String::from_utf8_lossy(bytes)
             .split("data: ")
             .skip(1)
+            .filter(|s| s.trim() != "[DONE]")
             .map(|s| {
                 serde_json::from_str(s).map_err(|e| Error::InvalidStream {
                     deserialization_error: e.to_string(),
@@ -211,29 +471,32 @@ impl HttpClient {
             .collect()
     }
 
-    fn header_from_token(api_token: &str) -> header::HeaderValue {
-        let mut auth_value = header::HeaderValue::from_str(&format!("Bearer {api_token}")).unwrap();
-        // Consider marking security-sensitive headers with `set_sensitive`.
-        auth_value.set_sensitive(true);
-        auth_value
+    /// Adds the authentication header of [`How::auth_scheme`] to the request.
+    fn authenticate(&self, builder: RequestBuilder, how: &How) -> RequestBuilder {
+        let api_token = how.api_token.as_deref().or(self.api_token.as_deref());
+        let (name, value) = how.auth_scheme.header(api_token);
+        match header::HeaderValue::from_str(&value) {
+            Ok(mut value) => {
+                // Keep the credentials out of debug output
+                value.set_sensitive(true);
+                builder.header(name, value)
+            }
+            // Let reqwest report the invalid value once the request is built
+            Err(_) => builder.header(name, value),
+        }
     }
 
-    pub async fn tokenizer_by_model(
-        &self,
-        model: &str,
-        api_token: Option<String>,
-    ) -> Result<Tokenizer, Error> {
-        let api_token = api_token
-            .as_ref()
-            .or(self.api_token.as_ref())
-            .expect("API token needs to be set on client construction or per request");
+    pub async fn tokenizer_by_model(&self, model: &str, how: &How) -> Result<Tokenizer, Error> {
         let response = self
-            .http
-            .get(format!("{}/models/{model}/tokenizer", self.base))
-            .header(header::AUTHORIZATION, Self::header_from_token(api_token))
+            .authenticate(
+                self.http
+                    .get(format!("{}/models/{model}/tokenizer", self.base)),
+                how,
+            )
             .send()
             .await?;
-        let response = translate_http_error(response).await?;
+        // The priority is not sent along with requests for the tokenizer
+        let response = translate_http_error(response, false).await?;
         let bytes = response.bytes().await?;
         let tokenizer = Tokenizer::from_bytes(bytes).map_err(|e| Error::InvalidTokenizer {
             deserialization_error: e.to_string(),
@@ -242,7 +505,29 @@ impl HttpClient {
     }
 }
 
-async fn translate_http_error(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+/// Runs the request until it completes or [`How::cancellation`] is cancelled, whatever happens
+/// first.
+#[cfg_attr(not(feature = "cancellation"), allow(unused_variables))]
+async fn cancellable<T>(
+    how: &How,
+    request: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    #[cfg(feature = "cancellation")]
+    if let Some(cancellation) = &how.cancellation {
+        return cancellation
+            .token()
+            .run_until_cancelled(request)
+            .await
+            .unwrap_or(Err(Error::Cancelled));
+    }
+    request.await
+}
+
+/// `nice` tells whether the request has been sent with low priority.
+async fn translate_http_error(
+    response: reqwest::Response,
+    nice: bool,
+) -> Result<reqwest::Response, Error> {
     let status = response.status();
     if !status.is_success() {
         // Store body in a variable, so we can use it, even if it is not an Error emitted by
@@ -253,13 +538,19 @@ async fn translate_http_error(response: reqwest::Response) -> Result<reqwest::Re
         let api_error: Result<ApiError, _> = serde_json::from_str(&body);
         let translated_error = match status {
             StatusCode::TOO_MANY_REQUESTS => Error::TooManyRequests,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Error::Unauthorized {
+                status: status.as_u16(),
+                body,
+            },
             StatusCode::SERVICE_UNAVAILABLE => {
                 // Presence of `api_error` implies the error originated from the API itself (rather
                 // than the intermediate proxy) and so we can decode it as such.
-                if api_error.is_ok_and(|error| error.code == "QUEUE_FULL") {
-                    Error::Busy
-                } else {
-                    Error::Unavailable
+                match api_error {
+                    Ok(error) if error.code == "QUEUE_FULL" => Error::Busy {
+                        nice,
+                        queued: error.queued,
+                    },
+                    _ => Error::Unavailable,
                 }
             }
             _ => Error::Http {
@@ -282,6 +573,72 @@ struct ApiError<'a> {
     /// E.g. Differentiating between request rate limiting and parallel tasks limiting which both
     /// are 429 (the former is emitted by NGinx though).
     code: Cow<'a, str>,
+    /// Whether a rejected request had been queued before. Not reported for every error.
+    #[serde(default)]
+    queued: Option<bool>,
+}
+
+/// Position just after the first event boundary (an empty line) in the bytes of a server sent
+/// event stream. `None` if the bytes do not contain a complete event yet.
+fn event_end(bytes: &[u8]) -> Option<usize> {
+    [&b"\n\n"[..], b"\r\n\r\n"]
+        .into_iter()
+        .filter_map(|boundary| {
+            bytes
+                .windows(boundary.len())
+                .position(|window| window == boundary)
+                .map(|position| position + boundary.len())
+        })
+        .min()
+}
+
+/// Compresses the body of the request with gzip, if it has any.
+fn gzip_body(request: &mut reqwest::Request) {
+    let Some(body) = request.body().and_then(|body| body.as_bytes()) else {
+        return;
+    };
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // Writing into a `Vec` can not fail
+    encoder.write_all(body).unwrap();
+    let compressed = encoder.finish().unwrap();
+    *request.body_mut() = Some(compressed.into());
+    request.headers_mut().insert(
+        header::CONTENT_ENCODING,
+        header::HeaderValue::from_static("gzip"),
+    );
+}
+
+/// Parses the body of a successful response. Bodies which are not JSON at all, e.g. the login page
+/// of a misconfigured gateway, are reported as [`Error::Decode`]. JSON bodies which do not match the
+/// expected structure are reported as [`Error::InvalidResponse`].
+fn parse_body<B: DeserializeOwned>(text: &str) -> Result<B, Error> {
+    serde_json::from_str(text).map_err(|e| match serde_json::from_str(text) {
+        Ok(body) => Error::InvalidResponse {
+            deserialization_error: e.to_string(),
+            body,
+        },
+        Err(_) => Error::Decode {
+            deserialization_error: e.to_string(),
+            body: text.to_owned(),
+        },
+    })
+}
+
+/// Header used to correlate requests in the logs of client and server. See [`How::request_id`].
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Header grouping requests with the same prompt prefix. See [`How::cache_key`].
+const CACHE_KEY_HEADER: &str = "x-prompt-cache-key";
+
+/// The response of the server as it has been sent, before parsing it into an output. Returned by
+/// [`crate::Client::output_of_with_raw`] for debugging.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawResponse {
+    /// JSON body of the response.
+    pub body: serde_json::Value,
+    /// Value of the `X-Request-Id` header of the response, if sent by the server. Quote it when
+    /// reaching out to support about a specific request.
+    pub request_id: Option<String>,
 }
 
 /// Errors returned by the Aleph Alpha Client
@@ -293,22 +650,61 @@ pub enum Error {
         bit, otherwise these error will persist. Sorry for this, but we try to prevent DOS attacks."
     )]
     TooManyRequests,
-    /// Model is busy. Most likely due to many other users requesting its services right now.
+    /// Model is busy. Most likely due to many other users requesting its services right now, or due
+    /// to the low priority of the request.
     #[error(
         "Sorry the request to the Aleph Alpha API has been rejected due to the requested model \
         being very busy at the moment. We found it unlikely that your request would finish in a \
         reasonable timeframe, so it was rejected right away, rather than make you wait. You are \
         welcome to retry your request any time."
     )]
-    Busy,
+    Busy {
+        /// Whether the request has been sent with low priority, see [`How::be_nice`]. If so, the
+        /// request may well have been accepted with normal priority.
+        nice: bool,
+        /// Whether the request had been queued before it was rejected, if reported by the API.
+        queued: Option<bool>,
+    },
+    /// The API rejected the token, either because it is invalid (401) or because it lacks the
+    /// permission for the request (403). See [`crate::Client::verify_token`] to detect this
+    /// upfront.
+    #[error("The API token has been rejected with status code {status}. Body:\n{body}")]
+    Unauthorized { status: u16, body: String },
     /// The API itself is unavailable, most likely due to restart.
     #[error(
         "The service is currently unavailable. This is likely due to restart. Please try again \
         later."
     )]
     Unavailable,
+    /// The server answered successfully, but without any completion, chat choice or explanation,
+    /// e.g. because the content has been filtered.
+    #[error("The response of the server does not contain any completion, choice or explanation.")]
+    EmptyResponse,
     #[error("No response received within given timeout: {0:?}")]
     ClientTimeout(Duration),
+    /// A stream did not deliver any data within [`How::stream_idle_timeout`]. The stream ends
+    /// after this error.
+    #[error("Stream stalled. No data received within: {0:?}")]
+    StreamIdleTimeout(Duration),
+    /// The request body exceeds [`How::max_request_body_size`] and has not been sent.
+    #[error(
+        "The request body has a size of {size} bytes, which exceeds the limit of {limit} bytes. \
+        The request has not been sent."
+    )]
+    RequestTooLarge { size: usize, limit: usize },
+    /// The request has been cancelled by the cancellation set in `How`, before a response has
+    /// been received.
+    #[error("The request has been cancelled.")]
+    Cancelled,
+    /// A method trying several models has been called without any model to try.
+    #[error("No model has been specified. The request has not been sent.")]
+    NoModel,
+    /// An environment variable required to configure the client is not set.
+    #[error("The environment variable {name} is not set.")]
+    MissingEnvVar { name: &'static str },
+    /// The base URL passed to the client is not an absolute `http` or `https` URL.
+    #[error("Invalid base URL '{url}': {reason}")]
+    InvalidBaseUrl { url: String, reason: String },
     /// An error on the Http Protocol level.
     #[error("HTTP request failed with status code {}. Body:\n{}", status, body)]
     Http { status: u16, body: String },
@@ -323,17 +719,181 @@ pub enum Error {
         deserialization_error
     )]
     InvalidStream { deserialization_error: String },
+    /// The body of a successful response does not match the structure expected for the task.
+    /// Carries the raw body, so you can inspect what the server actually sent.
+    #[error(
+        "Response could not be correctly deserialized. Caused by:\n{}\nBody:\n{}",
+        deserialization_error,
+        body
+    )]
+    InvalidResponse {
+        deserialization_error: String,
+        body: serde_json::Value,
+    },
+    /// A successful response lacks information the task has asked for, e.g. because the server
+    /// does not support the option requesting it.
+    #[error("The response is incomplete: {reason}")]
+    IncompleteResponse { reason: String },
+    /// The body of a successful response is not JSON at all. This usually happens if a
+    /// misconfigured proxy or gateway answers in place of the API, e.g. with a login page. Carries
+    /// the body as text.
+    #[error(
+        "Response is not valid JSON. Caused by:\n{}\nBody:\n{}",
+        deserialization_error,
+        body
+    )]
+    Decode {
+        deserialization_error: String,
+        body: String,
+    },
+    /// The content of a chat response does not conform to the JSON schema requested in its
+    /// [`crate::ResponseFormat`].
+    #[error("The response does not match the requested JSON schema. Caused by:\n{reason}")]
+    SchemaViolation { reason: String },
+    /// The prompt template of a model is missing or can not be rendered.
+    #[error("The prompt template can not be rendered. Caused by:\n{reason}")]
+    InvalidPromptTemplate { reason: String },
+    /// The prompt can not be processed by the model, e.g. because it contains images and the
+    /// model is not multimodal. See [`crate::Client::validate_prompt`].
+    #[error("The prompt is not supported by the model. Caused by:\n{reason}")]
+    InvalidPrompt { reason: String },
+    /// The sampling settings of a task contradict each other, e.g. [`crate::Sampling::best_of`] is
+    /// smaller than the number of completions requested.
+    #[error("Invalid sampling settings: {reason}")]
+    InvalidSampling { reason: String },
+    /// The API returned an embedding with a different number of dimensions than requested via
+    /// [`crate::TaskSemanticEmbedding::compress_to_size`].
+    #[error("Expected an embedding with {expected} dimensions, but received {actual}.")]
+    UnexpectedEmbeddingSize { expected: usize, actual: usize },
     /// Most likely either TLS errors creating the Client, or IO errors.
     #[error(transparent)]
     Other(#[from] reqwest::Error),
 }
 
+impl Error {
+    /// Whether sending the same request again later may succeed. This is the case if the API is
+    /// temporarily overloaded or unavailable, or if the request timed out.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            Error::TooManyRequests
+            | Error::Busy { .. }
+            | Error::Unavailable
+            | Error::ClientTimeout(_)
+            | Error::StreamIdleTimeout(_) => true,
+            Error::Other(reqwest_error) => reqwest_error.is_timeout(),
+            _ => false,
+        }
+    }
+
+    /// Whether the connection to the API failed or broke down, e.g. in the middle of a stream.
+    /// Responses of the API, even unsuccessful ones, and timeouts do not count.
+    pub fn is_connection_lost(&self) -> bool {
+        match self {
+            Error::Other(reqwest_error) if !reqwest_error.is_timeout() => {
+                reqwest_error.is_connect()
+                    || reqwest_error.is_request()
+                    || reqwest_error.is_body()
+                    || reqwest_error.is_decode()
+            }
+            _ => false,
+        }
+    }
+
+    /// The HTTP status code returned by the API, if the error has been caused by an unsuccessful
+    /// response.
+    pub fn status_code(&self) -> Option<u16> {
+        match self {
+            Error::TooManyRequests => Some(StatusCode::TOO_MANY_REQUESTS.as_u16()),
+            Error::Busy { .. } | Error::Unavailable => {
+                Some(StatusCode::SERVICE_UNAVAILABLE.as_u16())
+            }
+            Error::Http { status, .. } | Error::Unauthorized { status, .. } => Some(*status),
+            Error::Other(reqwest_error) => reqwest_error.status().map(|status| status.as_u16()),
+            _ => None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{chat::ChatEvent, completion::CompletionEvent};
+    use crate::{chat::ChatEvent, completion::CompletionEvent, FinishReason};
 
     use super::*;
 
+    #[test]
+    fn explicit_request_timeout_wins_even_if_equal_to_default() {
+        // Given a client with a short default timeout
+        let client = HttpClient::new("http://localhost:8080".to_owned(), None)
+            .unwrap()
+            .with_timeout(Some(Duration::from_secs(1)));
+
+        // When a request explicitly asks for the default timeout of the library
+        let how = How {
+            client_timeout: ClientTimeout::Fixed(DEFAULT_CLIENT_TIMEOUT),
+            ..How::default()
+        };
+
+        // Then the timeout of the request is used
+        assert_eq!(client.timeout(&how).unwrap(), Some(DEFAULT_CLIENT_TIMEOUT));
+        assert_eq!(
+            client.timeout(&How::default()).unwrap(),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn deadline_applies_to_requests_without_timeout() {
+        // Given a request without timeout, but with a deadline
+        let client = HttpClient::new("http://localhost:8080".to_owned(), None).unwrap();
+        let how = How {
+            client_timeout: ClientTimeout::None,
+            deadline: Some(Instant::now() + Duration::from_secs(60)),
+            ..How::default()
+        };
+
+        // When
+        let timeout = client.timeout(&how).unwrap().unwrap();
+
+        // Then the time left until the deadline is used
+        assert!(timeout <= Duration::from_secs(60));
+        assert!(client
+            .timeout(&How {
+                client_timeout: ClientTimeout::None,
+                ..How::default()
+            })
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn valid_base_urls_are_accepted() {
+        for url in [
+            "http://localhost:8080",
+            "https://inference-api.pharia.your-company.com",
+        ] {
+            assert!(HttpClient::new(url.to_owned(), None).is_ok(), "{url}");
+        }
+    }
+
+    #[test]
+    fn malformed_base_urls_are_rejected() {
+        for url in [
+            "",
+            "not a url",
+            "inference-api.example.com",
+            "ftp://example.com",
+        ] {
+            // When
+            let result = HttpClient::new(url.to_owned(), None);
+
+            // Then
+            assert!(
+                matches!(result, Err(Error::InvalidBaseUrl { url: ref rejected, .. }) if rejected == url),
+                "{url}"
+            );
+        }
+    }
+
     #[test]
     fn stream_chunk_event_is_parsed() {
         // Given some bytes
@@ -345,7 +905,26 @@ mod tests {
 
         // Then the event is a stream chunk
         match event {
-            CompletionEvent::StreamChunk(chunk) => assert_eq!(chunk.index, 0),
+            CompletionEvent::StreamChunk(chunk) => {
+                assert_eq!(chunk.index, 0);
+                assert_eq!(chunk.num_tokens_generated, None);
+            }
+            _ => panic!("Expected a stream chunk"),
+        }
+    }
+
+    #[test]
+    fn stream_chunk_event_with_generated_tokens_is_parsed() {
+        // Given some bytes with a stream chunk reporting the number of generated tokens
+        let bytes = b"data: {\"type\":\"stream_chunk\",\"index\":0,\"completion\":\" The New York Times\",\"num_tokens_generated\":4}\n\n";
+
+        // When they are parsed
+        let events = HttpClient::parse_stream_event::<CompletionEvent>(bytes);
+        let event = events.first().unwrap().as_ref().unwrap();
+
+        // Then the number of generated tokens is part of the chunk
+        match event {
+            CompletionEvent::StreamChunk(chunk) => assert_eq!(chunk.num_tokens_generated, Some(4)),
             _ => panic!("Expected a stream chunk"),
         }
     }
@@ -388,6 +967,74 @@ mod tests {
         assert_eq!(event.choices[0].delta.role.as_ref().unwrap(), "assistant");
     }
 
+    #[test]
+    fn done_sentinel_between_events_is_skipped() {
+        // Given `[DONE]` in the middle of a chunk, followed by another event
+        let bytes = b"data: {\"choices\":[{\"finish_reason\":null,\"delta\":{\"content\":\"Hello\"}}]}\n\ndata: [DONE]\n\ndata: {\"choices\":[{\"finish_reason\":\"stop\",\"delta\":{\"content\":\"!\"}}]}\n\n";
+
+        // When they are parsed
+        let events = HttpClient::parse_stream_event::<ChatEvent>(bytes);
+
+        // Then the events on both sides of the sentinel are kept
+        let contents: Vec<_> = events
+            .into_iter()
+            .map(|event| event.unwrap().choices[0].delta.content.clone())
+            .collect();
+        assert_eq!(contents, ["Hello", "!"]);
+    }
+
+    #[test]
+    fn done_sentinel_alone_yields_no_event() {
+        assert!(HttpClient::parse_stream_event::<ChatEvent>(b"data: [DONE]\n\n").is_empty());
+        assert!(HttpClient::parse_stream_event::<ChatEvent>(b"data: [DONE]").is_empty());
+    }
+
+    #[test]
+    fn parsed_events_can_be_cloned() {
+        // Given a parsed completion and chat event
+        let completion_bytes = b"data: {\"type\":\"stream_summary\",\"index\":0,\"model_version\":\"2022-04\",\"finish_reason\":\"maximum_tokens\"}\n\n";
+        let chat_bytes = b"data: {\"choices\":[{\"finish_reason\":\"stop\",\"index\":0,\"delta\":{\"content\":\"Hi\"}}]}\n\n";
+        let completion_event = HttpClient::parse_stream_event::<CompletionEvent>(completion_bytes)
+            .pop()
+            .unwrap()
+            .unwrap();
+        let chat_event = HttpClient::parse_stream_event::<ChatEvent>(chat_bytes)
+            .pop()
+            .unwrap()
+            .unwrap();
+
+        // When buffering copies of them
+        let buffered = (completion_event.clone(), chat_event.clone());
+
+        // Then the copies equal the originals
+        assert_eq!(buffered, (completion_event, chat_event));
+    }
+
+    #[test]
+    fn event_end_is_found_after_empty_line() {
+        assert_eq!(event_end(b"data: {}\n\ndata: {"), Some(10));
+        assert_eq!(event_end(b"data: {}\r\n\r\n"), Some(12));
+        assert_eq!(event_end(b"data: {\"completion\":"), None);
+    }
+
+    #[test]
+    fn chat_stream_chunk_with_several_choices_is_parsed() {
+        // Given the bytes of a chunk with two concurrent generations
+        let bytes = b"data: {\"id\":\"a3ceca7f-32b2-4a6c-89e7-bc8eb5327f76\",\"choices\":[{\"finish_reason\":null,\"index\":0,\"delta\":{\"content\":\"Hello\"},\"logprobs\":null},{\"finish_reason\":null,\"index\":1,\"delta\":{\"content\":\"Hi\"},\"logprobs\":null}],\"created\":1729784197,\"model\":\"pharia-1-llm-7b-control\",\"system_fingerprint\":null,\"object\":\"chat.completion.chunk\",\"usage\":null}\n\n";
+
+        // When they are parsed
+        let events = HttpClient::parse_stream_event::<ChatEvent>(bytes);
+        let event = events.first().unwrap().as_ref().unwrap();
+
+        // Then each choice carries its index
+        let choices = event
+            .choices
+            .iter()
+            .map(|choice| (choice.index, choice.delta.content.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(choices, [(0, "Hello"), (1, "Hi")]);
+    }
+
     #[test]
     fn chat_stream_chunk_without_role_is_parsed() {
         // Given some bytes without a role
@@ -400,4 +1047,107 @@ mod tests {
         // Then the event is a chat stream chunk
         assert_eq!(event.choices[0].delta.content, "Hello! How can I help you today? If you have any questions or need assistance, feel free to ask.");
     }
+
+    #[test]
+    fn chat_stream_chunk_finish_reason_is_typed() {
+        // Given the bytes of the last chunk of a chat stream
+        let bytes = b"data: {\"id\":\"a3ceca7f-32b2-4a6c-89e7-bc8eb5327f76\",\"choices\":[{\"finish_reason\":\"length\",\"index\":0,\"delta\":{\"content\":\"\"},\"logprobs\":null}],\"created\":1729784197,\"model\":\"pharia-1-llm-7b-control\",\"system_fingerprint\":null,\"object\":\"chat.completion.chunk\",\"usage\":null}\n\n";
+
+        // When they are parsed
+        let events = HttpClient::parse_stream_event::<ChatEvent>(bytes);
+        let event = events.first().unwrap().as_ref().unwrap();
+
+        // Then the finish reason is available as typed enum
+        assert_eq!(
+            event.choices[0].typed_finish_reason(),
+            Some(FinishReason::Length)
+        );
+    }
+
+    #[test]
+    fn stream_summary_with_stop_sequence_is_parsed() {
+        // Given some bytes with a stream summary which finished on a stop sequence
+        let bytes = b"data: {\"type\":\"stream_summary\",\"index\":0,\"model_version\":\"2022-04\",\"finish_reason\":\"stop_sequence_reached\",\"stop_sequence\":\"\\n\"}\n\n";
+
+        // When they are parsed
+        let events = HttpClient::parse_stream_event::<CompletionEvent>(bytes);
+        let event = events.first().unwrap().as_ref().unwrap();
+
+        // Then the matched stop sequence is part of the summary
+        match event {
+            CompletionEvent::StreamSummary(summary) => {
+                assert_eq!(summary.stop_sequence.as_deref(), Some("\n"))
+            }
+            _ => panic!("Expected a stream summary"),
+        }
+    }
+
+    #[test]
+    fn chat_stream_chunk_stop_reasons_are_typed() {
+        let cases = [
+            ("stop", FinishReason::Stop),
+            ("length", FinishReason::Length),
+            ("content_filter", FinishReason::ContentFilter),
+            ("tool_calls", FinishReason::ToolCalls),
+        ];
+        for (reason, expected) in cases {
+            // Given the last chunk of a chat stream with the reason
+            let bytes = format!("data: {{\"id\":\"a3ceca7f-32b2-4a6c-89e7-bc8eb5327f76\",\"choices\":[{{\"finish_reason\":\"{reason}\",\"index\":0,\"delta\":{{\"content\":\"\"}},\"logprobs\":null}}],\"created\":1729784197,\"model\":\"pharia-1-llm-7b-control\",\"system_fingerprint\":null,\"object\":\"chat.completion.chunk\",\"usage\":null}}\n\n");
+
+            // When they are parsed
+            let events = HttpClient::parse_stream_event::<ChatEvent>(bytes.as_bytes());
+            let event = events.first().unwrap().as_ref().unwrap();
+
+            // Then the stop reason is mapped to its typed representation
+            assert_eq!(event.choices[0].typed_finish_reason(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn overload_and_timeouts_are_retriable() {
+        assert!(Error::TooManyRequests.is_retriable());
+        assert!(Error::Busy {
+            nice: false,
+            queued: None
+        }
+        .is_retriable());
+        assert!(Error::Unavailable.is_retriable());
+        assert!(Error::ClientTimeout(Duration::from_secs(1)).is_retriable());
+    }
+
+    #[test]
+    fn client_errors_are_not_retriable() {
+        let error = Error::Http {
+            status: 400,
+            body: "Bad Request".to_owned(),
+        };
+        assert!(!error.is_retriable());
+        let error = Error::InvalidStream {
+            deserialization_error: "EOF".to_owned(),
+        };
+        assert!(!error.is_retriable());
+    }
+
+    #[test]
+    fn status_codes_of_errors() {
+        assert_eq!(Error::TooManyRequests.status_code(), Some(429));
+        assert_eq!(
+            Error::Busy {
+                nice: true,
+                queued: None
+            }
+            .status_code(),
+            Some(503)
+        );
+        assert_eq!(Error::Unavailable.status_code(), Some(503));
+        let error = Error::Http {
+            status: 400,
+            body: "Bad Request".to_owned(),
+        };
+        assert_eq!(error.status_code(), Some(400));
+        assert_eq!(
+            Error::ClientTimeout(Duration::from_secs(1)).status_code(),
+            None
+        );
+    }
 }
@@ -1,5 +1,6 @@
 use image::{
-    imageops::FilterType::CatmullRom, DynamicImage, GenericImageView, ImageError, ImageFormat,
+    codecs::jpeg::JpegEncoder, imageops::FilterType::CatmullRom, DynamicImage, GenericImageView,
+    ImageError, ImageFormat, ImageReader,
 };
 use std::{
     cmp::min,
@@ -9,6 +10,8 @@ use std::{
 };
 use thiserror::Error as ThisError;
 
+use crate::ImageRegion;
+
 /// Image is shrinked on the server side, before it is send to the model. We might as well save the
 /// bandwith and do it right away.
 const DESIRED_IMAGE_SIZE: u32 = 384;
@@ -23,20 +26,59 @@ pub fn from_image_path(path: &Path) -> Result<Vec<u8>, LoadImageError> {
 }
 
 pub fn preprocess_image(org_image: &DynamicImage) -> Vec<u8> {
-    let center_cropped = center_cropped(org_image);
-    let resized = center_cropped.resize_exact(DESIRED_IMAGE_SIZE, DESIRED_IMAGE_SIZE, CatmullRom);
+    let resized = resized(org_image);
     let buf = Vec::new();
     let mut out = Cursor::new(buf);
     resized.write_to(&mut out, ImageFormat::Png).unwrap();
     out.into_inner()
 }
 
+/// Like [`preprocess_image`], but encodes the image as JPEG with the given quality (1-100). JPEG
+/// does not support transparency, so any alpha channel is dropped.
+pub fn preprocess_image_as_jpeg(org_image: &DynamicImage, quality: u8) -> Vec<u8> {
+    let resized = resized(org_image).into_rgb8();
+    let mut out = Vec::new();
+    JpegEncoder::new_with_quality(&mut out, quality.clamp(1, 100))
+        .encode_image(&resized)
+        .unwrap();
+    out
+}
+
+/// Center cropped and resized to the size the model sees.
+fn resized(org_image: &DynamicImage) -> DynamicImage {
+    let center_cropped = center_cropped(org_image);
+    center_cropped.resize_exact(DESIRED_IMAGE_SIZE, DESIRED_IMAGE_SIZE, CatmullRom)
+}
+
+/// Width and height of an encoded image. Only the header of the image is decoded.
+pub fn dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Decodes an encoded image, e.g. a PNG, into its pixels.
+pub fn decode(bytes: &[u8]) -> Option<DynamicImage> {
+    ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?
+        .decode()
+        .ok()
+}
+
 fn center_cropped(image: &DynamicImage) -> DynamicImage {
-    let (height, width) = image.dimensions();
-    let size = min(height, width);
-    let x = (height - size) / 2;
-    let y = (width - size) / 2;
-    image.crop_imm(x, y, width, height)
+    let (width, height) = image.dimensions();
+    let (x, y, size) = center_crop(width, height);
+    image.crop_imm(x, y, size, size)
+}
+
+/// Left, top and side length of the square which is cut out of an image with the given dimensions
+/// during preprocessing.
+pub fn center_crop(width: u32, height: u32) -> (u32, u32, u32) {
+    let size = min(width, height);
+    ((width - size) / 2, (height - size) / 2, size)
 }
 
 /// Errors returned by the Aleph Alpha Client
@@ -48,4 +90,10 @@ pub enum LoadImageError {
     UnknownImageFormat(#[source] ImageError),
     #[error("Error opening input image file.")]
     Io(#[source] io::Error),
+    #[error("Region {region:?} is empty or exceeds the image of {width}x{height} pixels.")]
+    InvalidRegion {
+        region: ImageRegion,
+        width: u32,
+        height: u32,
+    },
 }
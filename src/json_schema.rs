@@ -0,0 +1,94 @@
+//! Local validation of JSON values against a JSON schema, using the `jsonschema` crate. All drafts
+//! it supports are understood, the draft is detected from `$schema`. References to remote schemas
+//! can not be resolved.
+
+use serde_json::Value;
+
+/// Checks `instance` against `schema`. Returns a description of the first violation found, or of
+/// the problem with the schema if it is invalid itself.
+pub fn validate(schema: &Value, instance: &Value) -> Result<(), String> {
+    let validator =
+        jsonschema::validator_for(schema).map_err(|e| format!("Invalid JSON schema: {e}"))?;
+    validator
+        .validate(instance)
+        .map_err(|e| format!("{}: {e}", e.instance_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn person_schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer"},
+                "hobbies": {"type": "array", "items": {"type": "string"}}
+            },
+            "required": ["name", "age"],
+            "additionalProperties": false
+        })
+    }
+
+    #[test]
+    fn conforming_value_is_valid() {
+        let person = json!({"name": "Alice", "age": 42, "hobbies": ["chess"]});
+
+        assert_eq!(validate(&person_schema(), &person), Ok(()));
+    }
+
+    #[test]
+    fn integer_with_zero_fraction_is_an_integer() {
+        let person = json!({"name": "Alice", "age": 42.0});
+
+        assert_eq!(validate(&person_schema(), &person), Ok(()));
+    }
+
+    #[test]
+    fn missing_required_property_is_reported() {
+        let person = json!({"name": "Alice"});
+
+        let error = validate(&person_schema(), &person).unwrap_err();
+
+        assert!(error.contains("\"age\" is a required property"), "{error}");
+    }
+
+    #[test]
+    fn wrong_type_in_nested_array_is_reported() {
+        let person = json!({"name": "Alice", "age": 42, "hobbies": ["chess", 7]});
+
+        let error = validate(&person_schema(), &person).unwrap_err();
+
+        assert!(error.starts_with("/hobbies/1: "), "{error}");
+    }
+
+    #[test]
+    fn additional_properties_are_rejected_if_forbidden() {
+        let person = json!({"name": "Alice", "age": 42, "email": "alice@example.com"});
+
+        let error = validate(&person_schema(), &person).unwrap_err();
+
+        assert!(error.contains("email"), "{error}");
+    }
+
+    #[test]
+    fn keywords_beyond_structure_are_checked() {
+        let schema = json!({"type": "string", "pattern": "^[a-z]+$", "maxLength": 5});
+
+        assert!(validate(&schema, &json!("alice")).is_ok());
+        assert!(validate(&schema, &json!("Alice")).is_err());
+        assert!(validate(&schema, &json!("alexander")).is_err());
+    }
+
+    #[test]
+    fn invalid_schema_is_reported() {
+        let schema = json!({"type": "no-such-type"});
+
+        let error = validate(&schema, &json!(1)).unwrap_err();
+
+        assert!(error.starts_with("Invalid JSON schema"), "{error}");
+    }
+}
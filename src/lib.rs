@@ -23,49 +23,84 @@
 //! }
 //! ```
 
+#[cfg(feature = "cancellation")]
+mod cancellation;
 mod chat;
+mod code_block;
 mod completion;
 mod detokenization;
+mod embedding_index;
 mod explanation;
+mod finish_reason;
 mod http;
 mod image_preprocessing;
+mod json_schema;
 mod logprobs;
+mod model_settings;
+mod pricing;
 mod prompt;
+mod prompt_template;
+mod rate_limit;
 mod semantic_embedding;
 mod stream;
 mod tokenization;
+mod trace_context;
+use async_stream::stream;
+use completion::TaskCompletionDetails;
 use dotenvy::dotenv;
-use futures_util::Stream;
+use futures_util::{Stream, StreamExt};
 use http::HttpClient;
-use semantic_embedding::{BatchSemanticEmbeddingOutput, SemanticEmbeddingOutput};
+use regex::Regex;
+use reqwest::header::HeaderMap;
 use std::env;
-use std::{pin::Pin, time::Duration};
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use tokenizers::Tokenizer;
 
 pub use self::{
     chat::{
-        ChatEvent, ChatOutput, ChatSampling, ChatStreamChunk, Distribution, Message, TaskChat,
-        Usage,
+        ChatEvent, ChatOutput, ChatSampling, ChatStreamChunk, Distribution, JsonSchema,
+        MaxTokensField, Message, OwnedTaskChat, ResponseFormat, TaskChat, Usage,
     },
+    code_block::{extract_code_blocks, CodeBlock},
     completion::{
-        CompletionEvent, CompletionOutput, CompletionSummary, Sampling, Stopping, StreamChunk,
-        StreamSummary, TaskCompletion,
+        CompletionDetails, CompletionEvent, CompletionOutput, CompletionSummary,
+        OwnedTaskCompletion, Sampling, Stopping, StreamChunk, StreamSummary, TaskCompletion,
     },
-    detokenization::{DetokenizationOutput, TaskDetokenization},
+    detokenization::{DetokenizationOutput, TaskBatchDetokenization, TaskDetokenization},
+    embedding_index::EmbeddingIndex,
     explanation::{
-        Explanation, ExplanationOutput, Granularity, ImageScore, ItemExplanation,
-        PromptGranularity, TaskExplanation, TextScore,
+        Explanation, ExplanationOutput, Granularity, ImageScore, ItemExplanation, PixelRect,
+        PromptGranularity, TargetGranularity, TaskBatchExplanation, TaskExplanation, TextScore,
     },
-    http::{Error, Job, Task},
+    finish_reason::FinishReason,
+    http::{Error, Job, RawResponse, Task},
     logprobs::{Logprob, Logprobs},
-    prompt::{Modality, Prompt},
+    model_settings::{ModelSettings, TaskModelSettings},
+    pricing::{PriceTable, TokenPrice},
+    prompt::{ImageRegion, Modality, Prompt},
+    rate_limit::RateLimit,
     semantic_embedding::{
-        SemanticRepresentation, TaskBatchSemanticEmbedding, TaskSemanticEmbedding,
+        chunk_text_by_tokens, BatchSemanticEmbeddingChunk, BatchSemanticEmbeddingOutput,
+        SemanticEmbeddingOutput, SemanticRepresentation, TaskBatchSemanticEmbedding,
+        TaskSemanticEmbedding, TaskSemanticEmbeddingWithInstruction,
     },
     stream::{StreamJob, StreamTask},
-    tokenization::{TaskTokenization, TokenizationOutput},
+    tokenization::{
+        token_bias, token_spans, SpecialToken, SpecialTokens, TaskTokenization, TokenSpan,
+        TokenizationOutput,
+    },
+    trace_context::TraceContext,
 };
 
+#[cfg(feature = "cancellation")]
+pub use self::cancellation::Cancellation;
+
 /// Execute Jobs against the Aleph Alpha API
 pub struct Client {
     /// This client does all the work of sending the requests and talking to the AA API. The only
@@ -73,6 +108,10 @@ pub struct Client {
     /// can be executed, which allows for an alternative non generic interface which might produce
     /// easier to read code for the end user in many use cases.
     http_client: HttpClient,
+    /// Model settings fetched e.g. by [`Self::render_chat_as_prompt`], by model name.
+    model_settings: Mutex<HashMap<String, ModelSettings>>,
+    /// Tokenizers fetched by [`Self::tokenize_with_spans`], by model name.
+    tokenizers: Mutex<HashMap<String, Arc<Tokenizer>>>,
 }
 
 impl Client {
@@ -85,7 +124,11 @@ impl Client {
     /// a panic.
     pub fn new(host: impl Into<String>, api_token: Option<String>) -> Result<Self, Error> {
         let http_client = HttpClient::new(host.into(), api_token)?;
-        Ok(Self { http_client })
+        Ok(Self {
+            http_client,
+            model_settings: Mutex::default(),
+            tokenizers: Mutex::default(),
+        })
     }
 
     /// A client instance that always uses the same token for all requests.
@@ -93,10 +136,119 @@ impl Client {
         Self::new(host, Some(api_token.into()))
     }
 
+    /// Overrides the default timeout for requests sent by this client. Requests which do not
+    /// specify a [`How::client_timeout`] of their own will be cancelled after this duration.
+    ///
+    /// ```no_run
+    /// use aleph_alpha_client::{Client, Error};
+    /// use std::time::Duration;
+    ///
+    /// fn interactive_client() -> Result<Client, Error> {
+    ///     Ok(Client::from_env()?.with_timeout(Duration::from_secs(10)))
+    /// }
+    /// ```
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            http_client: self.http_client.with_timeout(Some(timeout)),
+            ..self
+        }
+    }
+
+    /// Requests which do not specify a [`How::client_timeout`] of their own wait for the server as
+    /// long as it takes, e.g. in batch jobs which legitimately run longer than any fixed timeout.
+    pub fn without_timeout(self) -> Self {
+        Self {
+            http_client: self.http_client.with_timeout(None),
+            ..self
+        }
+    }
+
+    /// Accept TLS certificates which can not be verified, e.g. self-signed certificates of a local
+    /// inference server used for testing. Off by default.
+    ///
+    /// (!) This is dangerous. With this option set, any certificate is trusted, including expired
+    /// ones and those issued for other hosts. This makes the client vulnerable to man in the middle
+    /// attacks, which could e.g. steal your API token. Never use this in production.
+    pub fn accept_invalid_certs(self, accept_invalid_certs: bool) -> Result<Self, Error> {
+        Ok(Self {
+            http_client: self
+                .http_client
+                .accept_invalid_certs(accept_invalid_certs)?,
+            ..self
+        })
+    }
+
+    /// Limits the number of idle connections kept alive to the API. By default all connections are
+    /// kept alive, so they can be reused by later requests without another handshake.
+    ///
+    /// When firing many concurrent requests, e.g. for a large embedding job, set this to at least
+    /// the number of concurrent requests. Otherwise connections are closed after each wave of
+    /// requests, only to be opened again by the next one. Lower it, or set it to zero, to free
+    /// resources in processes which only talk to the API in rare bursts.
+    ///
+    /// ```no_run
+    /// use aleph_alpha_client::{Client, Error};
+    ///
+    /// fn embedding_client() -> Result<Client, Error> {
+    ///     // We never have more than 64 requests in flight
+    ///     Client::from_env()?.with_pool_max_idle_per_host(64)
+    /// }
+    /// ```
+    pub fn with_pool_max_idle_per_host(self, max_idle: usize) -> Result<Self, Error> {
+        Ok(Self {
+            http_client: self.http_client.with_pool_max_idle_per_host(max_idle)?,
+            ..self
+        })
+    }
+
+    /// Speak HTTP/2 to the API right away, without negotiating the protocol first. HTTP/2
+    /// multiplexes concurrent requests over a single connection, which improves throughput for
+    /// many concurrent requests.
+    ///
+    /// For `https` URLs this is usually not needed, as HTTP/2 is negotiated during the TLS
+    /// handshake anyway. Use it for servers speaking HTTP/2 over plain `http`, e.g. a local
+    /// inference server behind a gateway. Requests fail against servers not supporting HTTP/2.
+    pub fn with_http2_prior_knowledge(self) -> Result<Self, Error> {
+        Ok(Self {
+            http_client: self.http_client.with_http2_prior_knowledge()?,
+            ..self
+        })
+    }
+
+    /// Closes all idle connections kept alive by this client, so short lived processes can exit
+    /// promptly. This consumes the client. Since every request borrows the client, consuming it
+    /// implies that there are no requests in flight anymore. Streams which have already been
+    /// returned keep their own connection open until they are dropped.
+    pub fn shutdown(self) {
+        self.http_client.shutdown();
+    }
+
+    /// Creates a client from the `PHARIA_AI_TOKEN` and `INFERENCE_URL` environment variables,
+    /// reading a `.env` file if present. If `INFERENCE_URL` is not set at runtime, the value it had
+    /// when the client has been compiled is used instead. This way, only the token is required at
+    /// runtime for builds targeting a fixed inference deployment. Use [`Self::try_from_env`] to
+    /// require both variables at runtime.
+    ///
+    /// Fails with [`Error::MissingEnvVar`] if `PHARIA_AI_TOKEN` is not set, or `INFERENCE_URL` has
+    /// been set neither at runtime nor at compile time.
     pub fn from_env() -> Result<Self, Error> {
         let _ = dotenv();
-        let api_token = env::var("PHARIA_AI_TOKEN").unwrap();
-        let inference_url = env::var("INFERENCE_URL").unwrap();
+        let api_token = env_var("PHARIA_AI_TOKEN")?;
+        let inference_url =
+            inference_url_or_default(env::var("INFERENCE_URL").ok(), DEFAULT_INFERENCE_URL).ok_or(
+                Error::MissingEnvVar {
+                    name: "INFERENCE_URL",
+                },
+            )?;
+        Self::with_auth(inference_url, api_token)
+    }
+
+    /// Like [`Self::from_env`], but strict: both `PHARIA_AI_TOKEN` and `INFERENCE_URL` must be set
+    /// at runtime, or in a `.env` file. Fails with [`Error::MissingEnvVar`] otherwise.
+    pub fn try_from_env() -> Result<Self, Error> {
+        let _ = dotenv();
+        let api_token = env_var("PHARIA_AI_TOKEN")?;
+        let inference_url = env_var("INFERENCE_URL")?;
         Self::with_auth(inference_url, api_token)
     }
 
@@ -142,9 +294,50 @@ impl Client {
         self.http_client.output_of(task, how).await
     }
 
+    /// Like [`Self::output_of`], but additionally returns the raw response sent by the server,
+    /// i.e. its JSON body and request id. Useful for debugging, e.g. if a field you expect is
+    /// missing in the output. If the body can not be parsed into the output,
+    /// [`Error::InvalidResponse`] carries the raw body instead.
+    pub async fn output_of_with_raw<T: Job>(
+        &self,
+        task: &T,
+        how: &How,
+    ) -> Result<(T::Output, RawResponse), Error> {
+        self.http_client.output_of_with_raw(task, how).await
+    }
+
+    /// Like [`Self::output_of`], but additionally returns the headers of the response, e.g. to
+    /// read rate limit information a gateway in front of the API sets. See
+    /// [`RateLimit::from_headers`].
+    pub async fn output_of_with_headers<T: Job>(
+        &self,
+        task: &T,
+        how: &How,
+    ) -> Result<(T::Output, HeaderMap), Error> {
+        self.http_client.output_of_with_headers(task, how).await
+    }
+
+    /// The JSON body which would be sent to the API for `task`, without sending it. Useful for
+    /// debugging, e.g. if a request is rejected by the API.
+    ///
+    /// ```
+    /// use aleph_alpha_client::{Client, Task, TaskCompletion};
+    ///
+    /// let client = Client::new("http://localhost:8080", None).unwrap();
+    /// let task = TaskCompletion::from_text("An apple a day").with_maximum_tokens(10);
+    /// let body = client.request_json(&task.with_model("luminous-base")).unwrap();
+    /// assert_eq!(body["maximum_tokens"], 10);
+    /// ```
+    pub fn request_json<T: Job>(&self, task: &T) -> Result<serde_json::Value, Error> {
+        self.http_client.request_json(task)
+    }
+
     /// An embedding trying to capture the semantic meaning of a text. Cosine similarity can be used
     /// find out how well two texts (or multimodal prompts) match. Useful for search usecases.
     ///
+    /// If [`TaskSemanticEmbedding::compress_to_size`] is set, an embedding with a different number
+    /// of dimensions is reported as [`Error::UnexpectedEmbeddingSize`].
+    ///
     /// See the example for [`cosine_similarity`].
     pub async fn semantic_embedding(
         &self,
@@ -154,18 +347,186 @@ impl Client {
         self.http_client.output_of(task, how).await
     }
 
+    /// An embedding of a prompt, steered by an instruction describing its use case. Requires a
+    /// model supporting instructable embeddings.
+    ///
+    /// ```no_run
+    /// use aleph_alpha_client::{
+    ///     Client, Error, How, Prompt, TaskSemanticEmbeddingWithInstruction,
+    /// };
+    ///
+    /// async fn embed_for_similarity() -> Result<(), Error> {
+    ///     let client = Client::from_env()?;
+    ///     let task = TaskSemanticEmbeddingWithInstruction::for_similarity(Prompt::from_text(
+    ///         "An apple a day keeps the doctor away.",
+    ///     ));
+    ///     let model = "pharia-1-embedding-4608-control";
+    ///     let output = client
+    ///         .semantic_embedding_with_instruction(&task, model, &How::default())
+    ///         .await?;
+    ///     dbg!(output.embedding);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn semantic_embedding_with_instruction(
+        &self,
+        task: &TaskSemanticEmbeddingWithInstruction<'_>,
+        model: &str,
+        how: &How,
+    ) -> Result<SemanticEmbeddingOutput, Error> {
+        self.http_client
+            .output_of(&task.with_model(model), how)
+            .await
+    }
+
     /// An batch of embeddings trying to capture the semantic meaning of a text.
+    ///
+    /// The API limits the number of prompts per request. Batches with more than
+    /// [`TaskBatchSemanticEmbedding::DEFAULT_CHUNK_SIZE`] prompts are transparently split into
+    /// multiple requests. The embeddings are returned in the order of the prompts. Embeddings
+    /// not matching [`TaskBatchSemanticEmbedding::compress_to_size`] are reported as
+    /// [`Error::UnexpectedEmbeddingSize`].
     pub async fn batch_semantic_embedding(
         &self,
         task: &TaskBatchSemanticEmbedding<'_>,
         how: &How,
     ) -> Result<BatchSemanticEmbeddingOutput, Error> {
-        self.http_client.output_of(task, how).await
+        self.batch_semantic_embedding_in_chunks(
+            task,
+            TaskBatchSemanticEmbedding::DEFAULT_CHUNK_SIZE,
+            how,
+        )
+        .await
+    }
+
+    /// Same as [`Self::batch_semantic_embedding`], but sends at most `chunk_size` prompts per
+    /// request. Requests are sent one after another. The embeddings are returned in the order of
+    /// the prompts.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    pub async fn batch_semantic_embedding_in_chunks(
+        &self,
+        task: &TaskBatchSemanticEmbedding<'_>,
+        chunk_size: usize,
+        how: &How,
+    ) -> Result<BatchSemanticEmbeddingOutput, Error> {
+        let mut embeddings = Vec::with_capacity(task.prompts.len());
+        let mut chunks = self.stream_batch_semantic_embedding(task, chunk_size, how);
+        while let Some(chunk) = chunks.next().await {
+            embeddings.extend(chunk?.embeddings);
+        }
+        Ok(BatchSemanticEmbeddingOutput { embeddings })
+    }
+
+    /// Embeds a large batch of prompts in chunks of at most `chunk_size` prompts. The embeddings
+    /// of each chunk are yielded as soon as they are computed, so you can start processing them
+    /// before the entire batch is finished. Chunks are executed one after another and are yielded
+    /// in order. Each chunk carries the index of its first prompt within the original batch.
+    ///
+    /// ```no_run
+    /// use aleph_alpha_client::{
+    ///     Client, Error, How, Prompt, SemanticRepresentation, TaskBatchSemanticEmbedding,
+    /// };
+    /// use futures_util::StreamExt;
+    ///
+    /// async fn embed_documents(documents: &[&str]) -> Result<(), Error> {
+    ///     let client = Client::from_env()?;
+    ///     let task = TaskBatchSemanticEmbedding {
+    ///         prompts: documents.iter().map(|&text| Prompt::from_text(text)).collect(),
+    ///         representation: SemanticRepresentation::Document,
+    ///         compress_to_size: Some(128),
+    ///     };
+    ///     let how = How::default();
+    ///     let mut stream = client.stream_batch_semantic_embedding(&task, 100, &how);
+    ///     while let Some(chunk) = stream.next().await {
+    ///         let chunk = chunk?;
+    ///         println!("Embedded documents {} to {}", chunk.offset, chunk.offset + chunk.embeddings.len());
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    pub fn stream_batch_semantic_embedding<'a>(
+        &'a self,
+        task: &'a TaskBatchSemanticEmbedding<'a>,
+        chunk_size: usize,
+        how: &'a How,
+    ) -> Pin<Box<dyn Stream<Item = Result<BatchSemanticEmbeddingChunk, Error>> + Send + 'a>> {
+        assert!(chunk_size > 0, "chunk_size must be larger than zero");
+        Box::pin(stream! {
+            let mut offset = 0;
+            for chunk in task.chunks(chunk_size) {
+                let output = self.http_client.output_of(&chunk, how).await?;
+                let num_embeddings = output.embeddings.len();
+                yield Ok(BatchSemanticEmbeddingChunk { offset, embeddings: output.embeddings });
+                offset += num_embeddings;
+            }
+        })
+    }
+
+    /// Embeds the prompts of a stream as they arrive, so neither the prompts nor the embeddings of
+    /// a huge corpus have to be held in memory at once. Up to `max_concurrency` requests are in
+    /// flight at the same time. Embeddings are yielded as soon as they are ready, which may differ
+    /// from the order of the prompts. Each one comes with the index of its prompt in the input
+    /// stream, and each prompt succeeds or fails on its own.
+    ///
+    /// ```no_run
+    /// use aleph_alpha_client::{Client, Error, How, Prompt, SemanticRepresentation};
+    /// use futures_util::{stream, StreamExt};
+    ///
+    /// async fn embed_corpus(documents: Vec<String>) -> Result<(), Error> {
+    ///     let client = Client::from_env()?;
+    ///     let how = How::default();
+    ///     let prompts = stream::iter(documents).map(Prompt::from_text);
+    ///
+    ///     let mut embeddings =
+    ///         client.embed_stream(prompts, SemanticRepresentation::Document, &how, 10);
+    ///     while let Some((index, embedding)) = embeddings.next().await {
+    ///         println!("Document {index} has {} dimensions", embedding?.dimensions());
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_concurrency` is zero.
+    pub fn embed_stream<'a>(
+        &'a self,
+        prompts: impl Stream<Item = Prompt<'a>> + Send + 'a,
+        representation: SemanticRepresentation,
+        how: &'a How,
+        max_concurrency: usize,
+    ) -> impl Stream<Item = (usize, Result<SemanticEmbeddingOutput, Error>)> + Send + 'a {
+        assert!(
+            max_concurrency > 0,
+            "max_concurrency must be larger than zero"
+        );
+        prompts
+            .enumerate()
+            .map(move |(index, prompt)| async move {
+                let task = TaskSemanticEmbedding {
+                    prompt,
+                    representation,
+                    compress_to_size: None,
+                };
+                (index, self.semantic_embedding(&task, how).await)
+            })
+            .buffer_unordered(max_concurrency)
     }
 
     /// Instruct a model served by the aleph alpha API to continue writing a piece of text (or
     /// multimodal document).
     ///
+    /// If [`Sampling::best_of`] is set, the server generates several candidates and returns the
+    /// one with the highest log probability per token. A `best_of` smaller than one is reported as
+    /// [`Error::InvalidSampling`] without sending the request.
+    ///
     /// ```no_run
     /// use aleph_alpha_client::{Client, How, TaskCompletion, Task, Error};
     ///
@@ -200,6 +561,156 @@ impl Client {
             .await
     }
 
+    /// Like [`Client::completion`], but reports the tokens of the completion along with their ids,
+    /// byte offsets and log probabilities in a single [`CompletionDetails`]. Tokens, token ids and
+    /// the log probabilities of the sampled tokens are requested regardless of the settings of
+    /// `task`. Top log probabilities are included if requested with [`TaskCompletion::logprobs`].
+    pub async fn completion_details(
+        &self,
+        task: &TaskCompletion<'_>,
+        model: &str,
+        how: &How,
+    ) -> Result<CompletionDetails, Error> {
+        self.http_client
+            .output_of(&TaskCompletionDetails(task).with_model(model), how)
+            .await
+    }
+
+    /// Completes several independent prompts with the same model. Up to `max_concurrency` requests
+    /// are in flight at the same time. The results are returned in the order of the tasks, and
+    /// each task succeeds or fails on its own.
+    ///
+    /// This is different from sampling several candidates for the same prompt, as each task may
+    /// have a different prompt and settings.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_concurrency` is zero.
+    pub async fn completions_batch(
+        &self,
+        tasks: &[TaskCompletion<'_>],
+        model: &str,
+        how: &How,
+        max_concurrency: usize,
+    ) -> Vec<Result<CompletionOutput, Error>> {
+        assert!(
+            max_concurrency > 0,
+            "max_concurrency must be larger than zero"
+        );
+        futures_util::stream::iter(tasks)
+            .map(|task| self.completion(task, model, how))
+            .buffered(max_concurrency)
+            .collect()
+            .await
+    }
+
+    /// Like [`Client::completion`], but tries the models in order until one of them answers. Moves
+    /// on to the next model if a model is not found (404), [`Error::Unavailable`] or
+    /// [`Error::Busy`]. Any other error is returned right away, as another model is unlikely to
+    /// fare better. If all models fail, the error of the last one is returned. Fails with
+    /// [`Error::NoModel`] if `models` is empty.
+    pub async fn completion_with_fallback(
+        &self,
+        task: &TaskCompletion<'_>,
+        models: &[&str],
+        how: &How,
+    ) -> Result<CompletionOutput, Error> {
+        let (last, preferred) = models.split_last().ok_or(Error::NoModel)?;
+        for model in preferred {
+            match self.completion(task, model, how).await {
+                Err(Error::Http { status: 404, .. } | Error::Unavailable | Error::Busy { .. }) => {}
+                result => return result,
+            }
+        }
+        self.completion(task, last, how).await
+    }
+
+    /// Resumes a completion which has been cut off, e.g. because it reached the maximum number of
+    /// tokens (see [`CompletionOutput::is_truncated`]). `prev` is the output of `task`. Its
+    /// completion is appended to the prompt of `task` and the model is asked to continue from
+    /// there, with the same stopping and sampling settings. The returned output contains the
+    /// concatenation of both completions, the finish reason of the second request and the usage of
+    /// both requests summed up.
+    ///
+    /// If `task` echoes the prompt, the echo is stripped from the completion of `prev` before it is
+    /// appended, and kept in front of the returned completion. Prompts with images are echoed as
+    /// text which can not be told apart from the completion, so they are rejected with
+    /// [`Error::InvalidPrompt`] in this case.
+    ///
+    /// ```no_run
+    /// use aleph_alpha_client::{Client, How, TaskCompletion, Error};
+    ///
+    /// async fn print_long_completion() -> Result<(), Error> {
+    ///     let client = Client::from_env()?;
+    ///     let task = TaskCompletion::from_text("An apple a day").with_maximum_tokens(64);
+    ///     let how = How::default();
+    ///
+    ///     let mut output = client.completion(&task, "luminous-base", &how).await?;
+    ///     while output.is_truncated() {
+    ///         output = client
+    ///             .continue_completion(&output, &task, "luminous-base", &how)
+    ///             .await?;
+    ///     }
+    ///     println!("An apple a day{}", output.completion);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn continue_completion(
+        &self,
+        prev: &CompletionOutput,
+        task: &TaskCompletion<'_>,
+        model: &str,
+        how: &How,
+    ) -> Result<CompletionOutput, Error> {
+        let generated = if task.echo {
+            // The previous completion starts with the echoed prompt, which must not be repeated
+            task.prompt
+                .text()
+                .and_then(|text| prev.completion.strip_prefix(text.as_str()))
+                .ok_or_else(|| Error::InvalidPrompt {
+                    reason: "The echoed prompt can not be told apart from the completion. \
+                        Continue completions of prompts with images without echo."
+                        .to_owned(),
+                })?
+        } else {
+            prev.completion.as_str()
+        };
+        let mut prompt = task.prompt.borrow();
+        // Tokenize the previous completion together with the text in front of it, as the model
+        // would have seen it while generating.
+        prompt.extend_last_text(generated);
+        let continuation = TaskCompletion {
+            prompt,
+            stopping: Stopping {
+                maximum_tokens: task.stopping.maximum_tokens,
+                stop_sequences: task.stopping.stop_sequences,
+            },
+            sampling: task.sampling.clone(),
+            special_tokens: task.special_tokens,
+            logprobs: task.logprobs,
+            token_ids: task.token_ids,
+            // The prompt has already been echoed by the previous request
+            echo: false,
+            disable_optimizations: task.disable_optimizations,
+        };
+        let next = self.completion(&continuation, model, how).await?;
+        Ok(CompletionOutput {
+            completion: prev.completion.clone() + &next.completion,
+            finish_reason: next.finish_reason,
+            logprobs: prev.logprobs.iter().cloned().chain(next.logprobs).collect(),
+            token_ids: prev
+                .token_ids
+                .iter()
+                .copied()
+                .chain(next.token_ids)
+                .collect(),
+            usage: Usage {
+                prompt_tokens: prev.usage.prompt_tokens + next.usage.prompt_tokens,
+                completion_tokens: prev.usage.completion_tokens + next.usage.completion_tokens,
+            },
+        })
+    }
+
     /// Instruct a model served by the aleph alpha API to continue writing a piece of text.
     /// Stream the response as a series of events.
     ///
@@ -235,12 +746,80 @@ impl Client {
         model: &str,
         how: &How,
     ) -> Result<Pin<Box<dyn Stream<Item = Result<CompletionEvent, Error>> + Send>>, Error> {
-        self.http_client
+        let start = Instant::now();
+        let mut stream = self
+            .http_client
             .stream_output_of(&Task::with_model(task, model), how)
-            .await
+            .await?;
+        if how.measure_token_timing {
+            stream = with_token_timing(stream, start, |event| match event {
+                CompletionEvent::StreamChunk(chunk) => Some(&mut chunk.time_since_previous),
+                _ => None,
+            });
+        }
+        if task.echo {
+            stream = with_echo_split(stream, task.prompt.text());
+        }
+        Ok(stream)
+    }
+
+    /// Like [`Client::stream_completion`], but stops once the completion matches `stop`. Useful for
+    /// stop conditions the API does not support, e.g. "any digit" or "an empty line". The regex is
+    /// applied on the client side to the whole completion received so far, so keep using
+    /// [`Stopping::stop_sequences`] for plain strings, which save the tokens after the match.
+    ///
+    /// Once `stop` matches, the chunk containing the match is yielded truncated to the text in front
+    /// of the match, followed by a [`StreamSummary`] with the finish reason
+    /// `stop_sequence_reached` and the matched text as its stop sequence. Its model version is
+    /// `None`, as the server reports it only in its own summary. The stream then ends and the
+    /// request is cancelled. Parts of the match which arrived in earlier chunks have already been
+    /// yielded with them. If `stop` never matches, the stream is passed through unchanged. An
+    /// echoed prompt (see [`TaskCompletion::echo`]) is passed through, too, and never matched.
+    pub async fn stream_completion_until(
+        &self,
+        task: &TaskCompletion<'_>,
+        model: &str,
+        stop: Regex,
+        how: &How,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<CompletionEvent, Error>> + Send>>, Error> {
+        let mut events = self.stream_completion(task, model, how).await?;
+        Ok(Box::pin(stream! {
+            let mut completion = String::new();
+            while let Some(event) = events.next().await {
+                let Ok(CompletionEvent::StreamChunk(mut chunk)) = event else {
+                    yield event;
+                    continue;
+                };
+                if chunk.is_echo {
+                    yield Ok(CompletionEvent::StreamChunk(chunk));
+                    continue;
+                }
+                let chunk_start = completion.len();
+                completion.push_str(&chunk.completion);
+                let Some(found) = stop.find(&completion) else {
+                    yield Ok(CompletionEvent::StreamChunk(chunk));
+                    continue;
+                };
+                // The match may begin in an earlier chunk, which has been yielded already
+                chunk.completion.truncate(found.start().saturating_sub(chunk_start));
+                if !chunk.completion.is_empty() {
+                    yield Ok(CompletionEvent::StreamChunk(chunk));
+                }
+                yield Ok(CompletionEvent::StreamSummary(StreamSummary {
+                    model_version: None,
+                    finish_reason: "stop_sequence_reached".to_owned(),
+                    stop_sequence: Some(found.as_str().to_owned()),
+                }));
+                break;
+            }
+        }))
     }
 
     /// Send a chat message to a model.
+    ///
+    /// If [`TaskChat::validate_response`] is set, the content of the response is validated against
+    /// the [`TaskChat::response_format`]. A response violating it is reported as
+    /// [`Error::SchemaViolation`].
     /// ```no_run
     /// use aleph_alpha_client::{Client, How, TaskChat, Error, Message};
     ///
@@ -304,9 +883,194 @@ impl Client {
         model: &str,
         how: &How,
     ) -> Result<Pin<Box<dyn Stream<Item = Result<ChatStreamChunk, Error>> + Send>>, Error> {
-        self.http_client
+        let start = Instant::now();
+        let events = self
+            .http_client
             .stream_output_of(&StreamTask::with_model(task, model), how)
-            .await
+            .await?;
+        // Each event carries one chunk per choice, each of them is yielded on its own
+        let mut stream: Pin<Box<dyn Stream<Item = Result<ChatStreamChunk, Error>> + Send>> =
+            Box::pin(events.flat_map(|event| {
+                futures_util::stream::iter(match event {
+                    Ok(chunks) => chunks.into_iter().map(Ok).collect(),
+                    Err(e) => vec![Err(e)],
+                })
+            }));
+        if how.measure_token_timing {
+            stream = with_token_timing(stream, start, |chunk| Some(&mut chunk.time_since_previous));
+        }
+        if let Some(format) = task.format_to_validate() {
+            stream = with_response_check(stream, format.clone());
+        }
+        Ok(stream)
+    }
+
+    /// Like [`Self::stream_chat`], but reconnects if the connection drops in the middle of the
+    /// stream, at most `max_reconnects` times. Useful for long answers over flaky networks.
+    ///
+    /// Reconnecting is best-effort: the chat is sent again with the answer received so far as
+    /// message of the assistant, so the model continues it instead of starting over. The resumed
+    /// answer may not be exactly the one which would have been generated without interruption.
+    /// If [`Stopping::maximum_tokens`] is set, the answer received so far is tokenized with the
+    /// tokenizer of the model (fetched once and cached) and its tokens are deducted from the
+    /// maximum of the resumed chat, so the answer as a whole stays within the budget. Only a lost
+    /// connection triggers a reconnect, see [`Error::is_connection_lost`]. All other errors, and a
+    /// lost connection after the last reconnect or with no tokens left, are yielded and end the
+    /// stream.
+    pub fn stream_chat_with_reconnect<'a>(
+        &'a self,
+        task: &'a TaskChat<'a>,
+        model: &'a str,
+        how: &'a How,
+        max_reconnects: u32,
+    ) -> Pin<Box<dyn Stream<Item = Result<ChatStreamChunk, Error>> + Send + 'a>> {
+        Box::pin(stream! {
+            let mut received = String::new();
+            let mut received_tokens = 0;
+            let mut reconnects = 0;
+            loop {
+                let continuation;
+                let current = if reconnects == 0 {
+                    task
+                } else {
+                    continuation = task.continuation(received.clone(), received_tokens);
+                    &continuation
+                };
+                let mut chunks = match self.stream_chat(current, model, how).await {
+                    Ok(chunks) => chunks,
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+                let mut lost = None;
+                while let Some(chunk) = chunks.next().await {
+                    match chunk {
+                        Ok(chunk) => {
+                            received.push_str(&chunk.delta.content);
+                            yield Ok(chunk);
+                        }
+                        Err(e) if e.is_connection_lost() && reconnects < max_reconnects => {
+                            lost = Some(e);
+                            break;
+                        }
+                        Err(e) => {
+                            yield Err(e);
+                            return;
+                        }
+                    }
+                }
+                let Some(lost) = lost else {
+                    return;
+                };
+                if let Some(maximum) = task.stopping.maximum_tokens {
+                    received_tokens = match self.count_tokens(&received, model, how).await {
+                        Ok(count) => count,
+                        Err(e) => {
+                            yield Err(e);
+                            return;
+                        }
+                    };
+                    if received_tokens >= maximum {
+                        yield Err(lost);
+                        return;
+                    }
+                }
+                reconnects += 1;
+            }
+        })
+    }
+
+    /// Number of tokens `text` consists of for `model`, using the cached tokenizer of the model.
+    async fn count_tokens(&self, text: &str, model: &str, how: &How) -> Result<u32, Error> {
+        let tokenizer = self.cached_tokenizer(model, how).await?;
+        let encoding = tokenizer
+            .encode(text, false)
+            .map_err(|e| Error::InvalidTokenizer {
+                deserialization_error: e.to_string(),
+            })?;
+        Ok(encoding.len() as u32)
+    }
+
+    /// Like [`Self::stream_chat`], but additionally returns a future resolving to the complete
+    /// answer, assembled from the deltas of the stream. Lets you show the tokens as they arrive,
+    /// while handing the complete message on, e.g. to a log.
+    ///
+    /// The future resolves as soon as the stream has delivered the chunk with the finish reason.
+    /// It resolves to `None` if the stream fails or is dropped before. The stream does not carry
+    /// any token usage, so the usage of the assembled output is zero.
+    ///
+    /// ```no_run
+    /// use aleph_alpha_client::{Client, How, TaskChat, Error, Message};
+    /// use futures_util::StreamExt;
+    ///
+    /// async fn print_and_log_chat() -> Result<(), Error> {
+    ///     let client = Client::from_env()?;
+    ///     let task = TaskChat::with_message(Message::user("Hello, how are you?"));
+    ///
+    ///     let (mut stream, output) = client
+    ///         .stream_chat_with_final(&task, "pharia-1-llm-7b-control", &How::default())
+    ///         .await?;
+    ///     while let Some(Ok(event)) = stream.next().await {
+    ///         print!("{}", event.delta.content);
+    ///     }
+    ///     if let Some(output) = output.await {
+    ///         eprintln!("Answer: {}", output.message.content);
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn stream_chat_with_final(
+        &self,
+        task: &TaskChat<'_>,
+        model: &str,
+        how: &How,
+    ) -> Result<
+        (
+            Pin<Box<dyn Stream<Item = Result<ChatStreamChunk, Error>> + Send>>,
+            impl Future<Output = Option<ChatOutput>> + Send,
+        ),
+        Error,
+    > {
+        let mut chunks = self.stream_chat(task, model, how).await?;
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let stream = stream! {
+            let mut sender = Some(sender);
+            let mut role = None;
+            let mut content = String::new();
+            let mut failed = false;
+            while let Some(chunk) = chunks.next().await {
+                match &chunk {
+                    Ok(chunk) if !failed => {
+                        role = role.or_else(|| chunk.delta.role.clone());
+                        content.push_str(&chunk.delta.content);
+                        if let Some(finish_reason) = &chunk.finish_reason {
+                            let message = Message::new(
+                                role.take().unwrap_or_else(|| "assistant".to_owned()),
+                                std::mem::take(&mut content),
+                            );
+                            let usage = Usage {
+                                prompt_tokens: 0,
+                                completion_tokens: 0,
+                            };
+                            let output =
+                                ChatOutput::new(message, finish_reason.clone(), Vec::new(), usage);
+                            // The receiver may already be gone, if nobody is interested
+                            if let Some(sender) = sender.take() {
+                                let _ = sender.send(output);
+                            }
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(_) => failed = true,
+                }
+                yield chunk;
+            }
+        };
+        Ok((
+            Box::pin(stream) as Pin<Box<dyn Stream<Item = _> + Send>>,
+            async move { receiver.await.ok() },
+        ))
     }
 
     /// Returns an explanation given a prompt and a target (typically generated
@@ -333,6 +1097,9 @@ impl Client {
     ///         sampling: Sampling::MOST_LIKELY,
     ///         special_tokens: false,
     ///         logprobs: Logprobs::No,
+    ///         token_ids: false,
+    ///         echo: false,
+    ///         disable_optimizations: false,
     ///     };
     ///     let response = client.completion(&task, model, &How::default()).await?;
     ///
@@ -358,6 +1125,22 @@ impl Client {
             .await
     }
 
+    /// Explains several targets for the same prompt, e.g. if you want to compare which parts of
+    /// the prompt favour one answer over another. One request per target is sent concurrently. The
+    /// explanations are returned in the order of the targets. Fails if any of the requests fails.
+    pub async fn batch_explanation(
+        &self,
+        task: &TaskBatchExplanation<'_>,
+        model: &str,
+        how: &How,
+    ) -> Result<Vec<ExplanationOutput>, Error> {
+        futures_util::future::try_join_all(
+            task.tasks()
+                .map(|task| async move { self.explanation(&task, model, how).await }),
+        )
+        .await
+    }
+
     /// Tokenize a prompt for a specific model.
     ///
     /// ```no_run
@@ -394,6 +1177,72 @@ impl Client {
             .await
     }
 
+    /// Tokenizes the text and reports for each token the range of bytes it covers in the text,
+    /// e.g. to highlight the tokens in a UI. The API does not report these offsets, so the text is
+    /// tokenized locally. The tokenizer of the model is fetched once and cached.
+    pub async fn tokenize_with_spans(
+        &self,
+        text: &str,
+        model: &str,
+        how: &How,
+    ) -> Result<Vec<TokenSpan>, Error> {
+        let tokenizer = self.cached_tokenizer(model, how).await?;
+        token_spans(&tokenizer, text).map_err(|e| Error::InvalidTokenizer {
+            deserialization_error: e.to_string(),
+        })
+    }
+
+    /// Resolves words to the [`Sampling::logit_bias`] of their tokens, so you can e.g. boost the
+    /// word "yes" without tokenizing it yourself. Every token of a word receives the bias of the
+    /// word. The tokenizer of the model is fetched once and cached.
+    ///
+    /// ```no_run
+    /// use aleph_alpha_client::{Client, Error, How, TaskCompletion};
+    ///
+    /// async fn boosted_yes() -> Result<(), Error> {
+    ///     let client = Client::from_env()?;
+    ///     let model = "pharia-1-llm-7b-control";
+    ///     let mut task = TaskCompletion::from_text("Is the sky blue? Answer:");
+    ///     task.sampling.logit_bias =
+    ///         Some(client.token_bias(&[(" yes", 5.0)], model, &How::default()).await?);
+    ///     let response = client.completion(&task, model, &How::default()).await?;
+    ///     println!("{}", response.completion);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn token_bias(
+        &self,
+        words: &[(&str, f64)],
+        model: &str,
+        how: &How,
+    ) -> Result<HashMap<u32, f64>, Error> {
+        let tokenizer = self.cached_tokenizer(model, how).await?;
+        token_bias(&tokenizer, words).map_err(|e| Error::InvalidTokenizer {
+            deserialization_error: e.to_string(),
+        })
+    }
+
+    /// The special tokens of the model, e.g. to build prompts with begin and end of text markers
+    /// yourself. The tokenizer of the model is fetched once and cached.
+    pub async fn special_tokens(&self, model: &str, how: &How) -> Result<SpecialTokens, Error> {
+        let tokenizer = self.cached_tokenizer(model, how).await?;
+        Ok(SpecialTokens::from_tokenizer(&tokenizer))
+    }
+
+    /// Tokenizer of the model, fetched on first use.
+    async fn cached_tokenizer(&self, model: &str, how: &How) -> Result<Arc<Tokenizer>, Error> {
+        let cached = self.tokenizers.lock().unwrap().get(model).cloned();
+        if let Some(tokenizer) = cached {
+            return Ok(tokenizer);
+        }
+        let tokenizer = Arc::new(self.http_client.tokenizer_by_model(model, how).await?);
+        self.tokenizers
+            .lock()
+            .unwrap()
+            .insert(model.to_owned(), tokenizer.clone());
+        Ok(tokenizer)
+    }
+
     /// Detokenize a list of token ids into a string.
     ///
     /// ```no_run
@@ -428,15 +1277,290 @@ impl Client {
             .await
     }
 
+    /// Detokenize several sequences of token ids with the same model. The API does not offer a
+    /// batch route, so one request is sent for each sequence, all of them concurrently. The outputs
+    /// are returned in the order of the sequences.
+    ///
+    /// ```no_run
+    /// use aleph_alpha_client::{Client, Error, How, TaskBatchDetokenization};
+    ///
+    /// async fn batch_detokenize() -> Result<(), Error> {
+    ///     let client = Client::from_env()?;
+    ///
+    ///     let task = TaskBatchDetokenization {
+    ///         sequences: vec![&[556, 48741], &[247, 2983]],
+    ///     };
+    ///     let responses = client
+    ///         .batch_detokenize(&task, "luminous-base", &How::default())
+    ///         .await?;
+    ///
+    ///     dbg!(&responses);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn batch_detokenize(
+        &self,
+        task: &TaskBatchDetokenization<'_>,
+        model: &str,
+        how: &How,
+    ) -> Result<Vec<DetokenizationOutput>, Error> {
+        futures_util::future::try_join_all(
+            task.tasks()
+                .map(|task| async move { self.detokenize(&task, model, how).await }),
+        )
+        .await
+    }
+
+    /// Renders a chat into the single prompt the model expects, using the prompt template of the
+    /// model. The prompt can then be sent to the completion endpoint, e.g. if the chat endpoint is
+    /// not available for a model. The template is fetched once and cached.
+    ///
+    /// Templates are Jinja, rendered like the chat templates of Hugging Face tokenizers.
+    /// `bos_token` and `eos_token` render as empty strings. Templates failing to render, and
+    /// models without a template, are reported as [`Error::InvalidPromptTemplate`].
+    pub async fn render_chat_as_prompt(
+        &self,
+        model: &str,
+        task: &TaskChat<'_>,
+        how: &How,
+    ) -> Result<Prompt<'static>, Error> {
+        let template = self
+            .model_settings(model, how)
+            .await?
+            .and_then(|settings| settings.prompt_template)
+            .ok_or_else(|| Error::InvalidPromptTemplate {
+                reason: format!("No prompt template available for model {model}"),
+            })?;
+        let text = prompt_template::render(&template, &task.messages)
+            .map_err(|reason| Error::InvalidPromptTemplate { reason })?;
+        Ok(Prompt::from_text(text))
+    }
+
+    /// Chat with a model which only offers the completion endpoint. The messages are rendered into
+    /// a prompt using the template of the model (see [`Self::render_chat_as_prompt`]) and the
+    /// completion is returned as the message of the assistant. This allows using the same chat API
+    /// across all models.
+    ///
+    /// The [`TaskChat::response_format`] is not supported by the completion endpoint and therefore
+    /// ignored.
+    pub async fn chat_via_completion(
+        &self,
+        model: &str,
+        task: &TaskChat<'_>,
+        how: &How,
+    ) -> Result<ChatOutput, Error> {
+        let prompt = self.render_chat_as_prompt(model, task, how).await?;
+        let ChatSampling {
+            temperature,
+            top_p,
+            frequency_penalty,
+            presence_penalty,
+        } = task.sampling;
+        let completion_task = TaskCompletion {
+            prompt,
+            stopping: Stopping {
+                maximum_tokens: task.stopping.maximum_tokens,
+                stop_sequences: task.stopping.stop_sequences,
+            },
+            sampling: Sampling {
+                temperature,
+                top_p,
+                frequency_penalty,
+                presence_penalty,
+                ..Sampling::MOST_LIKELY
+            },
+            special_tokens: false,
+            logprobs: task.logprobs,
+            token_ids: false,
+            echo: false,
+            disable_optimizations: false,
+        };
+        let output = self.completion(&completion_task, model, how).await?;
+        // Report the finish reason the way the chat endpoint would
+        let finish_reason = match output.typed_finish_reason() {
+            FinishReason::Stop => "stop".to_owned(),
+            FinishReason::Length => "length".to_owned(),
+            FinishReason::ContentFilter => "content_filter".to_owned(),
+            FinishReason::ToolCalls => "tool_calls".to_owned(),
+            FinishReason::Unknown(reason) => reason,
+        };
+        Ok(ChatOutput::new(
+            Message::assistant(output.completion),
+            finish_reason,
+            output.logprobs,
+            output.usage,
+        ))
+    }
+
+    /// Checks the prompt against the capabilities of the model, before sending it. Prompts
+    /// containing images for a model which is not multimodal, or more images than `max_images`,
+    /// are reported as [`Error::InvalidPrompt`]. The model settings are fetched once and cached.
+    ///
+    /// The API does not report how many images a model accepts in a single prompt, so the limit,
+    /// if any, has to be provided by the caller.
+    pub async fn validate_prompt(
+        &self,
+        model: &str,
+        prompt: &Prompt<'_>,
+        max_images: Option<usize>,
+        how: &How,
+    ) -> Result<(), Error> {
+        let settings =
+            self.model_settings(model, how)
+                .await?
+                .ok_or_else(|| Error::InvalidPrompt {
+                    reason: format!("Model {model} is not available"),
+                })?;
+        let images = prompt.image_count();
+        if images > 0 && !settings.multimodal {
+            return Err(Error::InvalidPrompt {
+                reason: format!("Model {model} does not support images"),
+            });
+        }
+        if let Some(max_images) = max_images.filter(|&max| images > max) {
+            return Err(Error::InvalidPrompt {
+                reason: format!(
+                    "Model {model} is limited to {max_images} images, but the prompt contains \
+                    {images}"
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks that the API accepts the token, by making a cheap authenticated request. Lets e.g.
+    /// command line tools fail fast on bad credentials, rather than after preparing their work.
+    /// Fails with [`Error::Unauthorized`] if the token is rejected.
+    ///
+    /// ```no_run
+    /// use aleph_alpha_client::{Client, Error, How};
+    ///
+    /// async fn check_credentials() -> Result<(), Error> {
+    ///     let client = Client::from_env()?;
+    ///     client.verify_token(&How::default()).await
+    /// }
+    /// ```
+    pub async fn verify_token(&self, how: &How) -> Result<(), Error> {
+        self.http_client
+            .output_of(&TaskModelSettings, how)
+            .await
+            .map(|_| ())
+    }
+
+    /// Settings of the model, or `None` if the API does not know it. The settings of all models are
+    /// fetched at once and cached afterwards.
+    pub(crate) async fn model_settings(
+        &self,
+        model: &str,
+        how: &How,
+    ) -> Result<Option<ModelSettings>, Error> {
+        if let Some(settings) = self.model_settings.lock().unwrap().get(model) {
+            return Ok(Some(settings.clone()));
+        }
+        let fetched = self.http_client.output_of(&TaskModelSettings, how).await?;
+        let mut cache = self.model_settings.lock().unwrap();
+        cache.extend(
+            fetched
+                .into_iter()
+                .map(|settings| (settings.name.clone(), settings)),
+        );
+        Ok(cache.get(model).cloned())
+    }
+
     pub async fn tokenizer_by_model(
         &self,
         model: &str,
         api_token: Option<String>,
     ) -> Result<Tokenizer, Error> {
-        self.http_client.tokenizer_by_model(model, api_token).await
+        let how = How {
+            api_token,
+            ..How::default()
+        };
+        self.http_client.tokenizer_by_model(model, &how).await
     }
 }
 
+/// Inference URL used by [`Client::from_env`] if `INFERENCE_URL` is not set at runtime.
+const DEFAULT_INFERENCE_URL: Option<&str> = option_env!("INFERENCE_URL");
+
+/// The inference URL set at runtime, falling back to `default`.
+fn inference_url_or_default(runtime: Option<String>, default: Option<&str>) -> Option<String> {
+    runtime.or_else(|| default.map(str::to_owned))
+}
+
+/// Value of the environment variable `name`, which is required to configure the client.
+fn env_var(name: &'static str) -> Result<String, Error> {
+    env::var(name).map_err(|_| Error::MissingEnvVar { name })
+}
+
+/// Checks the content assembled from the chunks of the stream against `format`, once the chunk
+/// with the finish reason has been received. A violation is yielded as error after that chunk.
+fn with_response_check(
+    mut stream: Pin<Box<dyn Stream<Item = Result<ChatStreamChunk, Error>> + Send>>,
+    format: ResponseFormat,
+) -> Pin<Box<dyn Stream<Item = Result<ChatStreamChunk, Error>> + Send>> {
+    Box::pin(stream! {
+        let mut content = String::new();
+        while let Some(chunk) = stream.next().await {
+            let finished = match &chunk {
+                Ok(chunk) => {
+                    content.push_str(&chunk.delta.content);
+                    chunk.finish_reason.is_some()
+                }
+                Err(_) => false,
+            };
+            yield chunk;
+            if finished {
+                if let Err(e) = format.check(&content) {
+                    yield Err(e);
+                }
+            }
+        }
+    })
+}
+
+/// Splits the echoed `prompt` from the generated text in the first chunk of the stream. See
+/// [`StreamChunk::is_echo`].
+fn with_echo_split(
+    stream: Pin<Box<dyn Stream<Item = Result<CompletionEvent, Error>> + Send>>,
+    prompt_text: Option<String>,
+) -> Pin<Box<dyn Stream<Item = Result<CompletionEvent, Error>> + Send>> {
+    let mut is_first_chunk = true;
+    Box::pin(stream.flat_map(move |event| {
+        let events = match event {
+            Ok(CompletionEvent::StreamChunk(chunk)) if is_first_chunk => {
+                is_first_chunk = false;
+                chunk
+                    .split_echo(prompt_text.as_deref())
+                    .into_iter()
+                    .map(|chunk| Ok(CompletionEvent::StreamChunk(chunk)))
+                    .collect()
+            }
+            event => vec![event],
+        };
+        futures_util::stream::iter(events)
+    }))
+}
+
+/// Records on each delta of the stream the time passed since the previous delta, or since `start`
+/// for the first one. `timing` selects the field to record the duration in, events which are not
+/// deltas are skipped.
+fn with_token_timing<T: Send + 'static>(
+    stream: Pin<Box<dyn Stream<Item = Result<T, Error>> + Send>>,
+    start: Instant,
+    timing: fn(&mut T) -> Option<&mut Option<Duration>>,
+) -> Pin<Box<dyn Stream<Item = Result<T, Error>> + Send>> {
+    let mut previous = start;
+    Box::pin(stream.map(move |mut item| {
+        if let Some(time_since_previous) = item.as_mut().ok().and_then(timing) {
+            let now = Instant::now();
+            *time_since_previous = Some(now - previous);
+            previous = now;
+        }
+        item
+    }))
+}
+
 /// Controls of how to execute a task
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct How {
@@ -446,28 +1570,293 @@ pub struct How {
     /// and you do not want to increase queue time for other users too much.
     ///
     /// (!) This increases how often you get a `Busy` response.
+    ///
+    /// Setting this flag is equivalent to setting [`How::priority`] to [`Priority::Low`] and takes
+    /// precedence over it.
     pub be_nice: bool,
 
+    /// Hint for the scheduler of the API on how urgent this request is compared to others. Use
+    /// [`Priority::Low`] for batch workloads and experiments and [`Priority::High`] for
+    /// interactive use cases in a mixed workload.
+    pub priority: Priority,
+
     /// The maximum duration of a request before the client cancels the request. This is not passed on
     /// to the server but only handled by the client locally, i.e. the client will not wait longer than
     /// this duration for a response.
-    pub client_timeout: Duration,
+    ///
+    /// By default, the default timeout of the client applies. See [`Client::with_timeout`]. Set it
+    /// to [`ClientTimeout::None`] to wait for the server as long as it takes.
+    pub client_timeout: ClientTimeout,
+
+    /// Point in time by which the request must have been answered. Useful if several requests
+    /// share an overall deadline, e.g. in a pipeline. The request times out after whatever comes
+    /// first, [`How::client_timeout`] or the deadline. The deadline also applies if the timeout has
+    /// been disabled with [`ClientTimeout::None`]. If the deadline has already passed, the
+    /// request is not sent and fails with [`Error::ClientTimeout`].
+    pub deadline: Option<Instant>,
 
     /// API token used to authenticate the request, overwrites the default token provided on setup
     /// Default token may not provide the tracking or permission that is wanted for the request
     pub api_token: Option<String>,
+
+    /// Maximum size of the request body in bytes. Requests with larger bodies are not sent, but
+    /// fail locally with [`Error::RequestTooLarge`]. This guards against accidentally sending
+    /// huge payloads, e.g. a prompt containing a large image.
+    pub max_request_body_size: usize,
+
+    /// Compress the body of the request with gzip, announced by a `Content-Encoding: gzip` header.
+    /// Saves upload bandwidth for large requests, e.g. batch embeddings. Off by default, as it
+    /// requires the server, or a proxy in front of it, to decompress request bodies. Servers not
+    /// supporting it reject the request. [`How::max_request_body_size`] applies to the body
+    /// before compression.
+    pub compress_request: bool,
+
+    /// Identifies the request in the logs of the client and the server. Sent to the API as
+    /// `X-Request-Id` header. The id the server has used is available through
+    /// [`Client::output_of_with_raw`].
+    pub request_id: Option<String>,
+
+    /// Additional query parameters appended to the URL of the request. Allows to toggle features
+    /// of the API, or of gateways in front of it, which this client does not know about (yet).
+    pub query_params: Vec<(String, String)>,
+
+    /// Maximum time to wait for the next piece of a streamed chat or completion. If the stream
+    /// stalls for longer, it yields [`Error::StreamIdleTimeout`] and ends, so a stalled stream can
+    /// be told apart from a finished one. Unlike [`How::client_timeout`], this does not limit the
+    /// total duration of a long stream.
+    pub stream_idle_timeout: Option<Duration>,
+
+    /// Maximum size in bytes of a single event of a streamed chat or completion. If the server
+    /// sends more data without completing the event, the stream yields [`Error::InvalidStream`]
+    /// and ends. This guards against unbounded memory growth caused by a malformed stream.
+    pub max_stream_event_size: usize,
+
+    /// Measure the time between the deltas of streamed chats and completions. If set, each delta
+    /// carries the time passed since the previous one in `time_since_previous`, or the time to the
+    /// first token for the first delta. Useful to profile latency and throughput.
+    pub measure_token_timing: bool,
+
+    /// Propagates a distributed trace to the API via the `traceparent` and `tracestate` headers,
+    /// so the request can be correlated with the rest of your trace. See
+    /// [`How::with_new_trace`] to start a new one.
+    pub trace_context: Option<TraceContext>,
+
+    /// How the API token is sent. Defaults to a `Bearer` token in the `Authorization` header.
+    /// Change it for gateways in front of the API which expect credentials in another form.
+    pub auth_scheme: AuthScheme,
+
+    /// Hint that requests with the same key share a prompt prefix, e.g. a long system prompt or
+    /// document, sent as `X-Prompt-Cache-Key` header. Servers supporting prefix caching may answer
+    /// such requests faster. This is best-effort: servers without caching ignore it, and the
+    /// answer is the same either way.
+    pub cache_key: Option<String>,
+
+    /// Cancels the request once its token is cancelled, failing it with [`Error::Cancelled`].
+    /// Applies to requests which are not streamed. Streams are cancelled by dropping them.
+    #[cfg(feature = "cancellation")]
+    pub cancellation: Option<Cancellation>,
 }
 
+/// The aleph-alpha-api cancels request after 5 minute. On the client side a request can take
+/// longer in case of network errors, therefore by default we wait slightly longer.
+const DEFAULT_CLIENT_TIMEOUT: Duration = Duration::from_secs(300 + 5);
+
 impl Default for How {
     fn default() -> Self {
-        // the aleph-alpha-api cancels request after 5 minute
-        let api_timeout = Duration::from_secs(300);
         Self {
             be_nice: Default::default(),
-            // on the client side a request can take longer in case of network errors
-            // therefore by default we wait slightly longer
-            client_timeout: api_timeout + Duration::from_secs(5),
+            priority: Priority::default(),
+            client_timeout: ClientTimeout::Default,
+            deadline: None,
             api_token: None,
+            // Generous enough for any prompt the API would accept
+            max_request_body_size: 32 * 1024 * 1024,
+            compress_request: false,
+            request_id: None,
+            query_params: Vec::new(),
+            stream_idle_timeout: None,
+            // Far larger than any event the API sends, even with logprobs
+            max_stream_event_size: 16 * 1024 * 1024,
+            measure_token_timing: false,
+            trace_context: None,
+            auth_scheme: AuthScheme::default(),
+            cache_key: None,
+            #[cfg(feature = "cancellation")]
+            cancellation: None,
+        }
+    }
+}
+
+/// Per call changes to a [`How`], applied with [`How::merge`]. Fields left at `None` keep the
+/// value of the base settings. See the fields of [`How`] for their meaning. Fields which are
+/// optional in [`How`] are doubly wrapped, so `Some(None)` clears the value of the base settings.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct HowOverrides {
+    pub be_nice: Option<bool>,
+    pub priority: Option<Priority>,
+    pub client_timeout: Option<ClientTimeout>,
+    pub deadline: Option<Option<Instant>>,
+    pub api_token: Option<Option<String>>,
+    pub max_request_body_size: Option<usize>,
+    pub compress_request: Option<bool>,
+    pub request_id: Option<Option<String>>,
+    /// Replaces the query parameters of the base settings as a whole.
+    pub query_params: Option<Vec<(String, String)>>,
+    pub stream_idle_timeout: Option<Option<Duration>>,
+    pub max_stream_event_size: Option<usize>,
+    pub measure_token_timing: Option<bool>,
+    pub trace_context: Option<Option<TraceContext>>,
+    pub auth_scheme: Option<AuthScheme>,
+    pub cache_key: Option<Option<String>>,
+    #[cfg(feature = "cancellation")]
+    pub cancellation: Option<Option<Cancellation>>,
+}
+
+/// How a request is authenticated. See [`How::auth_scheme`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum AuthScheme {
+    /// `Authorization: Bearer {token}`, as expected by the API.
+    #[default]
+    Bearer,
+    /// The token as value of a header with the given name, e.g. `X-API-Key: {token}`.
+    ApiKeyHeader(String),
+    /// The given header name and value, as they are, e.g. `Authorization` and `ApiKey {token}`.
+    /// The API token of the client and of [`How::api_token`] is not used.
+    Raw(String, String),
+}
+
+impl AuthScheme {
+    /// Name and value of the header authenticating a request.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the scheme requires a token, but `api_token` is `None`.
+    pub(crate) fn header(&self, api_token: Option<&str>) -> (&str, String) {
+        let token =
+            || api_token.expect("API token needs to be set on client construction or per request");
+        match self {
+            AuthScheme::Bearer => (
+                reqwest::header::AUTHORIZATION.as_str(),
+                format!("Bearer {}", token()),
+            ),
+            AuthScheme::ApiKeyHeader(name) => (name, token().to_owned()),
+            AuthScheme::Raw(name, value) => (name, value.clone()),
+        }
+    }
+}
+
+/// Client side timeout of a request. See [`How::client_timeout`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ClientTimeout {
+    /// The default timeout of the client applies. See [`Client::with_timeout`].
+    #[default]
+    Default,
+    /// Cancel the request after the given duration.
+    Fixed(Duration),
+    /// Wait for the server as long as it takes, e.g. for batch jobs which legitimately run longer
+    /// than any fixed timeout. The request then only ends once the server responds or cancels it,
+    /// or [`How::deadline`] is reached.
+    None,
+}
+
+/// Queue priority of a request. See [`How::priority`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Priority {
+    /// Reduce the load for the models you intend to use, so other users are not slowed down by
+    /// your requests. Same as setting [`How::be_nice`].
+    ///
+    /// (!) This increases how often you get a `Busy` response.
+    Low,
+    /// Default priority of the API.
+    #[default]
+    Normal,
+    /// Ask the API to prefer this request over requests with normal priority.
+    High,
+}
+
+impl Priority {
+    /// Query parameters communicating the priority to the API. Normal priority is the default of
+    /// the API, so we just omit it.
+    fn query(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Priority::Low => &[("nice", "true")],
+            Priority::Normal => &[],
+            Priority::High => &[("priority", "high")],
+        }
+    }
+}
+
+impl How {
+    /// Same settings, but sent as part of a new sampled trace with random ids. Use it if you
+    /// want to trace a request without having a trace to join yet.
+    pub fn with_new_trace(mut self) -> Self {
+        self.trace_context = Some(TraceContext::new_random());
+        self
+    }
+
+    /// Copy of these settings with the fields set in `overrides` replaced. Lets you derive the
+    /// settings of a single call from shared base settings.
+    ///
+    /// ```
+    /// use aleph_alpha_client::{ClientTimeout, How, HowOverrides};
+    /// use std::time::Duration;
+    ///
+    /// let base = How::default();
+    /// let how = base.merge(HowOverrides {
+    ///     client_timeout: Some(ClientTimeout::Fixed(Duration::from_secs(10))),
+    ///     ..HowOverrides::default()
+    /// });
+    /// assert_eq!(how.client_timeout, ClientTimeout::Fixed(Duration::from_secs(10)));
+    /// ```
+    pub fn merge(&self, overrides: HowOverrides) -> How {
+        let HowOverrides {
+            be_nice,
+            priority,
+            client_timeout,
+            deadline,
+            api_token,
+            max_request_body_size,
+            compress_request,
+            request_id,
+            query_params,
+            stream_idle_timeout,
+            max_stream_event_size,
+            measure_token_timing,
+            trace_context,
+            auth_scheme,
+            cache_key,
+            #[cfg(feature = "cancellation")]
+            cancellation,
+        } = overrides;
+        let base = self.clone();
+        How {
+            be_nice: be_nice.unwrap_or(base.be_nice),
+            priority: priority.unwrap_or(base.priority),
+            client_timeout: client_timeout.unwrap_or(base.client_timeout),
+            deadline: deadline.unwrap_or(base.deadline),
+            api_token: api_token.unwrap_or(base.api_token),
+            max_request_body_size: max_request_body_size.unwrap_or(base.max_request_body_size),
+            compress_request: compress_request.unwrap_or(base.compress_request),
+            request_id: request_id.unwrap_or(base.request_id),
+            query_params: query_params.unwrap_or(base.query_params),
+            stream_idle_timeout: stream_idle_timeout.unwrap_or(base.stream_idle_timeout),
+            max_stream_event_size: max_stream_event_size.unwrap_or(base.max_stream_event_size),
+            measure_token_timing: measure_token_timing.unwrap_or(base.measure_token_timing),
+            trace_context: trace_context.unwrap_or(base.trace_context),
+            auth_scheme: auth_scheme.unwrap_or(base.auth_scheme),
+            cache_key: cache_key.unwrap_or(base.cache_key),
+            #[cfg(feature = "cancellation")]
+            cancellation: cancellation.unwrap_or(base.cancellation),
+        }
+    }
+
+    /// Priority with which the request is sent, taking the legacy [`How::be_nice`] flag into
+    /// account.
+    fn effective_priority(&self) -> Priority {
+        if self.be_nice {
+            Priority::Low
+        } else {
+            self.priority
         }
     }
 }
@@ -547,7 +1936,7 @@ pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
 
 #[cfg(test)]
 mod tests {
-    use crate::Prompt;
+    use crate::{inference_url_or_default, Prompt};
 
     #[test]
     fn ability_to_generate_prompt_in_local_function() {
@@ -557,4 +1946,31 @@ mod tests {
 
         assert_eq!(Prompt::from_text("My test prompt"), local_function())
     }
+
+    #[test]
+    fn inference_url_falls_back_to_default_if_only_token_is_set() {
+        // Given no inference URL set at runtime
+        let runtime = None;
+
+        // When
+        let url = inference_url_or_default(runtime, Some("https://inference-api.example.com"));
+
+        // Then
+        assert_eq!(url.as_deref(), Some("https://inference-api.example.com"));
+    }
+
+    #[test]
+    fn inference_url_set_at_runtime_takes_precedence() {
+        let url = inference_url_or_default(
+            Some("http://localhost:8080".to_owned()),
+            Some("https://inference-api.example.com"),
+        );
+
+        assert_eq!(url.as_deref(), Some("http://localhost:8080"));
+    }
+
+    #[test]
+    fn missing_inference_url_without_default_is_reported() {
+        assert_eq!(inference_url_or_default(None, None), None);
+    }
 }
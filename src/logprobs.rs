@@ -1,10 +1,12 @@
 use std::str::{self, Utf8Error};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Logprobs {
     /// Do not return any logprobs
+    #[default]
     No,
     /// Return only the logprob of the tokens which have actually been sampled into the completion.
     Sampled,
@@ -12,7 +14,7 @@ pub enum Logprobs {
     Top(u8),
 }
 
-#[derive(Deserialize, Debug, PartialEq)]
+#[derive(Deserialize, Debug, PartialEq, Clone)]
 pub struct Logprob {
     // The API returns both a UTF-8 String token and bytes as an array of numbers. We only
     // deserialize bytes as it is the better source of truth.
@@ -26,4 +28,86 @@ impl Logprob {
     pub fn token_as_str(&self) -> Result<&str, Utf8Error> {
         str::from_utf8(&self.token)
     }
+
+    /// The logprob with single precision. Logprobs are deserialized with the full precision the API
+    /// reports them with, while e.g. embeddings use `f32`. Use this if you want to combine them
+    /// without converting yourself.
+    pub fn logprob_f32(&self) -> f32 {
+        self.logprob as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Distribution;
+
+    use super::*;
+
+    #[test]
+    fn logprob_as_f32() {
+        let logprob = Logprob {
+            token: b" keeps".to_vec(),
+            logprob: -0.25,
+        };
+
+        assert_eq!(logprob.logprob_f32(), -0.25f32);
+    }
+
+    #[test]
+    fn distribution_logprob_as_f32() {
+        let distribution = Distribution {
+            sampled: Logprob {
+                token: b" keeps".to_vec(),
+                logprob: -0.1,
+            },
+            top: vec![],
+        };
+
+        assert_eq!(distribution.logprob_f32(), -0.1f32);
+    }
+
+    fn logprob(token: &str, logprob: f64) -> Logprob {
+        Logprob {
+            token: token.as_bytes().to_vec(),
+            logprob,
+        }
+    }
+
+    #[test]
+    fn top_n_of_distribution() {
+        // Given a distribution with three top logprobs
+        let distribution = Distribution {
+            sampled: logprob(" keeps", -0.5),
+            top: vec![
+                logprob(" is", -0.2),
+                logprob(" keeps", -0.5),
+                logprob(" makes", -1.7),
+            ],
+        };
+
+        // Then
+        assert_eq!(
+            distribution.top_n(2),
+            [logprob(" is", -0.2), logprob(" keeps", -0.5)]
+        );
+        assert_eq!(distribution.top_n(5).len(), 3);
+        assert!(distribution.top_n(0).is_empty());
+        assert!(distribution.contains_sampled_in_top());
+    }
+
+    #[test]
+    fn sampled_token_outside_of_top() {
+        let distribution = Distribution {
+            sampled: logprob(" banana", -4.0),
+            top: vec![logprob(" is", -0.2)],
+        };
+        let without_top = Distribution {
+            top: vec![],
+            ..distribution.clone()
+        };
+
+        assert!(!distribution.contains_sampled_in_top());
+        assert!(!without_top.contains_sampled_in_top());
+        assert!(without_top.top_n(1).is_empty());
+    }
 }
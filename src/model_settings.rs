@@ -0,0 +1,32 @@
+use serde::Deserialize;
+
+use crate::{Error, Job};
+
+/// Settings of a model as reported by the API.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelSettings {
+    /// Name of the model. E.g. `luminous-base`.
+    pub name: String,
+    /// Template used to render a conversation into a prompt for this model, if any.
+    #[serde(default)]
+    pub prompt_template: Option<String>,
+    /// Whether the model accepts images in its prompt.
+    #[serde(default)]
+    pub multimodal: bool,
+}
+
+/// Lists the settings of all models available at the API.
+pub struct TaskModelSettings;
+
+impl Job for TaskModelSettings {
+    type Output = Vec<ModelSettings>;
+    type ResponseBody = Vec<ModelSettings>;
+
+    fn build_request(&self, client: &reqwest::Client, base: &str) -> reqwest::RequestBuilder {
+        client.get(format!("{base}/model-settings"))
+    }
+
+    fn body_to_output(&self, response: Self::ResponseBody) -> Result<Self::Output, Error> {
+        Ok(response)
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use crate::Usage;
+
+/// Price of a single token for a model, in a currency of your choice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenPrice {
+    /// Price of a single token in the prompt.
+    pub prompt: f64,
+    /// Price of a single generated token.
+    pub completion: f64,
+}
+
+/// Prices per token by model name, used to estimate the cost of requests from their [`Usage`].
+/// Prices change and depend on your contract, so this client does not ship any. Fill in the ones
+/// which apply to you.
+///
+/// ```
+/// use aleph_alpha_client::{PriceTable, TokenPrice, Usage};
+///
+/// let prices = PriceTable::new().with_price(
+///     "pharia-1-llm-7b-control",
+///     TokenPrice { prompt: 0.000_001, completion: 0.000_002 },
+/// );
+/// let usage = Usage { prompt_tokens: 1000, completion_tokens: 500 };
+/// let cost = usage.estimated_cost("pharia-1-llm-7b-control", &prices).unwrap();
+/// assert!((cost - 0.002).abs() < 1e-9);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PriceTable {
+    prices: HashMap<String, TokenPrice>,
+}
+
+impl PriceTable {
+    /// An empty price table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the price for a model, replacing any previous one.
+    pub fn with_price(mut self, model: impl Into<String>, price: TokenPrice) -> Self {
+        self.prices.insert(model.into(), price);
+        self
+    }
+
+    /// Price of the model, if it is part of the table.
+    pub fn price(&self, model: &str) -> Option<TokenPrice> {
+        self.prices.get(model).copied()
+    }
+}
+
+impl Usage {
+    /// Estimated cost of a request to `model` with this usage. `None` if the price table does not
+    /// contain the model.
+    pub fn estimated_cost(&self, model: &str, prices: &PriceTable) -> Option<f64> {
+        let price = prices.price(model)?;
+        Some(
+            self.prompt_tokens as f64 * price.prompt
+                + self.completion_tokens as f64 * price.completion,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_table() -> PriceTable {
+        PriceTable::new()
+            .with_price(
+                "luminous-base",
+                TokenPrice {
+                    prompt: 0.03,
+                    completion: 0.04,
+                },
+            )
+            .with_price(
+                "pharia-1-llm-7b-control",
+                TokenPrice {
+                    prompt: 0.01,
+                    completion: 0.02,
+                },
+            )
+    }
+
+    #[test]
+    fn cost_of_prompt_and_completion_tokens() {
+        // Given
+        let usage = Usage {
+            prompt_tokens: 100,
+            completion_tokens: 50,
+        };
+
+        // When
+        let cost = usage
+            .estimated_cost("pharia-1-llm-7b-control", &sample_table())
+            .unwrap();
+
+        // Then 100 * 0.01 + 50 * 0.02
+        assert!((cost - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn cost_of_unknown_model_is_not_estimated() {
+        let usage = Usage {
+            prompt_tokens: 100,
+            completion_tokens: 50,
+        };
+
+        let cost = usage.estimated_cost("unknown-model", &sample_table());
+
+        assert_eq!(cost, None);
+    }
+}
@@ -4,28 +4,112 @@ use std::{
 };
 
 use base64::{prelude::BASE64_STANDARD, Engine};
-use image::DynamicImage;
+use image::{DynamicImage, GenericImageView};
 use itertools::Itertools;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::image_preprocessing::{self, LoadImageError};
 
 /// A prompt which is passed to the model for inference. Usually it is one text item, but it could
 /// also be a combination of several modalities like images and text.
-#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+///
+/// Prompts can be deserialized from the same JSON format they are serialized to, so you can e.g.
+/// keep a library of prompts on disk.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
 pub struct Prompt<'a>(Vec<Modality<'a>>);
 
 impl<'a> Prompt<'a> {
+    /// Create an empty prompt. Items can be added with [`Self::push_text`] and
+    /// [`Self::push_image`].
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
     /// Create a prompt from a single text item.
     pub fn from_text(text: impl Into<Cow<'a, str>>) -> Self {
         Self(vec![Modality::from_text(text)])
     }
 
+    /// Create a prompt with one text item for each of the given texts. E.g. for few shot examples.
+    /// Use [`Self::join_consecutive_text_items`] if you want to merge them into a single item.
+    pub fn from_texts<T>(texts: impl IntoIterator<Item = T>) -> Self
+    where
+        T: Into<Cow<'a, str>>,
+    {
+        Self(texts.into_iter().map(Modality::from_text).collect())
+    }
+
     /// Create a multimodal prompt from a list of individual items with any modality.
     pub fn from_vec(items: Vec<Modality<'a>>) -> Self {
         Self(items)
     }
 
+    /// Number of image items in the prompt.
+    pub fn image_count(&self) -> usize {
+        self.0
+            .iter()
+            .filter(|item| matches!(item, Modality::Image { .. }))
+            .count()
+    }
+
+    /// Appends a text item at the end of the prompt. Use [`Self::join_consecutive_text_items`] if
+    /// you want to merge it with a text item in front of it.
+    pub fn push_text(&mut self, text: impl Into<Cow<'a, str>>) {
+        self.0.push(Modality::from_text(text));
+    }
+
+    /// Appends `text` to the last item of the prompt if it is a text, otherwise adds a new text
+    /// item. Text items in front of it are left untouched.
+    pub(crate) fn extend_last_text(&mut self, text: &str) {
+        match self.0.last_mut() {
+            Some(Modality::Text { data }) => data.to_mut().push_str(text),
+            _ => self.push_text(text.to_owned()),
+        }
+    }
+
+    /// Appends an image item at the end of the prompt, e.g. created with
+    /// [`Modality::from_image_path`].
+    pub fn push_image(&mut self, image: Modality<'a>) {
+        self.0.push(image);
+    }
+
+    /// Estimated size in bytes of the prompt as part of a JSON request body, e.g. to predict
+    /// whether a request exceeds [`crate::How::max_request_body_size`]. Images count with their
+    /// base64 encoding, which is about a third larger than the encoded image itself. Characters in
+    /// texts which need to be escaped in JSON are counted only once, so texts with many quotes or
+    /// control characters are slightly underestimated. This is about bytes, not tokens.
+    pub fn serialized_size_estimate(&self) -> usize {
+        // `[]` around the items and a `,` between each of them
+        let separators = 2 + self.0.len().saturating_sub(1);
+        let items: usize = self
+            .0
+            .iter()
+            .map(|item| match item {
+                // {"type":"text","data":""}
+                Modality::Text { data } => 25 + data.len(),
+                // {"type":"image","data":""}
+                Modality::Image { data } => 26 + data.len(),
+            })
+            .sum();
+        separators + items
+    }
+
+    /// The text of the prompt, if it consists of text items only.
+    pub(crate) fn text(&self) -> Option<String> {
+        self.0
+            .iter()
+            .map(|item| match item {
+                Modality::Text { data } => Some(data.as_ref()),
+                Modality::Image { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Copies borrowed contents, so the prompt no longer depends on the lifetime of its inputs.
+    pub fn into_owned(self) -> Prompt<'static> {
+        Prompt(self.0.into_iter().map(Modality::into_owned).collect())
+    }
+
     /// Allows you to borrow the contents of the prompt without allocating a new one.
     pub fn borrow(&'a self) -> Prompt<'a> {
         Self(self.0.iter().map(|item| item.borrow()).collect())
@@ -60,7 +144,7 @@ impl<'a> Prompt<'a> {
 
 /// The prompt for models can be a combination of different modalities (Text and Image). The type of
 /// modalities which are supported depend on the Model in question.
-#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Modality<'a> {
     /// The only type of prompt which can be used with pure language models
@@ -69,6 +153,35 @@ pub enum Modality<'a> {
     Image { data: Cow<'a, str> },
 }
 
+/// A rectangular region of an image in pixels, e.g. to show only part of an image to the model with
+/// [`Modality::from_image_region`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageRegion {
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ImageRegion {
+    pub fn new(left: u32, top: u32, width: u32, height: u32) -> Self {
+        Self {
+            left,
+            top,
+            width,
+            height,
+        }
+    }
+
+    /// `true` if the region is not empty and lies entirely within an image of the given size.
+    fn is_within(&self, image_width: u32, image_height: u32) -> bool {
+        let fits = |start: u32, length: u32, limit: u32| {
+            length > 0 && start.checked_add(length).is_some_and(|end| end <= limit)
+        };
+        fits(self.left, self.width, image_width) && fits(self.top, self.height, image_height)
+    }
+}
+
 impl<'a> Modality<'a> {
     /// Instantiates a text prompt
     pub fn from_text(text: impl Into<Cow<'a, str>>) -> Self {
@@ -99,6 +212,9 @@ impl<'a> Modality<'a> {
     ///         sampling: Sampling::MOST_LIKELY,
     ///         special_tokens: false,
     ///         logprobs: Logprobs::No,
+    ///         token_ids: false,
+    ///         echo: false,
+    ///         disable_optimizations: false,
     ///     };
     ///     // Execute
     ///     let model = "luminous-base";
@@ -123,6 +239,55 @@ impl<'a> Modality<'a> {
         Ok(Self::from_image_bytes(&bytes))
     }
 
+    /// Image input for model, encoded as JPEG instead of PNG. Like [`Self::from_image`], the image
+    /// is center cropped and resized.
+    ///
+    /// For photographs JPEG yields far smaller payloads than the lossless PNG, at the cost of
+    /// compression artifacts. `quality` ranges from 1 (smallest) to 100 (best quality). Around 80
+    /// is a good trade off for photos. Stick to [`Self::from_image`] for images with text or sharp
+    /// edges, e.g. scanned documents or diagrams.
+    pub fn from_image_as_jpeg(image: &DynamicImage, quality: u8) -> Result<Self, LoadImageError> {
+        let bytes = image_preprocessing::preprocess_image_as_jpeg(image, quality);
+        Ok(Self::from_image_bytes(&bytes))
+    }
+
+    /// An image followed by its caption as text, e.g. to build multimodal datasets. `image` is an
+    /// image modality created with e.g. [`Self::from_image_path`]. Extend a prompt with the
+    /// returned items or pass them to [`Prompt::from_vec`].
+    ///
+    /// ```no_run
+    /// use aleph_alpha_client::{Modality, Prompt};
+    ///
+    /// let image = Modality::from_image_path("cat.png").unwrap();
+    /// let prompt = Prompt::from_vec(Modality::image_with_caption(image, "A cat on a mat").into());
+    /// ```
+    pub fn image_with_caption(image: Self, caption: impl Into<Cow<'a, str>>) -> [Self; 2] {
+        [image, Self::from_text(caption)]
+    }
+
+    /// Image input for model, showing only a region of the image.
+    ///
+    /// Use this to direct the attention of the model to a specific part of an image, e.g. a
+    /// paragraph of a scanned document. The region is cut out of the image before preprocessing.
+    /// As the model can only see squared pictures, regions which are not square shaped are center
+    /// cropped. Fails if the region is empty or does not lie within the image. Use
+    /// [`crate::ImageScore::to_original_pixels_in_region`] to relate explanations to the image.
+    pub fn from_image_region(
+        image: &DynamicImage,
+        region: ImageRegion,
+    ) -> Result<Self, LoadImageError> {
+        let (width, height) = image.dimensions();
+        if !region.is_within(width, height) {
+            return Err(LoadImageError::InvalidRegion {
+                region,
+                width,
+                height,
+            });
+        }
+        let cropped = image.crop_imm(region.left, region.top, region.width, region.height);
+        Self::from_image(&cropped)
+    }
+
     /// Generates an image input from the binary representation of the image.
     ///
     /// Using this constructor you must use a binary representation compatible with the API. Png is
@@ -135,6 +300,60 @@ impl<'a> Modality<'a> {
         }
     }
 
+    /// Width and height of an image modality, as seen by the model. For images created with
+    /// [`Self::from_image`] or [`Self::from_image_path`] these are the dimensions after cropping
+    /// and resizing. Use them to relate e.g. the bounding boxes of an explanation to the image.
+    ///
+    /// Returns `None` for text modalities or if the image data can not be decoded.
+    pub fn image_dimensions(&self) -> Option<(u32, u32)> {
+        match self {
+            Modality::Text { .. } => None,
+            Modality::Image { data } => {
+                let bytes = BASE64_STANDARD.decode(data.as_bytes()).ok()?;
+                image_preprocessing::dimensions(&bytes)
+            }
+        }
+    }
+
+    /// Compares two modalities by what the model gets to see. Unlike `==`, images are compared by
+    /// their pixels rather than by their base64 encoding, so the same picture encoded in different
+    /// ways, e.g. with a different PNG compression, is considered equal. Texts are compared as
+    /// they are.
+    pub fn semantically_eq(&self, other: &Modality<'_>) -> bool {
+        match (self, other) {
+            (Modality::Text { data }, Modality::Text { data: other }) => data == other,
+            (Modality::Image { data }, Modality::Image { data: other }) => {
+                if data == other {
+                    return true;
+                }
+                let decode = |data: &str| {
+                    let bytes = BASE64_STANDARD.decode(data.as_bytes()).ok()?;
+                    image_preprocessing::decode(&bytes)
+                };
+                match (decode(data), decode(other)) {
+                    (Some(image), Some(other)) => {
+                        image.dimensions() == other.dimensions()
+                            && image.to_rgba8().as_raw() == other.to_rgba8().as_raw()
+                    }
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Copies borrowed contents, so the modality no longer depends on the lifetime of its input.
+    pub fn into_owned(self) -> Modality<'static> {
+        match self {
+            Modality::Text { data } => Modality::Text {
+                data: Cow::Owned(data.into_owned()),
+            },
+            Modality::Image { data } => Modality::Image {
+                data: Cow::Owned(data.into_owned()),
+            },
+        }
+    }
+
     /// Create a semantically idetical entry of modality which borrows the contents of this one.
     ///
     /// It is very practical to allow Modality of e.g. Text to take both ownership of the string it
@@ -157,6 +376,101 @@ impl<'a> Modality<'a> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn extend_last_text_leaves_items_in_front_alone() {
+        let mut prompt = Prompt::from_vec(vec![
+            Modality::from_text("A picture of"),
+            Modality::Image {
+                data: "aW1hZ2U=".into(),
+            },
+            Modality::from_text("It shows"),
+        ]);
+
+        prompt.extend_last_text(" a cat");
+
+        assert_eq!(prompt.text(), None);
+        assert_eq!(prompt.0.len(), 3);
+        assert_eq!(prompt.0[0], Modality::from_text("A picture of"));
+        assert_eq!(prompt.0[2], Modality::from_text("It shows a cat"));
+    }
+
+    #[test]
+    fn extend_last_text_after_image_adds_text_item() {
+        let mut prompt = Prompt::from_vec(vec![Modality::Image {
+            data: "aW1hZ2U=".into(),
+        }]);
+
+        prompt.extend_last_text("A cat");
+
+        assert_eq!(prompt.0.len(), 2);
+        assert_eq!(prompt.0[1], Modality::from_text("A cat"));
+    }
+
+    #[test]
+    fn prompt_from_three_texts() {
+        let prompt = Prompt::from_texts(["foo", "bar", "baz"]);
+
+        assert_eq!(
+            prompt.0,
+            vec![
+                Modality::from_text("foo"),
+                Modality::from_text("bar"),
+                Modality::from_text("baz")
+            ]
+        );
+    }
+
+    #[test]
+    fn serialized_size_of_text_and_image_is_estimated() {
+        // Given a prompt with an image and a text containing a character to escape
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, 128])
+        }));
+        let prompt = Prompt::from_vec(vec![
+            Modality::from_image(&image).unwrap(),
+            Modality::from_text("What is shown in this \"picture\"?"),
+        ]);
+
+        // When
+        let estimate = prompt.serialized_size_estimate();
+
+        // Then
+        let actual = serde_json::to_string(&prompt).unwrap().len();
+        assert!(
+            actual.abs_diff(estimate) <= actual / 100,
+            "{estimate} vs {actual}"
+        );
+        assert_eq!(Prompt::new().serialized_size_estimate(), 2);
+        let texts = Prompt::from_texts(["foo", "bar"]);
+        assert_eq!(
+            texts.serialized_size_estimate(),
+            serde_json::to_string(&texts).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn image_is_followed_by_its_caption() {
+        // Given
+        let image = Modality::from_image(&DynamicImage::new_rgb8(4, 4)).unwrap();
+
+        // When
+        let mut prompt = Prompt::from_text("Describe the pictures.");
+        prompt.0.extend(Modality::image_with_caption(
+            image.clone(),
+            "A black square",
+        ));
+
+        // Then
+        assert_eq!(
+            prompt.0,
+            vec![
+                Modality::from_text("Describe the pictures."),
+                image,
+                Modality::from_text("A black square")
+            ]
+        );
+    }
+
     #[test]
     fn can_concatenate_prompt_items() {
         let mut prompt =
@@ -174,4 +488,185 @@ mod tests {
 
         assert_eq!(prompt.0, vec![Modality::from_text("foo\nbar")]);
     }
+
+    #[test]
+    fn prompt_is_built_incrementally() {
+        // Given an empty prompt
+        let mut prompt = Prompt::new();
+        let image = Modality::Image {
+            data: "aGVsbG8=".into(),
+        };
+
+        // When items are pushed in a loop
+        for caption in ["first", "second"] {
+            prompt.push_image(image.clone());
+            prompt.push_text(caption);
+        }
+
+        // Then they are in the order they have been pushed
+        assert_eq!(
+            prompt.0,
+            vec![
+                image.clone(),
+                Modality::from_text("first"),
+                image,
+                Modality::from_text("second")
+            ]
+        );
+        assert_eq!(prompt.image_count(), 2);
+    }
+
+    #[test]
+    fn new_prompt_is_empty() {
+        assert_eq!(Prompt::new(), Prompt::from_vec(Vec::new()));
+    }
+
+    /// Image modality with the image encoded in the given format, without any preprocessing.
+    fn encoded_as(image: &DynamicImage, format: image::ImageFormat) -> Modality<'static> {
+        let mut bytes = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut bytes, format).unwrap();
+        Modality::Image {
+            data: BASE64_STANDARD.encode(bytes.into_inner()).into(),
+        }
+    }
+
+    #[test]
+    fn same_image_in_different_encodings_is_semantically_equal() {
+        // Given the same image, once encoded as PNG and once as BMP
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(4, 4, |x, y| {
+            image::Rgb([x as u8 * 60, y as u8 * 60, 0])
+        }));
+        let png = encoded_as(&image, image::ImageFormat::Png);
+        let bmp = encoded_as(&image, image::ImageFormat::Bmp);
+
+        // Then
+        assert_ne!(png, bmp);
+        assert!(png.semantically_eq(&bmp));
+    }
+
+    #[test]
+    fn different_images_are_not_semantically_equal() {
+        let black = encoded_as(&DynamicImage::new_rgb8(4, 4), image::ImageFormat::Png);
+        let larger = encoded_as(&DynamicImage::new_rgb8(8, 8), image::ImageFormat::Png);
+
+        assert!(!black.semantically_eq(&larger));
+        assert!(!black.semantically_eq(&Modality::from_text("black")));
+    }
+
+    #[test]
+    fn image_dimensions_after_preprocessing() {
+        // Given a non square image
+        let image = DynamicImage::new_rgb8(600, 400);
+
+        // When it is turned into a modality
+        let modality = Modality::from_image(&image).unwrap();
+
+        // Then it has been cropped and resized to a square
+        assert_eq!(modality.image_dimensions(), Some((384, 384)));
+    }
+
+    #[test]
+    fn image_region_is_encoded() {
+        // Given an image with a red left half and a blue right half
+        let red = image::Rgb([255u8, 0, 0]);
+        let blue = image::Rgb([0u8, 0, 255]);
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(200, 100, |x, _| {
+            if x < 100 {
+                red
+            } else {
+                blue
+            }
+        }));
+
+        // When only the right half is used as modality
+        let modality =
+            Modality::from_image_region(&image, ImageRegion::new(100, 0, 100, 100)).unwrap();
+
+        // Then the encoded image is entirely blue
+        let Modality::Image { data } = modality else {
+            panic!("Expected an image modality")
+        };
+        let bytes = BASE64_STANDARD.decode(data.as_bytes()).unwrap();
+        let encoded = image::load_from_memory(&bytes).unwrap().to_rgb8();
+        assert!(encoded.pixels().all(|pixel| *pixel == blue));
+    }
+
+    #[test]
+    fn empty_image_region_is_rejected() {
+        let image = DynamicImage::new_rgb8(200, 100);
+
+        let result = Modality::from_image_region(&image, ImageRegion::new(10, 10, 0, 0));
+
+        assert!(matches!(result, Err(LoadImageError::InvalidRegion { .. })));
+    }
+
+    #[test]
+    fn image_region_out_of_bounds_is_rejected() {
+        let image = DynamicImage::new_rgb8(200, 100);
+
+        let exceeding = Modality::from_image_region(&image, ImageRegion::new(150, 0, 100, 100));
+        let overflowing =
+            Modality::from_image_region(&image, ImageRegion::new(u32::MAX, 0, 2, 100));
+
+        assert!(matches!(
+            exceeding,
+            Err(LoadImageError::InvalidRegion {
+                width: 200,
+                height: 100,
+                ..
+            })
+        ));
+        assert!(matches!(
+            overflowing,
+            Err(LoadImageError::InvalidRegion { .. })
+        ));
+    }
+
+    #[test]
+    fn jpeg_payload_of_photo_is_smaller() {
+        // Given an image with a lot of fine detail, like a photograph
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(512, 512, |x, y| {
+            let noise = (x.wrapping_mul(7919) ^ y.wrapping_mul(104_729)) % 64;
+            image::Rgb([(x / 2 + noise) as u8, (y / 2 + noise) as u8, noise as u8])
+        }));
+
+        // When it is encoded both ways
+        let png = Modality::from_image(&image).unwrap();
+        let jpeg = Modality::from_image_as_jpeg(&image, 80).unwrap();
+
+        // Then the JPEG is smaller, but seen by the model with the same dimensions
+        let size = |modality: &Modality| match modality {
+            Modality::Image { data } => data.len(),
+            Modality::Text { .. } => unreachable!(),
+        };
+        assert!(size(&jpeg) < size(&png));
+        assert_eq!(jpeg.image_dimensions(), Some((384, 384)));
+    }
+
+    #[test]
+    fn text_has_no_image_dimensions() {
+        assert_eq!(Modality::from_text("foo").image_dimensions(), None);
+    }
+
+    #[test]
+    fn mixed_prompt_survives_serde_round_trip() {
+        // Given a prompt with both an image and a text item
+        let prompt = Prompt::from_vec(vec![
+            Modality::Image {
+                data: "aGVsbG8=".into(),
+            },
+            Modality::from_text("A picture of "),
+        ]);
+
+        // When it is serialized and deserialized again
+        let json = serde_json::to_string(&prompt).unwrap();
+        let deserialized: Prompt = serde_json::from_str(&json).unwrap();
+
+        // Then it is unchanged and uses the tagged format of the API
+        assert_eq!(
+            json,
+            r#"[{"type":"image","data":"aGVsbG8="},{"type":"text","data":"A picture of "}]"#
+        );
+        assert_eq!(deserialized, prompt);
+    }
 }
@@ -0,0 +1,144 @@
+//! Renders chat conversations into a single prompt using the prompt template of a model. Templates
+//! are Jinja, as used for the `chat_template` in the `tokenizer_config.json` of Hugging Face
+//! models, and are rendered the same way: with `trim_blocks` and `lstrip_blocks` enabled and
+//! common Python string methods like `strip` available.
+//!
+//! The template is rendered with the following variables:
+//!
+//! * `messages`: The messages of the chat, each with a `role` and `content`.
+//! * `add_generation_prompt`: Always `true`, as the prompt is completed by the assistant.
+//! * `bos_token` and `eos_token`: Empty strings. The client does not know the special tokens of
+//!   the model, and the beginning of text is marked by the API.
+//! * `raise_exception`: Fails rendering with the given message.
+
+use minijinja::{context, Environment, ErrorKind, Value};
+
+use crate::Message;
+
+/// Renders `messages` into `template`. Returns a description of the problem if the template can
+/// not be parsed or rendering fails, e.g. because the template raises an exception.
+pub fn render(template: &str, messages: &[Message]) -> Result<String, String> {
+    environment()
+        .render_str(template, context(messages))
+        .map_err(|error| error.to_string())
+}
+
+/// System prompt the template falls back to if a chat does not start with a system message. It is
+/// read from the `default_system_message` variable, which templates like the one of
+/// `mistralai/Mistral-Small-24B-Instruct-2501` set at the top level. `None` if the template does
+/// not set it.
+pub fn default_system_prompt(template: &str) -> Result<Option<String>, String> {
+    let env = environment();
+    let template = env
+        .template_from_str(template)
+        .map_err(|error| error.to_string())?;
+    // Templates commonly inspect the first message, so evaluate them with a chat which has one.
+    let state = template
+        .eval_to_state(context(&[Message::user("")]))
+        .map_err(|error| error.to_string())?;
+    Ok(state
+        .lookup("default_system_message")
+        .and_then(|value| value.as_str().map(str::to_owned))
+        .filter(|prompt| !prompt.is_empty()))
+}
+
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+    env.add_function("raise_exception", |message: String| -> Result<(), _> {
+        Err(minijinja::Error::new(ErrorKind::InvalidOperation, message))
+    });
+    env
+}
+
+fn context(messages: &[Message]) -> Value {
+    context! {
+        messages,
+        add_generation_prompt => true,
+        bos_token => "",
+        eos_token => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `chat_template` of `meta-llama/Meta-Llama-3-8B-Instruct`.
+    const LLAMA_3_TEMPLATE: &str = "{% set loop_messages = messages %}{% for message in loop_messages %}{% set content = '<|start_header_id|>' + message['role'] + '<|end_header_id|>\n\n'+ message['content'] | trim + '<|eot_id|>' %}{% if loop.index0 == 0 %}{% set content = bos_token + content %}{% endif %}{{ content }}{% endfor %}{% if add_generation_prompt %}{{ '<|start_header_id|>assistant<|end_header_id|>\n\n' }}{% endif %}";
+
+    /// `chat_template` of `HuggingFaceH4/zephyr-7b-beta`.
+    const ZEPHYR_TEMPLATE: &str = "{% for message in messages %}\n{% if message['role'] == 'user' %}\n{{ '<|user|>\n' + message['content'] + eos_token }}\n{% elif message['role'] == 'system' %}\n{{ '<|system|>\n' + message['content'] + eos_token }}\n{% elif message['role'] == 'assistant' %}\n{{ '<|assistant|>\n'  + message['content'] + eos_token }}\n{% endif %}\n{% if loop.last and add_generation_prompt %}\n{{ '<|assistant|>' }}\n{% endif %}\n{% endfor %}";
+
+    #[test]
+    fn render_llama_3_template() {
+        let messages = [Message::system("Be brief."), Message::user(" Hello ")];
+
+        let rendered = render(LLAMA_3_TEMPLATE, &messages).unwrap();
+
+        assert_eq!(
+            rendered,
+            "<|start_header_id|>system<|end_header_id|>\n\nBe brief.<|eot_id|>\
+            <|start_header_id|>user<|end_header_id|>\n\nHello<|eot_id|>\
+            <|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+    }
+
+    #[test]
+    fn render_zephyr_template_with_block_whitespace_removed() {
+        let messages = [Message::system("Be brief."), Message::user("Hello")];
+
+        let rendered = render(ZEPHYR_TEMPLATE, &messages).unwrap();
+
+        assert_eq!(
+            rendered,
+            "<|system|>\nBe brief.\n<|user|>\nHello\n<|assistant|>\n"
+        );
+    }
+
+    #[test]
+    fn whitespace_control_and_python_methods_are_supported() {
+        let template = "{%- for message in messages -%}\n  {{- message.role.upper() }}: {{ message.content.strip() }}\n{%- endfor %}";
+        let messages = [Message::user(" Hello ")];
+
+        let rendered = render(template, &messages).unwrap();
+
+        assert_eq!(rendered, "USER: Hello");
+    }
+
+    #[test]
+    fn exception_raised_by_template_is_reported() {
+        let template = "{% if messages[0].role != 'system' %}{{ raise_exception('System message required') }}{% endif %}";
+
+        let error = render(template, &[Message::user("Hello")]).unwrap_err();
+
+        assert!(error.contains("System message required"), "{error}");
+    }
+
+    #[test]
+    fn default_system_message_is_read_from_template() {
+        let template = "{%- set default_system_message = \"Be brief.\" %}\n{%- if messages[0]['role'] == 'system' %}\n    {%- set system_message = messages[0]['content'] %}\n{%- else %}\n    {%- set system_message = default_system_message %}\n{%- endif %}\n{{- '[SYSTEM_PROMPT]' + system_message + '[/SYSTEM_PROMPT]' }}";
+
+        let prompt = default_system_prompt(template).unwrap();
+
+        assert_eq!(prompt.as_deref(), Some("Be brief."));
+    }
+
+    #[test]
+    fn template_without_default_system_message_has_none() {
+        let prompt = default_system_prompt(ZEPHYR_TEMPLATE).unwrap();
+
+        assert_eq!(prompt, None);
+    }
+
+    #[test]
+    fn syntax_error_is_reported() {
+        let template = "{% for message in messages %}{{ message.content }}";
+
+        let error = render(template, &[]).unwrap_err();
+
+        assert!(error.contains("syntax error"), "{error}");
+    }
+}
@@ -0,0 +1,91 @@
+use reqwest::header::HeaderMap;
+
+/// Rate limit state reported by a gateway in front of the API, via the `X-RateLimit-Limit`,
+/// `X-RateLimit-Remaining` and `X-RateLimit-Reset` headers. Lets you throttle yourself before
+/// running into [`crate::Error::TooManyRequests`]. Obtain the headers with
+/// [`crate::Client::output_of_with_headers`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RateLimit {
+    /// Number of requests left in the current window.
+    pub remaining: Option<u64>,
+    /// Number of requests allowed per window.
+    pub limit: Option<u64>,
+    /// When the window resets, as sent by the gateway. Usually the seconds until the reset, some
+    /// gateways send a unix timestamp instead.
+    pub reset: Option<u64>,
+}
+
+impl RateLimit {
+    /// Parses the rate limit headers. `None` if none of them is present. Headers with values which
+    /// are not a number are ignored.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let parse = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+        };
+        let rate_limit = RateLimit {
+            remaining: parse("x-ratelimit-remaining"),
+            limit: parse("x-ratelimit-limit"),
+            reset: parse("x-ratelimit-reset"),
+        };
+        let is_reported = rate_limit.remaining.is_some()
+            || rate_limit.limit.is_some()
+            || rate_limit.reset.is_some();
+        is_reported.then_some(rate_limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::{HeaderMap, HeaderValue};
+
+    use super::RateLimit;
+
+    #[test]
+    fn rate_limit_headers_are_parsed() {
+        // Given
+        let mut headers = HeaderMap::new();
+        headers.insert("X-RateLimit-Limit", HeaderValue::from_static("100"));
+        headers.insert("X-RateLimit-Remaining", HeaderValue::from_static("41"));
+        headers.insert("X-RateLimit-Reset", HeaderValue::from_static("30"));
+
+        // When
+        let rate_limit = RateLimit::from_headers(&headers);
+
+        // Then
+        assert_eq!(
+            rate_limit,
+            Some(RateLimit {
+                remaining: Some(41),
+                limit: Some(100),
+                reset: Some(30)
+            })
+        );
+    }
+
+    #[test]
+    fn partial_rate_limit_headers_are_parsed() {
+        // Given only the remaining requests and a malformed reset
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("0"));
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("soon"));
+
+        // When
+        let rate_limit = RateLimit::from_headers(&headers).unwrap();
+
+        // Then
+        assert_eq!(rate_limit.remaining, Some(0));
+        assert_eq!(rate_limit.limit, None);
+        assert_eq!(rate_limit.reset, None);
+    }
+
+    #[test]
+    fn no_rate_limit_without_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+
+        assert_eq!(RateLimit::from_headers(&headers), None);
+    }
+}
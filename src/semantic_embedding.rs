@@ -1,10 +1,11 @@
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::fmt::{self, Debug, Display};
+use tokenizers::Tokenizer;
 
-use crate::{http::Task, Job, Prompt};
+use crate::{http::Task, Error, Job, Prompt};
 
 /// Allows you to choose a semantic representation fitting for your usecase.
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum SemanticRepresentation {
     /// Useful for comparing prompts to each other, in use cases such as clustering, classification,
@@ -21,6 +22,23 @@ pub enum SemanticRepresentation {
     Query,
 }
 
+impl SemanticRepresentation {
+    /// Name of the representation as sent to the API, e.g. `"symmetric"`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SemanticRepresentation::Symmetric => "symmetric",
+            SemanticRepresentation::Document => "document",
+            SemanticRepresentation::Query => "query",
+        }
+    }
+}
+
+impl Display for SemanticRepresentation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 /// Create embeddings for prompts which can be used for downstream tasks. E.g. search, classifiers
 #[derive(Serialize, Debug)]
 pub struct TaskSemanticEmbedding<'a> {
@@ -59,6 +77,43 @@ pub struct SemanticEmbeddingOutput {
     pub embedding: Vec<f32>,
 }
 
+impl SemanticEmbeddingOutput {
+    /// Number of dimensions of the embedding.
+    pub fn dimensions(&self) -> usize {
+        self.embedding.len()
+    }
+
+    /// Converts the embedding to half precision floats. This halves the memory required to store
+    /// it, e.g. in a vector database, at a modest loss of accuracy.
+    #[cfg(feature = "half")]
+    pub fn to_f16(&self) -> Vec<half::f16> {
+        self.embedding
+            .iter()
+            .copied()
+            .map(half::f16::from_f32)
+            .collect()
+    }
+
+    /// Copies the embedding into a one dimensional array, e.g. to use it in matrix operations.
+    #[cfg(feature = "ndarray")]
+    pub fn to_array1(&self) -> ndarray::Array1<f32> {
+        ndarray::Array1::from_vec(self.embedding.clone())
+    }
+}
+
+/// Checks that the embedding has been compressed to the requested size, if any.
+fn check_dimensions(compress_to_size: Option<u32>, embedding: &[f32]) -> Result<(), Error> {
+    match compress_to_size {
+        Some(expected) if expected as usize != embedding.len() => {
+            Err(Error::UnexpectedEmbeddingSize {
+                expected: expected as usize,
+                actual: embedding.len(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
 impl Task for TaskSemanticEmbedding<'_> {
     type Output = SemanticEmbeddingOutput;
     type ResponseBody = SemanticEmbeddingOutput;
@@ -76,8 +131,9 @@ impl Task for TaskSemanticEmbedding<'_> {
         client.post(format!("{base}/semantic_embed")).json(&body)
     }
 
-    fn body_to_output(&self, response: Self::ResponseBody) -> Self::Output {
-        response
+    fn body_to_output(&self, response: Self::ResponseBody) -> Result<Self::Output, Error> {
+        check_dimensions(self.compress_to_size, &response.embedding)?;
+        Ok(response)
     }
 }
 
@@ -94,8 +150,80 @@ impl Job for TaskSemanticEmbedding<'_> {
         client.post(format!("{base}/semantic_embed")).json(&body)
     }
 
-    fn body_to_output(&self, response: Self::ResponseBody) -> Self::Output {
-        response
+    fn body_to_output(&self, response: Self::ResponseBody) -> Result<Self::Output, Error> {
+        check_dimensions(self.compress_to_size, &response.embedding)?;
+        Ok(response)
+    }
+}
+
+/// Create an embedding for a prompt, steered by a natural language instruction describing its use
+/// case. Requires a model supporting instructable embeddings.
+#[derive(Serialize, Debug)]
+pub struct TaskSemanticEmbeddingWithInstruction<'a> {
+    /// Describes what the embedding is used for, e.g. "Represent the question to retrieve
+    /// documents answering it". See [`Self::with_representation`] for presets.
+    pub instruction: &'a str,
+    /// The prompt (usually text) to be embedded.
+    pub input: Prompt<'a>,
+    /// Whether to normalize the embedding to unit length. Normalized embeddings can be compared
+    /// with a plain dot product instead of the cosine similarity. Defaults to `false` on the server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normalize: Option<bool>,
+}
+
+impl<'a> TaskSemanticEmbeddingWithInstruction<'a> {
+    /// Embeds the input using an instruction preset fitting the semantic representation, analogous
+    /// to [`TaskSemanticEmbedding::representation`].
+    pub fn with_representation(representation: SemanticRepresentation, input: Prompt<'a>) -> Self {
+        let instruction = match representation {
+            SemanticRepresentation::Symmetric => {
+                "Represent the text to find other texts with a similar meaning"
+            }
+            SemanticRepresentation::Document => {
+                "Represent the document to be retrieved by search queries"
+            }
+            SemanticRepresentation::Query => {
+                "Represent the question to retrieve documents answering it"
+            }
+        };
+        Self {
+            instruction,
+            input,
+            normalize: None,
+        }
+    }
+
+    /// The most common setup for similarity search and clustering: a symmetric instruction preset
+    /// and a normalized embedding.
+    pub fn for_similarity(input: Prompt<'a>) -> Self {
+        Self {
+            normalize: Some(true),
+            ..Self::with_representation(SemanticRepresentation::Symmetric, input)
+        }
+    }
+}
+
+impl Task for TaskSemanticEmbeddingWithInstruction<'_> {
+    type Output = SemanticEmbeddingOutput;
+    type ResponseBody = SemanticEmbeddingOutput;
+
+    fn build_request(
+        &self,
+        client: &reqwest::Client,
+        base: &str,
+        model: &str,
+    ) -> reqwest::RequestBuilder {
+        let body = RequestBody {
+            model,
+            semantic_embedding_task: self,
+        };
+        client
+            .post(format!("{base}/instructable_embed"))
+            .json(&body)
+    }
+
+    fn body_to_output(&self, response: Self::ResponseBody) -> Result<Self::Output, Error> {
+        Ok(response)
     }
 }
 
@@ -120,12 +248,60 @@ pub struct TaskBatchSemanticEmbedding<'a> {
     pub compress_to_size: Option<u32>,
 }
 
+impl TaskBatchSemanticEmbedding<'_> {
+    /// Number of prompts sent per request by [`crate::Client::batch_semantic_embedding`]. Matches
+    /// the maximum number of prompts the API accepts in one request.
+    pub const DEFAULT_CHUNK_SIZE: usize = 100;
+
+    /// Splits the batch into smaller batches of at most `chunk_size` prompts each. The prompts are
+    /// borrowed, so no copy of the (potentially large) prompts is made.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    pub fn chunks(
+        &self,
+        chunk_size: usize,
+    ) -> impl Iterator<Item = TaskBatchSemanticEmbedding<'_>> + '_ {
+        self.prompts
+            .chunks(chunk_size)
+            .map(|prompts| TaskBatchSemanticEmbedding {
+                prompts: prompts.iter().map(Prompt::borrow).collect(),
+                representation: self.representation,
+                compress_to_size: self.compress_to_size,
+            })
+    }
+}
+
 /// Heap allocated vec of embeddings. Can hold full embeddings or compressed ones
 #[derive(Deserialize)]
 pub struct BatchSemanticEmbeddingOutput {
     pub embeddings: Vec<Vec<f32>>,
 }
 
+impl BatchSemanticEmbeddingOutput {
+    /// Copies the embeddings into a matrix with one row per prompt, e.g. to compute the
+    /// similarities of all of them at once. Fails if the embeddings differ in size.
+    #[cfg(feature = "ndarray")]
+    pub fn to_array2(&self) -> Result<ndarray::Array2<f32>, ndarray::ShapeError> {
+        let dimensions = self.embeddings.first().map_or(0, Vec::len);
+        ndarray::Array2::from_shape_vec(
+            (self.embeddings.len(), dimensions),
+            self.embeddings.concat(),
+        )
+    }
+}
+
+/// Embeddings for a contiguous part of the prompts of a [`TaskBatchSemanticEmbedding`]. Returned
+/// by [`crate::Client::stream_batch_semantic_embedding`].
+#[derive(Debug, PartialEq)]
+pub struct BatchSemanticEmbeddingChunk {
+    /// Index of the prompt in the original batch the first embedding belongs to.
+    pub offset: usize,
+    /// One embedding for each prompt of the chunk, in the order of the original batch.
+    pub embeddings: Vec<Vec<f32>>,
+}
+
 impl Job for TaskBatchSemanticEmbedding<'_> {
     type Output = BatchSemanticEmbeddingOutput;
     type ResponseBody = BatchSemanticEmbeddingOutput;
@@ -141,7 +317,236 @@ impl Job for TaskBatchSemanticEmbedding<'_> {
             .json(&body)
     }
 
-    fn body_to_output(&self, response: Self::ResponseBody) -> Self::Output {
-        response
+    fn body_to_output(&self, response: Self::ResponseBody) -> Result<Self::Output, Error> {
+        for embedding in &response.embeddings {
+            check_dimensions(self.compress_to_size, embedding)?;
+        }
+        Ok(response)
+    }
+}
+
+/// Splits a long text into chunks of at most `chunk_tokens` tokens, so each of them can be embedded
+/// separately, e.g. using [`crate::Client::batch_semantic_embedding`]. Consecutive chunks share
+/// `overlap` tokens, so context at the chunk boundaries is not lost. Chunks are slices of the
+/// original text, so whitespace and formatting are preserved.
+///
+/// Use [`crate::Client::tokenizer_by_model`] to obtain the tokenizer of the embedding model.
+///
+/// # Panics
+///
+/// Panics if `overlap` is not smaller than `chunk_tokens`.
+pub fn chunk_text_by_tokens(
+    tokenizer: &Tokenizer,
+    text: &str,
+    chunk_tokens: usize,
+    overlap: usize,
+) -> Result<Vec<String>, tokenizers::Error> {
+    assert!(
+        overlap < chunk_tokens,
+        "overlap must be smaller than chunk_tokens"
+    );
+    let encoding = tokenizer.encode(text, false)?;
+    let offsets = encoding.get_offsets();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < offsets.len() {
+        let end = (start + chunk_tokens).min(offsets.len());
+        chunks.push(text[offsets[start].0..offsets[end - 1].1].to_owned());
+        if end == offsets.len() {
+            break;
+        }
+        start = end - overlap;
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokenizers::{models::wordlevel::WordLevel, pre_tokenizers::whitespace::Whitespace};
+
+    use super::*;
+
+    #[test]
+    fn representation_names_match_serialization() {
+        for (representation, name) in [
+            (SemanticRepresentation::Symmetric, "symmetric"),
+            (SemanticRepresentation::Document, "document"),
+            (SemanticRepresentation::Query, "query"),
+        ] {
+            assert_eq!(representation.as_str(), name);
+            assert_eq!(representation.to_string(), name);
+            assert_eq!(serde_json::to_value(representation).unwrap(), name);
+        }
+    }
+
+    /// Tokenizer treating every word as one token
+    fn word_tokenizer(text: &str) -> Tokenizer {
+        let vocab = text
+            .split_whitespace()
+            .chain(["[UNK]"])
+            .enumerate()
+            .map(|(id, word)| (word.to_owned(), id as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_owned())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        tokenizer
+    }
+
+    #[test]
+    fn chunks_do_not_exceed_chunk_size() {
+        let text = "one two three four five";
+        let tokenizer = word_tokenizer(text);
+
+        let chunks = chunk_text_by_tokens(&tokenizer, text, 2, 0).unwrap();
+
+        assert_eq!(chunks, ["one two", "three four", "five"]);
+    }
+
+    #[test]
+    fn consecutive_chunks_overlap() {
+        let text = "one two three four five";
+        let tokenizer = word_tokenizer(text);
+
+        let chunks = chunk_text_by_tokens(&tokenizer, text, 3, 1).unwrap();
+
+        assert_eq!(chunks, ["one two three", "three four five"]);
+    }
+
+    #[test]
+    fn chunks_preserve_original_whitespace() {
+        let text = "one  two\nthree";
+        let tokenizer = word_tokenizer(text);
+
+        let chunks = chunk_text_by_tokens(&tokenizer, text, 2, 0).unwrap();
+
+        assert_eq!(chunks, ["one  two", "three"]);
+    }
+
+    #[test]
+    fn empty_text_has_no_chunks() {
+        let tokenizer = word_tokenizer("one");
+
+        let chunks = chunk_text_by_tokens(&tokenizer, "", 2, 0).unwrap();
+
+        assert!(chunks.is_empty());
+    }
+
+    #[test]
+    fn embedding_for_similarity_is_normalized() {
+        // When
+        let task = TaskSemanticEmbeddingWithInstruction::for_similarity(Prompt::from_text("Pizza"));
+
+        // Then
+        assert_eq!(task.normalize, Some(true));
+        let body = serde_json::to_value(RequestBody {
+            model: "pharia-1-embedding-4608-control",
+            semantic_embedding_task: &task,
+        })
+        .unwrap();
+        assert_eq!(body["normalize"], true);
+        assert_eq!(body["input"][0]["data"], "Pizza");
+    }
+}
+
+#[cfg(all(test, feature = "half"))]
+mod half_tests {
+    use half::f16;
+
+    use crate::cosine_similarity;
+
+    use super::SemanticEmbeddingOutput;
+
+    #[test]
+    fn half_precision_round_trip() {
+        // Given
+        let output = SemanticEmbeddingOutput {
+            embedding: vec![0.0, 1.0, -0.5, 0.123_456, 1024.0],
+        };
+
+        // When
+        let embedding = output.to_f16();
+
+        // Then
+        let restored: Vec<f32> = embedding.iter().copied().map(f16::to_f32).collect();
+        for (original, restored) in output.embedding.iter().zip(&restored) {
+            assert!((original - restored).abs() <= original.abs() * 1e-3);
+        }
+    }
+
+    #[test]
+    fn cosine_similarity_of_half_precision_embeddings() {
+        // Given
+        let a = SemanticEmbeddingOutput {
+            embedding: vec![0.1, 0.7, -0.3, 0.25],
+        };
+        let b = SemanticEmbeddingOutput {
+            embedding: vec![0.2, 0.6, -0.1, -0.4],
+        };
+
+        // When
+        let to_f32 =
+            |embedding: Vec<f16>| embedding.into_iter().map(f16::to_f32).collect::<Vec<_>>();
+        let similarity = cosine_similarity(&to_f32(a.to_f16()), &to_f32(b.to_f16()));
+
+        // Then
+        let expected = cosine_similarity(&a.embedding, &b.embedding);
+        assert!((similarity - expected).abs() < 1e-3);
+    }
+}
+
+#[cfg(all(test, feature = "ndarray"))]
+mod ndarray_tests {
+    use super::{BatchSemanticEmbeddingOutput, SemanticEmbeddingOutput};
+
+    #[test]
+    fn embedding_as_array1() {
+        // Given
+        let output = SemanticEmbeddingOutput {
+            embedding: vec![0.1, 0.7, -0.3, 0.25],
+        };
+
+        // When
+        let array = output.to_array1();
+
+        // Then
+        assert_eq!(array.shape(), [4]);
+        assert_eq!(array[2], -0.3);
+    }
+
+    #[test]
+    fn batch_of_embeddings_as_array2() {
+        // Given three embeddings with two dimensions each
+        let output = BatchSemanticEmbeddingOutput {
+            embeddings: vec![vec![1.0, 2.0], vec![3.0, 4.0], vec![5.0, 6.0]],
+        };
+
+        // When
+        let matrix = output.to_array2().unwrap();
+
+        // Then there is one row per embedding
+        assert_eq!(matrix.shape(), [3, 2]);
+        assert_eq!(matrix[[1, 0]], 3.0);
+        assert_eq!(matrix.row(2).to_vec(), [5.0, 6.0]);
+    }
+
+    #[test]
+    fn embeddings_of_different_sizes_are_no_matrix() {
+        let output = BatchSemanticEmbeddingOutput {
+            embeddings: vec![vec![1.0, 2.0], vec![3.0]],
+        };
+
+        assert!(output.to_array2().is_err());
+    }
+
+    #[test]
+    fn empty_batch_is_empty_matrix() {
+        let output = BatchSemanticEmbeddingOutput { embeddings: vec![] };
+
+        assert_eq!(output.to_array2().unwrap().shape(), [0, 0]);
     }
 }
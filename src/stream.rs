@@ -1,7 +1,7 @@
 use reqwest::RequestBuilder;
 use serde::Deserialize;
 
-use crate::http::MethodJob;
+use crate::{http::MethodJob, Error};
 
 /// A job send to the Aleph Alpha Api using the http client. A job wraps all the knowledge required
 /// for the Aleph Alpha API to specify its result. Notably it includes the model(s) the job is
@@ -23,6 +23,12 @@ pub trait StreamJob {
 
     /// Parses the response of the server into higher level structs for the user.
     fn body_to_output(response: Self::ResponseBody) -> Self::Output;
+
+    /// Checks the job for settings the API would reject, before it is sent. Defaults to accepting
+    /// every job.
+    fn validate(&self) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 /// A task send to the Aleph Alpha Api using the http client. Requires to specify a model before it
@@ -41,6 +47,12 @@ pub trait StreamTask {
     /// Parses the response of the server into higher level structs for the user.
     fn body_to_output(response: Self::ResponseBody) -> Self::Output;
 
+    /// Checks the task for settings the API would reject, before it is sent. Defaults to accepting
+    /// every task.
+    fn validate(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
     /// Turn your task into [`Job`] by annotating it with a model name.
     fn with_model<'a>(&'a self, model: &'a str) -> MethodJob<'a, Self>
     where
@@ -65,4 +77,8 @@ where
     fn body_to_output(response: T::ResponseBody) -> T::Output {
         T::body_to_output(response)
     }
+
+    fn validate(&self) -> Result<(), Error> {
+        self.task.validate()
+    }
 }
@@ -1,5 +1,8 @@
-use crate::Task;
+use std::collections::HashMap;
+
+use crate::{Error, Task};
 use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
 
 /// Input for a [crate::Client::tokenize] request.
 pub struct TaskTokenization<'a> {
@@ -14,7 +17,7 @@ pub struct TaskTokenization<'a> {
 }
 
 impl<'a> From<&'a str> for TaskTokenization<'a> {
-    fn from(prompt: &str) -> TaskTokenization {
+    fn from(prompt: &'a str) -> TaskTokenization<'a> {
         TaskTokenization {
             prompt,
             tokens: true,
@@ -24,7 +27,7 @@ impl<'a> From<&'a str> for TaskTokenization<'a> {
 }
 
 impl TaskTokenization<'_> {
-    pub fn new(prompt: &str, tokens: bool, token_ids: bool) -> TaskTokenization {
+    pub fn new(prompt: &str, tokens: bool, token_ids: bool) -> TaskTokenization<'_> {
         TaskTokenization {
             prompt,
             tokens,
@@ -85,7 +88,183 @@ impl Task for TaskTokenization<'_> {
         client.post(format!("{base}/tokenize")).json(&body)
     }
 
-    fn body_to_output(&self, response: Self::ResponseBody) -> Self::Output {
-        TokenizationOutput::from(response)
+    fn body_to_output(&self, response: Self::ResponseBody) -> Result<Self::Output, Error> {
+        Ok(TokenizationOutput::from(response))
+    }
+}
+
+/// A token together with its position in the tokenized text. See [`token_spans`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenSpan {
+    /// Id of the token in the vocabulary of the tokenizer.
+    pub token_id: u32,
+    /// Text representation of the token, as known by the tokenizer.
+    pub token: String,
+    /// Byte offset of the first byte of the token in the text.
+    pub start: usize,
+    /// Byte offset just after the last byte of the token in the text.
+    pub end: usize,
+}
+
+/// Tokenizes the text locally and reports for each token the range of bytes it covers in the text,
+/// e.g. to highlight the tokens in a UI. `&text[span.start..span.end]` is the part of the text
+/// the token originates from.
+pub fn token_spans(tokenizer: &Tokenizer, text: &str) -> Result<Vec<TokenSpan>, tokenizers::Error> {
+    let encoding = tokenizer.encode(text, false)?;
+    let spans = encoding
+        .get_ids()
+        .iter()
+        .zip(encoding.get_tokens())
+        .zip(encoding.get_offsets())
+        .map(|((&token_id, token), &(start, end))| TokenSpan {
+            token_id,
+            token: token.clone(),
+            start,
+            end,
+        })
+        .collect();
+    Ok(spans)
+}
+
+/// The special tokens of a tokenizer, e.g. the begin and end of text markers, ordered by id.
+/// Returned by [`crate::Client::special_tokens`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpecialTokens {
+    pub tokens: Vec<SpecialToken>,
+}
+
+/// A token with a special meaning for the model, which is never produced by tokenizing regular
+/// text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpecialToken {
+    /// Id of the token in the vocabulary of the tokenizer.
+    pub id: u32,
+    /// Text of the token, e.g. `<|endoftext|>`.
+    pub content: String,
+}
+
+impl SpecialTokens {
+    /// Extracts the special tokens from the added tokens of a tokenizer.
+    pub fn from_tokenizer(tokenizer: &Tokenizer) -> Self {
+        let mut tokens: Vec<_> = tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(_, token)| token.special)
+            .map(|(id, token)| SpecialToken {
+                id,
+                content: token.content,
+            })
+            .collect();
+        tokens.sort_by_key(|token| token.id);
+        Self { tokens }
+    }
+
+    /// Id of the special token with the given text, e.g. `<|endoftext|>`.
+    pub fn id(&self, content: &str) -> Option<u32> {
+        self.tokens
+            .iter()
+            .find(|token| token.content == content)
+            .map(|token| token.id)
+    }
+}
+
+/// Maps words to a [`crate::Sampling::logit_bias`]. Each word is tokenized locally and every one
+/// of its tokens receives the bias of the word. If words share a token, the bias of the later word
+/// wins.
+pub fn token_bias(
+    tokenizer: &Tokenizer,
+    words: &[(&str, f64)],
+) -> Result<HashMap<u32, f64>, tokenizers::Error> {
+    let mut bias = HashMap::new();
+    for &(word, word_bias) in words {
+        let encoding = tokenizer.encode(word, false)?;
+        bias.extend(encoding.get_ids().iter().map(|&id| (id, word_bias)));
+    }
+    Ok(bias)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokenizers::{
+        models::wordlevel::WordLevel, pre_tokenizers::whitespace::Whitespace, AddedToken,
+    };
+
+    use super::*;
+
+    /// Treats every word of the vocabulary as one token, unknown words become `[UNK]`.
+    fn word_level_tokenizer(words: &[&str]) -> Tokenizer {
+        let vocab = words
+            .iter()
+            .chain(&["[UNK]"])
+            .enumerate()
+            .map(|(id, &word)| (word.to_owned(), id as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_owned())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        tokenizer
+    }
+
+    #[test]
+    fn byte_offsets_of_multibyte_text() {
+        // Given a tokenizer treating every word as one token
+        let tokenizer = word_level_tokenizer(&["Grüße", "aus", "Köln"]);
+        let text = "Grüße aus Köln";
+
+        // When
+        let spans = token_spans(&tokenizer, text).unwrap();
+
+        // Then
+        let ranges: Vec<_> = spans.iter().map(|span| (span.start, span.end)).collect();
+        assert_eq!(ranges, [(0, 7), (8, 11), (12, 17)]);
+        assert_eq!(&text[spans[2].start..spans[2].end], "Köln");
+        assert_eq!(spans[2].token_id, 2);
+    }
+
+    #[test]
+    fn word_is_resolved_to_its_bias_entry() {
+        // Given
+        let tokenizer = word_level_tokenizer(&["no", "yes", "maybe"]);
+
+        // When
+        let bias = token_bias(&tokenizer, &[("yes", 3.0), ("maybe", -1.5)]).unwrap();
+
+        // Then
+        assert_eq!(bias, [(1, 3.0), (2, -1.5)].into());
+    }
+
+    #[test]
+    fn special_tokens_of_tokenizer() {
+        // Given a tokenizer with special tokens and a regular added token
+        let mut tokenizer = word_level_tokenizer(&["Hello", "world"]);
+        tokenizer.add_special_tokens(&[
+            AddedToken::from("<|begin_of_text|>", true),
+            AddedToken::from("<|endoftext|>", true),
+        ]);
+        tokenizer.add_tokens(&[AddedToken::from("Köln", false)]);
+
+        // When
+        let special_tokens = SpecialTokens::from_tokenizer(&tokenizer);
+
+        // Then
+        assert_eq!(
+            special_tokens.tokens,
+            [
+                SpecialToken {
+                    id: 3,
+                    content: "<|begin_of_text|>".to_owned()
+                },
+                SpecialToken {
+                    id: 4,
+                    content: "<|endoftext|>".to_owned()
+                }
+            ]
+        );
+        assert_eq!(special_tokens.id("<|endoftext|>"), Some(4));
+        assert_eq!(special_tokens.id("Köln"), None);
     }
 }
@@ -0,0 +1,141 @@
+/// W3C trace context (<https://www.w3.org/TR/trace-context/>) propagated to the API with
+/// [`crate::How::trace_context`]. Lets the requests of this client show up as part of your
+/// distributed traces.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    /// Identifies the whole trace. Must not be zero.
+    pub trace_id: u128,
+    /// Identifies the span in which the request is made. Must not be zero.
+    pub span_id: u64,
+    /// Whether the caller records the trace.
+    pub sampled: bool,
+    /// Vendor specific trace state, sent as `tracestate` header.
+    pub state: Option<String>,
+}
+
+impl TraceContext {
+    /// Context of an existing trace, e.g. the one of the request your service is handling.
+    pub fn new(trace_id: u128, span_id: u64, sampled: bool) -> Self {
+        Self {
+            trace_id,
+            span_id,
+            sampled,
+            state: None,
+        }
+    }
+
+    /// Context of a new sampled trace with random ids. Useful if there is no trace to join yet.
+    pub fn new_random() -> Self {
+        // Zero ids are invalid. Chances to hit them are negligible, but we do not want to rely on
+        // luck.
+        Self::new(
+            rand::random::<u128>().max(1),
+            rand::random::<u64>().max(1),
+            true,
+        )
+    }
+
+    /// Attaches vendor specific trace state. See [`Self::state`].
+    pub fn with_state(mut self, state: impl Into<String>) -> Self {
+        self.state = Some(state.into());
+        self
+    }
+
+    /// The key value pairs of the [`Self::state`], in the order they appear, e.g. to read the state
+    /// of an upstream vendor. Whitespace around the entries is ignored, as are empty and malformed
+    /// entries. Empty if there is no state.
+    pub fn tracestate_entries(&self) -> Vec<(String, String)> {
+        let Some(state) = &self.state else {
+            return Vec::new();
+        };
+        state
+            .split(',')
+            .filter_map(|entry| {
+                let (key, value) = entry.trim_matches([' ', '\t']).split_once('=')?;
+                Some((key.to_owned(), value.to_owned()))
+            })
+            .collect()
+    }
+
+    /// Value of the `traceparent` header, e.g.
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    pub fn traceparent(&self) -> String {
+        let flags = u8::from(self.sampled);
+        format!(
+            "00-{:032x}-{:016x}-{flags:02x}",
+            self.trace_id, self.span_id
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TraceContext;
+
+    #[test]
+    fn traceparent_is_formatted_as_specified() {
+        let context = TraceContext::new(0x4bf92f3577b34da6a3ce929d0e0e4736, 0xf067aa0ba902b7, true);
+
+        assert_eq!(
+            context.traceparent(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+    }
+
+    #[test]
+    fn random_trace_is_well_formed() {
+        // When
+        let traceparent = TraceContext::new_random().traceparent();
+
+        // Then
+        let parts: Vec<_> = traceparent.split('-').collect();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], "00");
+        assert_eq!(parts[1].len(), 32);
+        assert_eq!(parts[2].len(), 16);
+        assert_eq!(parts[3], "01");
+        let is_lower_hex = |part: &str| part.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'));
+        assert!(parts.iter().all(|part| is_lower_hex(part)));
+        assert_ne!(parts[1], "0".repeat(32));
+        assert_ne!(parts[2], "0".repeat(16));
+    }
+
+    #[test]
+    fn no_tracestate_entries_without_state() {
+        let context = TraceContext::new(1, 1, true);
+
+        assert!(context.tracestate_entries().is_empty());
+        assert!(context.with_state("").tracestate_entries().is_empty());
+    }
+
+    #[test]
+    fn single_tracestate_entry() {
+        let context = TraceContext::new(1, 1, true).with_state("congo=t61rcWkgMzE");
+
+        assert_eq!(
+            context.tracestate_entries(),
+            [("congo".to_owned(), "t61rcWkgMzE".to_owned())]
+        );
+    }
+
+    #[test]
+    fn multiple_tracestate_entries_with_whitespace() {
+        // Given a state with optional whitespace and an empty entry, as allowed by the spec
+        let context = TraceContext::new(1, 1, true)
+            .with_state("rojo=00f067aa0ba902b7 ,\tcongo=t61rcWkgMzE,, ");
+
+        // Then
+        assert_eq!(
+            context.tracestate_entries(),
+            [
+                ("rojo".to_owned(), "00f067aa0ba902b7".to_owned()),
+                ("congo".to_owned(), "t61rcWkgMzE".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn random_traces_differ() {
+        assert_ne!(TraceContext::new_random(), TraceContext::new_random());
+    }
+}
@@ -0,0 +1,33 @@
+//! Tests of [`Client::from_env`] and [`Client::try_from_env`]. Kept in their own test binary, as
+//! they change the environment of the process, which must not race with other tests.
+
+use aleph_alpha_client::{Client, Error};
+
+#[test]
+fn only_the_token_is_required_with_a_compiled_in_url() {
+    // Given only the token is set
+    std::env::set_var("PHARIA_AI_TOKEN", "dummy-token");
+    std::env::remove_var("INFERENCE_URL");
+
+    // When
+    let lenient = Client::from_env();
+    let strict = Client::try_from_env();
+
+    // Then the lenient constructor falls back to the URL set at compile time, if any
+    assert_eq!(lenient.is_ok(), option_env!("INFERENCE_URL").is_some());
+    if let Err(error) = lenient {
+        assert!(matches!(
+            error,
+            Error::MissingEnvVar {
+                name: "INFERENCE_URL"
+            }
+        ));
+    }
+    // And the strict one requires the URL at runtime
+    assert!(matches!(
+        strict,
+        Err(Error::MissingEnvVar {
+            name: "INFERENCE_URL"
+        })
+    ));
+}
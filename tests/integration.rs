@@ -2,9 +2,10 @@ use std::{fs::File, io::BufReader};
 
 use aleph_alpha_client::{
     cosine_similarity, ChatSampling, Client, CompletionEvent, Granularity, How, ImageScore,
-    ItemExplanation, Logprobs, Message, Modality, Prompt, PromptGranularity, Sampling,
-    SemanticRepresentation, Stopping, Task, TaskBatchSemanticEmbedding, TaskChat, TaskCompletion,
-    TaskDetokenization, TaskExplanation, TaskSemanticEmbedding, TaskTokenization, TextScore,
+    ItemExplanation, Logprobs, MaxTokensField, Message, Modality, Prompt, PromptGranularity,
+    Sampling, SemanticRepresentation, Stopping, Task, TaskBatchSemanticEmbedding, TaskChat,
+    TaskCompletion, TaskDetokenization, TaskExplanation, TaskSemanticEmbedding, TaskTokenization,
+    TextScore,
 };
 use dotenvy::dotenv;
 use futures_util::StreamExt;
@@ -223,6 +224,9 @@ async fn complete_structured_prompt() {
         sampling: Sampling::MOST_LIKELY,
         special_tokens: false,
         logprobs: Logprobs::No,
+        token_ids: false,
+        echo: false,
+        disable_optimizations: false,
     };
     let model = "luminous-base";
     let client = Client::with_auth(inference_url(), pharia_ai_token()).unwrap();
@@ -254,6 +258,9 @@ async fn maximum_tokens_none_request() {
         sampling: Sampling::MOST_LIKELY,
         special_tokens: false,
         logprobs: Logprobs::No,
+        token_ids: false,
+        echo: false,
+        disable_optimizations: false,
     };
     let model = "luminous-base";
     let client = Client::with_auth(inference_url(), pharia_ai_token()).unwrap();
@@ -293,8 +300,11 @@ async fn explain_request() {
         .unwrap();
 
     // Then
-    assert_eq!(response.items.len(), 2); // 1 text + 1 target
-    assert_eq!(text_scores(&response.items[0]).len(), num_input_sentences)
+    assert_eq!(response.explanations[0].items.len(), 2); // 1 text + 1 target
+    assert_eq!(
+        text_scores(&response.explanations[0].items[0]).len(),
+        num_input_sentences
+    )
 }
 
 #[tokio::test]
@@ -323,7 +333,10 @@ async fn explain_request_with_auto_granularity() {
         .unwrap();
 
     // Then
-    assert_eq!(text_scores(&response.items[0]).len(), num_input_tokens)
+    assert_eq!(
+        text_scores(&response.explanations[0].items[0]).len(),
+        num_input_tokens
+    )
 }
 
 #[tokio::test]
@@ -355,7 +368,10 @@ async fn explain_request_with_image_modality() {
         .unwrap();
 
     // Then
-    assert_eq!(image_scores(&response.items[0]).len(), num_input_images)
+    assert_eq!(
+        image_scores(&response.explanations[0].items[0]).len(),
+        num_input_images
+    )
 }
 
 fn text_scores(item: &ItemExplanation) -> Vec<TextScore> {
@@ -389,6 +405,9 @@ async fn describe_image_starting_from_a_path() {
         sampling: Sampling::MOST_LIKELY,
         special_tokens: false,
         logprobs: Logprobs::No,
+        token_ids: false,
+        echo: false,
+        disable_optimizations: false,
     };
     let model = "luminous-base";
     let client = Client::with_auth(inference_url(), pharia_ai_token()).unwrap();
@@ -420,6 +439,9 @@ async fn describe_image_starting_from_a_dyn_image() {
         sampling: Sampling::MOST_LIKELY,
         special_tokens: false,
         logprobs: Logprobs::No,
+        token_ids: false,
+        echo: false,
+        disable_optimizations: false,
     };
     let model = "luminous-base";
     let client = Client::with_auth(inference_url(), pharia_ai_token()).unwrap();
@@ -612,6 +634,10 @@ async fn frequency_penalty_request() {
         stopping,
         sampling,
         logprobs: Logprobs::No,
+        response_format: None,
+        validate_response: false,
+        user_id: None,
+        max_tokens_field: MaxTokensField::MaxTokens,
     };
 
     // When the response is requested
@@ -648,6 +674,10 @@ async fn presence_penalty_request() {
         stopping,
         sampling,
         logprobs: Logprobs::No,
+        response_format: None,
+        validate_response: false,
+        user_id: None,
+        max_tokens_field: MaxTokensField::MaxTokens,
     };
 
     // When the response is requested
@@ -684,6 +714,10 @@ async fn stop_sequences_request() {
         stopping,
         sampling: ChatSampling::MOST_LIKELY,
         logprobs: Logprobs::No,
+        response_format: None,
+        validate_response: false,
+        user_id: None,
+        max_tokens_field: MaxTokensField::MaxTokens,
     };
 
     // When the response is requested
@@ -710,6 +744,10 @@ async fn show_logprobs_sampled_chat() {
         stopping: Stopping::from_maximum_tokens(2),
         sampling: ChatSampling::MOST_LIKELY,
         logprobs: Logprobs::Sampled,
+        response_format: None,
+        validate_response: false,
+        user_id: None,
+        max_tokens_field: MaxTokensField::MaxTokens,
     };
 
     let response = client.chat(&task, model, &How::default()).await.unwrap();
@@ -736,6 +774,10 @@ async fn show_top_logprobs_chat() {
         stopping: Stopping::from_maximum_tokens(1),
         sampling: ChatSampling::MOST_LIKELY,
         logprobs: Logprobs::Top(2),
+        response_format: None,
+        validate_response: false,
+        user_id: None,
+        max_tokens_field: MaxTokensField::MaxTokens,
     };
 
     let response = client.chat(&task, model, &How::default()).await.unwrap();
@@ -825,6 +867,10 @@ async fn show_token_usage_chat() {
         stopping: Stopping::from_maximum_tokens(3),
         sampling: ChatSampling::MOST_LIKELY,
         logprobs: Logprobs::No,
+        response_format: None,
+        validate_response: false,
+        user_id: None,
+        max_tokens_field: MaxTokensField::MaxTokens,
     };
 
     // When
@@ -1,10 +1,32 @@
-use std::time::Duration;
+use std::{
+    future::Future,
+    io::Read,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-use aleph_alpha_client::{Client, Error, How, Task, TaskCompletion};
+use aleph_alpha_client::{
+    AuthScheme, Client, ClientTimeout, CompletionEvent, CompletionOutput, DetokenizationOutput,
+    Error, Granularity, How, HowOverrides, ItemExplanation, JsonSchema, Message, Modality,
+    Priority, Prompt, ResponseFormat, Sampling, SemanticRepresentation, Task,
+    TaskBatchDetokenization, TaskBatchExplanation, TaskBatchSemanticEmbedding, TaskChat,
+    TaskCompletion, TaskSemanticEmbedding, TraceContext, Usage,
+};
+use flate2::read::GzDecoder;
+use futures_util::StreamExt;
 use reqwest::StatusCode;
+use tokenizers::{models::wordlevel::WordLevel, pre_tokenizers::whitespace::Whitespace, Tokenizer};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tokio_native_tls::{native_tls, TlsAcceptor};
 use wiremock::{
-    matchers::{any, body_json_string, header, method, path},
-    Mock, MockServer, ResponseTemplate,
+    matchers::{any, body_json_string, body_partial_json, header, method, path, query_param},
+    Match, Mock, MockServer, Request, ResponseTemplate,
 };
 
 #[tokio::test]
@@ -84,6 +106,208 @@ async fn detect_rate_limiting() {
     assert!(matches!(error, Error::TooManyRequests));
 }
 
+#[tokio::test]
+async fn completion_falls_back_to_next_model() {
+    // Given a preferred model which is unavailable, one which is unknown and one which answers
+    let mock_server = MockServer::start().await;
+    let answer = r#"{"model_version":"2021-12","completions":[{"completion":"\n","finish_reason":"maximum_tokens"}],"num_tokens_prompt_total":5,"num_tokens_generated":1}"#;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .and(body_partial_json(
+            serde_json::json!({"model": "unavailable"}),
+        ))
+        .respond_with(ResponseTemplate::new(503).set_body_string("Service Unavailable"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .and(body_partial_json(serde_json::json!({"model": "unknown"})))
+        .respond_with(ResponseTemplate::new(404).set_body_string("Model not found"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .and(body_partial_json(
+            serde_json::json!({"model": "luminous-base"}),
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_string(answer))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+
+    // When
+    let response = client
+        .completion_with_fallback(
+            &task,
+            &["unavailable", "unknown", "luminous-base"],
+            &How::default(),
+        )
+        .await
+        .unwrap();
+
+    // Then
+    assert_eq!(response.completion, "\n");
+}
+
+#[tokio::test]
+async fn completion_does_not_fall_back_on_bad_request() {
+    // Given a preferred model rejecting the request
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .respond_with(ResponseTemplate::new(400).set_body_string("Bad request"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+
+    // When
+    let error = client
+        .completion_with_fallback(&task, &["first", "second"], &How::default())
+        .await
+        .unwrap_err();
+
+    // Then the second model is not tried
+    assert_eq!(error.status_code(), Some(400));
+}
+
+#[tokio::test]
+async fn completion_without_fallback_models_is_an_error() {
+    // Given
+    let client = Client::with_auth("http://127.0.0.1:1", "dummy-token").unwrap();
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+
+    // When
+    let result = client
+        .completion_with_fallback(&task, &[], &How::default())
+        .await;
+
+    // Then
+    assert!(matches!(result, Err(Error::NoModel)));
+}
+
+#[tokio::test]
+async fn auth_scheme_determines_authentication_header() {
+    let cases = [
+        (AuthScheme::Bearer, "Authorization", "Bearer dummy-token"),
+        (
+            AuthScheme::ApiKeyHeader("X-API-Key".to_owned()),
+            "X-API-Key",
+            "dummy-token",
+        ),
+        (
+            AuthScheme::Raw("Authorization".to_owned(), "ApiKey secret".to_owned()),
+            "Authorization",
+            "ApiKey secret",
+        ),
+    ];
+    for (auth_scheme, name, value) in cases {
+        // Given
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/model-settings"))
+            .and(header(name, value))
+            .respond_with(ResponseTemplate::new(200).set_body_string("[]"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+        let how = How {
+            auth_scheme,
+            ..Default::default()
+        };
+
+        // When
+        let result = client.verify_token(&how).await;
+
+        // Then
+        assert!(result.is_ok(), "{name}: {value}");
+    }
+}
+
+#[cfg(feature = "cancellation")]
+#[tokio::test]
+async fn slow_request_is_cancelled() {
+    use aleph_alpha_client::Cancellation;
+
+    // Given a server which takes long to answer
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(10)))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let cancellation = Cancellation::new();
+    let how = How {
+        cancellation: Some(cancellation.clone()),
+        ..Default::default()
+    };
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+
+    // When the request is cancelled while in flight
+    let token = cancellation.token().clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        token.cancel();
+    });
+    let start = Instant::now();
+    let result = client.completion(&task, "luminous-base", &how).await;
+
+    // Then it fails right away
+    assert!(matches!(result, Err(Error::Cancelled)));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn valid_token_is_verified() {
+    // Given
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/model-settings"))
+        .and(header("Authorization", "Bearer dummy-token"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("[]"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+
+    // When
+    let result = client.verify_token(&How::default()).await;
+
+    // Then
+    assert!(result.is_ok());
+}
+
+#[tokio::test]
+async fn rejected_token_is_unauthorized() {
+    for status in [401, 403] {
+        // Given
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/model-settings"))
+            .respond_with(ResponseTemplate::new(status).set_body_string("Invalid token"))
+            .mount(&mock_server)
+            .await;
+        let client = Client::with_auth(mock_server.uri(), "invalid-token").unwrap();
+
+        // When
+        let error = client.verify_token(&How::default()).await.unwrap_err();
+
+        // Then
+        assert!(
+            matches!(&error, Error::Unauthorized { status: actual, body } if *actual == status && body == "Invalid token"),
+            "{error}"
+        );
+        assert_eq!(error.status_code(), Some(status));
+    }
+}
+
 /// Even if we do not open too many requests at once ourselves, the API may just be busy. We also
 /// want this scenario to be easily detectable by users.
 #[tokio::test]
@@ -125,7 +349,48 @@ async fn detect_queue_full() {
         .unwrap_err();
 
     // Then
-    assert!(matches!(error, Error::Busy));
+    assert!(matches!(
+        error,
+        Error::Busy {
+            nice: false,
+            queued: None
+        }
+    ));
+}
+
+#[tokio::test]
+async fn busy_error_tells_whether_request_was_nice() {
+    // Given a busy model reporting the request had been queued
+    let mock_server = MockServer::start().await;
+    let answer =
+        r#"{"error":"This model is very busy at this moment.","code":"QUEUE_FULL","queued":true}"#;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .and(query_param("nice", "true"))
+        .respond_with(ResponseTemplate::new(503).set_body_string(answer))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let how = How {
+        be_nice: true,
+        ..Default::default()
+    };
+
+    // When
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    let error = client
+        .completion(&task, "luminous-base", &how)
+        .await
+        .unwrap_err();
+
+    // Then
+    assert!(matches!(
+        error,
+        Error::Busy {
+            nice: true,
+            queued: Some(true)
+        }
+    ));
 }
 
 /// If the API is down, we want to detect this scenario and inform the user.
@@ -197,6 +462,80 @@ async fn be_nice() {
         .any(|(k, v)| k == "nice" && v == "true"));
 }
 
+/// Each priority level should be communicated to the server by its own query parameters.
+#[tokio::test]
+async fn priority_query_parameters() {
+    // Given
+    let mock_server = MockServer::start().await;
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let cases = [
+        (Priority::Low, vec![("nice".to_owned(), "true".to_owned())]),
+        (Priority::Normal, vec![]),
+        (
+            Priority::High,
+            vec![("priority".to_owned(), "high".to_owned())],
+        ),
+    ];
+
+    for (priority, expected_query) in cases {
+        // When
+        let _ = client
+            .output_of(
+                &task.with_model("luminous-base"),
+                &How {
+                    priority,
+                    ..Default::default()
+                },
+            )
+            .await;
+
+        // Then
+        let last_request = mock_server
+            .received_requests()
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+        let query = last_request
+            .url
+            .query_pairs()
+            .into_owned()
+            .collect::<Vec<_>>();
+        assert_eq!(query, expected_query, "query for {priority:?}");
+    }
+}
+
+/// `be_nice` is kept for compatibility and overrides any priority.
+#[tokio::test]
+async fn be_nice_takes_precedence_over_priority() {
+    // Given
+    let mock_server = MockServer::start().await;
+
+    // When
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let _ = client
+        .output_of(
+            &task.with_model("luminous-base"),
+            &How {
+                be_nice: true,
+                priority: Priority::High,
+                ..Default::default()
+            },
+        )
+        .await;
+
+    // Then
+    let last_request = &mock_server.received_requests().await.unwrap()[0];
+    let query = last_request
+        .url
+        .query_pairs()
+        .into_owned()
+        .collect::<Vec<_>>();
+    assert_eq!(query, [("nice".to_owned(), "true".to_owned())]);
+}
+
 #[tokio::test]
 async fn client_timeout() {
     // Given
@@ -215,7 +554,7 @@ async fn client_timeout() {
                 .with_maximum_tokens(1)
                 .with_model("any"),
             &How {
-                client_timeout: response_time / 2,
+                client_timeout: ClientTimeout::Fixed(response_time / 2),
                 ..Default::default()
             },
         )
@@ -225,3 +564,2306 @@ async fn client_timeout() {
     // Then
     assert!(matches!(result, Error::ClientTimeout(..)));
 }
+
+#[tokio::test]
+async fn deadline_limits_request_duration() {
+    // Given a deadline which is reached before the response arrives
+    let mock_server = MockServer::start().await;
+    let response_time = Duration::from_millis(200);
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(StatusCode::OK).set_delay(response_time))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let how = How {
+        deadline: Some(Instant::now() + response_time / 4),
+        ..Default::default()
+    };
+
+    // When
+    let result = client
+        .output_of(
+            &TaskCompletion::from_text("Hello,")
+                .with_maximum_tokens(1)
+                .with_model("any"),
+            &how,
+        )
+        .await
+        .unwrap_err();
+
+    // Then
+    assert!(matches!(result, Error::ClientTimeout(timeout) if timeout <= response_time / 4));
+}
+
+#[tokio::test]
+async fn elapsed_deadline_fails_without_sending_request() {
+    // Given a deadline in the past
+    let mock_server = MockServer::start().await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let how = How {
+        deadline: Some(Instant::now() - Duration::from_millis(1)),
+        ..Default::default()
+    };
+
+    // When
+    let result = client
+        .output_of(
+            &TaskCompletion::from_text("Hello,")
+                .with_maximum_tokens(1)
+                .with_model("any"),
+            &how,
+        )
+        .await
+        .unwrap_err();
+
+    // Then
+    assert!(matches!(result, Error::ClientTimeout(timeout) if timeout.is_zero()));
+    assert!(mock_server.received_requests().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn merged_token_override_authenticates_request() {
+    // Given base settings with a token and a per call override of it
+    let mock_server = MockServer::start().await;
+    let answer = r#"{"model_version":"2021-12","completions":[{"completion":"\n","finish_reason":"maximum_tokens"}],"num_tokens_prompt_total":5,"num_tokens_generated":1}"#;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .and(header("Authorization", "Bearer override-token"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(answer))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let base = How {
+        api_token: Some("base-token".to_owned()),
+        ..Default::default()
+    };
+
+    // When
+    let how = base.merge(HowOverrides {
+        api_token: Some(Some("override-token".to_owned())),
+        ..Default::default()
+    });
+    let result = client
+        .output_of(
+            &TaskCompletion::from_text("Hello,")
+                .with_maximum_tokens(1)
+                .with_model("luminous-base"),
+            &how,
+        )
+        .await;
+
+    // Then
+    assert!(result.is_ok());
+}
+
+#[test]
+fn merged_timeout_override_keeps_other_settings() {
+    // Given
+    let base = How {
+        priority: Priority::High,
+        request_id: Some("base-request".to_owned()),
+        ..Default::default()
+    };
+
+    // When
+    let how = base.merge(HowOverrides {
+        client_timeout: Some(ClientTimeout::Fixed(Duration::from_secs(7))),
+        ..Default::default()
+    });
+
+    // Then
+    assert_eq!(
+        how.client_timeout,
+        ClientTimeout::Fixed(Duration::from_secs(7))
+    );
+    assert_eq!(how.priority, Priority::High);
+    assert_eq!(how.request_id.as_deref(), Some("base-request"));
+    assert!(base.merge(HowOverrides::default()) == base);
+}
+
+#[test]
+fn merged_override_can_clear_optional_settings() {
+    // Given
+    let base = How {
+        request_id: Some("base-request".to_owned()),
+        cache_key: Some("base-cache-key".to_owned()),
+        stream_idle_timeout: Some(Duration::from_secs(5)),
+        ..Default::default()
+    };
+
+    // When
+    let how = base.merge(HowOverrides {
+        request_id: Some(None),
+        stream_idle_timeout: Some(None),
+        ..Default::default()
+    });
+
+    // Then
+    assert_eq!(how.request_id, None);
+    assert_eq!(how.stream_idle_timeout, None);
+    assert_eq!(how.cache_key.as_deref(), Some("base-cache-key"));
+}
+
+#[tokio::test]
+async fn stream_batch_semantic_embedding_in_chunks() {
+    // Given
+    let mock_server = MockServer::start().await;
+    let answer = r#"{"model_version":"2021-12","embeddings":[[1.0,0.0],[0.0,1.0]]}"#;
+    Mock::given(method("POST"))
+        .and(path("/batch_semantic_embed"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(answer))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let task = TaskBatchSemanticEmbedding {
+        prompts: ["a", "b", "c", "d"].map(Prompt::from_text).to_vec(),
+        representation: SemanticRepresentation::Symmetric,
+        compress_to_size: None,
+    };
+
+    // When
+    let how = How::default();
+    let chunks = client
+        .stream_batch_semantic_embedding(&task, 2, &how)
+        .collect::<Vec<_>>()
+        .await;
+
+    // Then
+    let offsets = chunks
+        .into_iter()
+        .map(|chunk| chunk.unwrap().offset)
+        .collect::<Vec<_>>();
+    assert_eq!(offsets, [0, 2]);
+    let requests = mock_server.received_requests().await.unwrap();
+    let bodies = requests
+        .iter()
+        .map(|request| request.body_json::<serde_json::Value>().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(bodies[0]["prompts"][0][0]["data"], "a");
+    assert_eq!(bodies[1]["prompts"][0][0]["data"], "c");
+    assert_eq!(bodies[1]["prompts"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn oversized_request_is_rejected_locally() {
+    // Given
+    let mock_server = MockServer::start().await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let huge_text = "a".repeat(2048);
+    let task = TaskCompletion::from_text(&huge_text).with_maximum_tokens(1);
+
+    // When
+    let error = client
+        .output_of(
+            &task.with_model("luminous-base"),
+            &How {
+                max_request_body_size: 1024,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap_err();
+
+    // Then
+    assert!(matches!(
+        error,
+        Error::RequestTooLarge { size, limit: 1024 } if size > 2048
+    ));
+    assert!(mock_server.received_requests().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn client_default_timeout() {
+    // Given
+    let mock_server = MockServer::start().await;
+    let response_time = Duration::from_millis(20);
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(StatusCode::OK).set_delay(response_time))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token")
+        .unwrap()
+        .with_timeout(response_time / 2);
+
+    // When
+    let result = client
+        .output_of(
+            &TaskCompletion::from_text("Hello,")
+                .with_maximum_tokens(1)
+                .with_model("any"),
+            &How::default(),
+        )
+        .await
+        .unwrap_err();
+
+    // Then
+    assert!(matches!(result, Error::ClientTimeout(timeout) if timeout == response_time / 2));
+}
+
+#[tokio::test]
+async fn request_timeout_takes_precedence_over_client_default() {
+    // Given
+    let mock_server = MockServer::start().await;
+    let response_time = Duration::from_millis(20);
+    let answer = r#"{"model_version":"2021-12","completions":[{"completion":"\n","finish_reason":"maximum_tokens"}],"num_tokens_prompt_total":5,"num_tokens_generated":1}"#;
+    Mock::given(any())
+        .respond_with(
+            ResponseTemplate::new(StatusCode::OK)
+                .set_body_string(answer)
+                .set_delay(response_time),
+        )
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token")
+        .unwrap()
+        .with_timeout(response_time / 2);
+
+    // When
+    let result = client
+        .output_of(
+            &TaskCompletion::from_text("Hello,")
+                .with_maximum_tokens(1)
+                .with_model("any"),
+            &How {
+                client_timeout: ClientTimeout::Fixed(response_time * 50),
+                ..Default::default()
+            },
+        )
+        .await;
+
+    // Then
+    assert!(result.is_ok());
+}
+
+#[tokio::test]
+async fn shutdown_closes_idle_connections() {
+    // Given a client which has kept a connection alive
+    let closed = Arc::new(tokio::sync::Notify::new());
+    let notify_closed = closed.clone();
+    let port = start_raw_server(move |stream| {
+        let notify_closed = notify_closed.clone();
+        async move {
+            let mut stream = BufReader::new(stream);
+            while read_request(&mut stream).await.is_some() {
+                let body = r#"{"model_version":"2021-12","completions":[{"completion":"\n","finish_reason":"maximum_tokens"}],"num_tokens_prompt_total":5,"num_tokens_generated":1}"#;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            notify_closed.notify_one();
+        }
+    })
+    .await;
+    let client = Client::with_auth(format!("http://127.0.0.1:{port}"), "dummy-token").unwrap();
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    client
+        .completion(&task, "luminous-base", &How::default())
+        .await
+        .unwrap();
+
+    // When
+    client.shutdown();
+
+    // Then the server sees the connection being closed
+    tokio::time::timeout(Duration::from_secs(5), closed.notified())
+        .await
+        .expect("Connection has not been closed by the client");
+}
+
+#[tokio::test]
+async fn batch_semantic_embedding_is_split_into_chunks() {
+    // Given a server embedding each prompt "n" as [n]
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/batch_semantic_embed"))
+        .respond_with(|request: &wiremock::Request| {
+            let body = request.body_json::<serde_json::Value>().unwrap();
+            let embeddings = body["prompts"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|prompt| {
+                    let number: f32 = prompt[0]["data"].as_str().unwrap().parse().unwrap();
+                    vec![number]
+                })
+                .collect::<Vec<_>>();
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "model_version": "2021-12",
+                "embeddings": embeddings
+            }))
+        })
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let texts = (0..5).map(|n| n.to_string()).collect::<Vec<_>>();
+    let task = TaskBatchSemanticEmbedding {
+        prompts: texts
+            .iter()
+            .map(|text| Prompt::from_text(text.as_str()))
+            .collect(),
+        representation: SemanticRepresentation::Symmetric,
+        compress_to_size: None,
+    };
+
+    // When
+    let output = client
+        .batch_semantic_embedding_in_chunks(&task, 2, &How::default())
+        .await
+        .unwrap();
+
+    // Then
+    assert_eq!(
+        output.embeddings,
+        [[0.0], [1.0], [2.0], [3.0], [4.0]].map(Vec::from)
+    );
+    assert_eq!(mock_server.received_requests().await.unwrap().len(), 3);
+}
+
+#[test]
+fn inspect_completion_request_json() {
+    // Given
+    let client = Client::with_auth("http://localhost:8080", "dummy-token").unwrap();
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+
+    // When
+    let body = client
+        .request_json(&task.with_model("luminous-base"))
+        .unwrap();
+
+    // Then
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "model": "luminous-base",
+            "prompt": [{"type": "text", "data": "Hello,"}],
+            "maximum_tokens": 1
+        })
+    );
+}
+
+#[test]
+fn inspect_chat_request_json() {
+    // Given
+    let client = Client::with_auth("http://localhost:8080", "dummy-token").unwrap();
+    let task = TaskChat::with_message(Message::user("Hello")).with_maximum_tokens(1);
+
+    // When
+    let body = client
+        .request_json(&task.with_model("pharia-1-llm-7b-control"))
+        .unwrap();
+
+    // Then
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "model": "pharia-1-llm-7b-control",
+            "messages": [{"role": "user", "content": "Hello"}],
+            "max_tokens": 1
+        })
+    );
+}
+
+async fn chat_with_json_schema_validation(content: &str) -> Result<(), Error> {
+    let mock_server = MockServer::start().await;
+    let answer = serde_json::json!({
+        "choices": [{
+            "message": {"role": "assistant", "content": content},
+            "finish_reason": "stop"
+        }],
+        "usage": {"prompt_tokens": 5, "completion_tokens": 10}
+    });
+    Mock::given(method("POST"))
+        .and(path("/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let task = TaskChat::with_message(Message::user("Who are you?"))
+        .with_response_format(ResponseFormat::JsonSchema {
+            json_schema: JsonSchema {
+                name: "person".to_owned(),
+                schema: serde_json::json!({
+                    "type": "object",
+                    "properties": {"name": {"type": "string"}},
+                    "required": ["name"]
+                }),
+                strict: None,
+            },
+        })
+        .with_response_validation();
+
+    client
+        .chat(&task, "pharia-1-llm-7b-control", &How::default())
+        .await
+        .map(drop)
+}
+
+#[tokio::test]
+async fn chat_response_conforming_to_schema_is_accepted() {
+    let result = chat_with_json_schema_validation(r#"{"name": "Pharia"}"#).await;
+
+    assert!(result.is_ok());
+}
+
+#[tokio::test]
+async fn chat_response_violating_schema_is_rejected() {
+    let result = chat_with_json_schema_validation(r#"{"age": 1}"#).await;
+
+    assert!(matches!(result, Err(Error::SchemaViolation { .. })));
+}
+
+#[tokio::test]
+async fn chat_response_violating_schema_is_rejected_by_output_of() {
+    // Given a response which is not a JSON object
+    let mock_server = MockServer::start().await;
+    let answer = serde_json::json!({
+        "choices": [{
+            "message": {"role": "assistant", "content": "[1, 2]"},
+            "finish_reason": "stop"
+        }],
+        "usage": {"prompt_tokens": 5, "completion_tokens": 10}
+    });
+    Mock::given(method("POST"))
+        .and(path("/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let task = TaskChat::with_message(Message::user("Answer in JSON"))
+        .with_response_format(ResponseFormat::JsonObject)
+        .with_response_validation();
+
+    // When
+    let result = client
+        .output_of(&task.with_model("pharia-1-llm-7b-control"), &How::default())
+        .await;
+
+    // Then
+    assert!(matches!(result, Err(Error::SchemaViolation { .. })));
+}
+
+#[tokio::test]
+async fn streamed_chat_response_violating_schema_is_rejected() {
+    // Given a stream assembling into something else than a JSON object
+    let mock_server = MockServer::start().await;
+    let events = concat!(
+        "data: {\"choices\":[{\"finish_reason\":null,\"delta\":{\"role\":\"assistant\",\"content\":\"[1,\"}}]}\n\n",
+        "data: {\"choices\":[{\"finish_reason\":\"stop\",\"delta\":{\"content\":\" 2]\"}}]}\n\n",
+    );
+    Mock::given(method("POST"))
+        .and(path("/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(events, "text/event-stream"))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let task = TaskChat::with_message(Message::user("Answer in JSON"))
+        .with_response_format(ResponseFormat::JsonObject)
+        .with_response_validation();
+
+    // When
+    let chunks = client
+        .stream_chat(&task, "pharia-1-llm-7b-control", &How::default())
+        .await
+        .unwrap()
+        .collect::<Vec<_>>()
+        .await;
+
+    // Then all chunks are delivered, followed by the violation
+    assert_eq!(chunks.len(), 3);
+    assert!(chunks[..2].iter().all(Result::is_ok));
+    assert!(matches!(chunks[2], Err(Error::SchemaViolation { .. })));
+}
+
+#[tokio::test]
+async fn completions_batch_preserves_order() {
+    // Given a server which answers the first prompt slowest
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .respond_with(|request: &wiremock::Request| {
+            let body = request.body_json::<serde_json::Value>().unwrap();
+            let text = body["prompt"][0]["data"].as_str().unwrap().to_owned();
+            let delay = if text == "0" { 50 } else { 0 };
+            ResponseTemplate::new(200)
+                .set_body_json(serde_json::json!({
+                    "model_version": "2021-12",
+                    "completions": [{"completion": text, "finish_reason": "maximum_tokens"}],
+                    "num_tokens_prompt_total": 1,
+                    "num_tokens_generated": 1
+                }))
+                .set_delay(Duration::from_millis(delay))
+        })
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let tasks = ["0", "1", "2"].map(TaskCompletion::from_text);
+
+    // When
+    let outputs = client
+        .completions_batch(&tasks, "luminous-base", &How::default(), 3)
+        .await;
+
+    // Then
+    let completions = outputs
+        .into_iter()
+        .map(|output| output.unwrap().completion)
+        .collect::<Vec<_>>();
+    assert_eq!(completions, ["0", "1", "2"]);
+}
+
+#[tokio::test]
+async fn render_chat_as_prompt_with_cached_template() {
+    // Given a model with a prompt template
+    let mock_server = MockServer::start().await;
+    let template = "{% for message in messages %}<|{{ message.role }}|>{{ message.content }}\n{% endfor %}<|assistant|>";
+    Mock::given(method("GET"))
+        .and(path("/model-settings"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+            {"name": "luminous-base", "prompt_template": null},
+            {"name": "pharia-1-llm-7b-control", "prompt_template": template}
+        ])))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let task = TaskChat::with_messages(vec![Message::system("Be brief."), Message::user("Hi")]);
+
+    // When rendering twice
+    let model = "pharia-1-llm-7b-control";
+    let first = client
+        .render_chat_as_prompt(model, &task, &How::default())
+        .await
+        .unwrap();
+    let second = client
+        .render_chat_as_prompt(model, &task, &How::default())
+        .await
+        .unwrap();
+
+    // Then the template is only fetched once
+    let expected = Prompt::from_text("<|system|>Be brief.\n<|user|>Hi\n<|assistant|>");
+    assert_eq!(first, expected);
+    assert_eq!(second, expected);
+}
+
+/// Accepts connections on a local port and hands each of them to `handle`, concurrently. For tests
+/// which need control over the connection itself, e.g. to count or drop it. Anything else is better
+/// served by wiremock. Returns the port.
+async fn start_raw_server<F, Fut>(handle: F) -> u16
+where
+    F: Fn(TcpStream) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(handle(stream));
+        }
+    });
+    port
+}
+
+/// Reads the next request from `stream` and returns its request line, e.g. `GET / HTTP/1.1`, and
+/// its body, as announced by its `Content-Length`. `None` if the connection is closed before the
+/// request is complete.
+async fn read_request(stream: &mut (impl AsyncBufRead + Unpin)) -> Option<(String, String)> {
+    let mut request_line = String::new();
+    if stream.read_line(&mut request_line).await.ok()? == 0 {
+        return None;
+    }
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await.ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok()?;
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await.ok()?;
+    Some((
+        request_line.trim_end().to_owned(),
+        String::from_utf8(body).ok()?,
+    ))
+}
+
+/// Tokenizer treating every word as one token, served by test servers in place of the tokenizer of
+/// a model.
+fn word_level_tokenizer_json(words: &[&str]) -> String {
+    let vocab = words
+        .iter()
+        .chain(&["[UNK]"])
+        .enumerate()
+        .map(|(id, &word)| (word.to_owned(), id as u32))
+        .collect();
+    let model = WordLevel::builder()
+        .vocab(vocab)
+        .unk_token("[UNK]".to_owned())
+        .build()
+        .unwrap();
+    let mut tokenizer = Tokenizer::new(model);
+    tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+    tokenizer.to_string(false).unwrap()
+}
+
+/// Encodes `data` as a chunk of a response with chunked transfer encoding.
+fn http_chunk(data: &str) -> String {
+    format!("{:x}\r\n{data}\r\n", data.len())
+}
+
+/// Serves a single completion over TLS, using a self-signed certificate. Returns the base URL.
+async fn start_self_signed_completion_server() -> String {
+    let identity = native_tls::Identity::from_pkcs8(
+        include_bytes!("self-signed-cert.pem"),
+        include_bytes!("self-signed-key.pem"),
+    )
+    .unwrap();
+    let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+    let port = start_raw_server(move |stream| {
+        let acceptor = acceptor.clone();
+        async move {
+            // Clients rejecting the certificate fail the handshake
+            let Ok(stream) = acceptor.accept(stream).await else {
+                return;
+            };
+            let mut stream = BufReader::new(stream);
+            if read_request(&mut stream).await.is_none() {
+                return;
+            }
+            let body = r#"{"model_version":"2021-12","completions":[{"completion":"\n","finish_reason":"maximum_tokens"}],"num_tokens_prompt_total":5,"num_tokens_generated":1}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+        }
+    })
+    .await;
+    format!("https://localhost:{port}")
+}
+
+#[tokio::test]
+async fn self_signed_certificate_is_rejected_by_default() {
+    // Given
+    let base = start_self_signed_completion_server().await;
+    let client = Client::with_auth(base, "dummy-token").unwrap();
+
+    // When
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    let result = client
+        .completion(&task, "luminous-base", &How::default())
+        .await;
+
+    // Then
+    assert!(matches!(result, Err(Error::Other(_))));
+}
+
+#[tokio::test]
+async fn self_signed_certificate_is_accepted_if_configured() {
+    // Given
+    let base = start_self_signed_completion_server().await;
+    let client = Client::with_auth(base, "dummy-token")
+        .unwrap()
+        .accept_invalid_certs(true)
+        .unwrap();
+
+    // When
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    let response = client
+        .completion(&task, "luminous-base", &How::default())
+        .await
+        .unwrap();
+
+    // Then
+    assert_eq!(response.completion, "\n");
+}
+
+#[tokio::test]
+async fn embed_stream_of_prompts() {
+    // Given
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/semantic_embed"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "model_version": "2021-12",
+            "embedding": [0.5, 0.5]
+        })))
+        .expect(5)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let how = How::default();
+    let prompts = futures_util::stream::iter(0..5).map(|i| Prompt::from_text(format!("Doc {i}")));
+
+    // When
+    let mut indices: Vec<_> = client
+        .embed_stream(prompts, SemanticRepresentation::Document, &how, 2)
+        .map(|(index, embedding)| {
+            assert_eq!(embedding.unwrap().dimensions(), 2);
+            index
+        })
+        .collect()
+        .await;
+
+    // Then each prompt has been embedded exactly once
+    indices.sort();
+    assert_eq!(indices, [0, 1, 2, 3, 4]);
+}
+
+async fn semantic_embedding_with_size(
+    compress_to_size: Option<u32>,
+    returned_size: usize,
+) -> Result<usize, Error> {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/semantic_embed"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "model_version": "2021-12",
+            "embedding": vec![0.5; returned_size]
+        })))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let task = TaskSemanticEmbedding {
+        prompt: Prompt::from_text("Hello"),
+        representation: SemanticRepresentation::Symmetric,
+        compress_to_size,
+    };
+
+    client
+        .semantic_embedding(&task, &How::default())
+        .await
+        .map(|output| output.dimensions())
+}
+
+#[tokio::test]
+async fn full_semantic_embedding_dimensions() {
+    let dimensions = semantic_embedding_with_size(None, 5120).await.unwrap();
+
+    assert_eq!(dimensions, 5120);
+}
+
+#[tokio::test]
+async fn compressed_semantic_embedding_dimensions() {
+    let dimensions = semantic_embedding_with_size(Some(128), 128).await.unwrap();
+
+    assert_eq!(dimensions, 128);
+}
+
+#[tokio::test]
+async fn semantic_embedding_with_unexpected_size_is_rejected() {
+    let error = semantic_embedding_with_size(Some(128), 5120)
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        error,
+        Error::UnexpectedEmbeddingSize {
+            expected: 128,
+            actual: 5120
+        }
+    ));
+}
+
+#[tokio::test]
+async fn batch_embedding_with_unexpected_size_is_rejected() {
+    // Given a server ignoring the requested compression for one of the prompts
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/batch_semantic_embed"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "model_version": "2021-12",
+            "embeddings": [vec![0.5; 128], vec![0.5; 5120]]
+        })))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let task = TaskBatchSemanticEmbedding {
+        prompts: vec![Prompt::from_text("Hello"), Prompt::from_text("World")],
+        representation: SemanticRepresentation::Symmetric,
+        compress_to_size: Some(128),
+    };
+
+    // When
+    let error = client
+        .output_of(&task, &How::default())
+        .await
+        .map(drop)
+        .unwrap_err();
+
+    // Then
+    assert!(matches!(
+        error,
+        Error::UnexpectedEmbeddingSize {
+            expected: 128,
+            actual: 5120
+        }
+    ));
+}
+
+/// Streams the given chat deltas as server sent events, waiting for `delay` before each of them.
+/// Returns the base URL.
+async fn start_delayed_chat_stream_server(
+    deltas: &'static [&'static str],
+    delay: Duration,
+) -> String {
+    let port = start_raw_server(move |stream| async move {
+        let mut stream = BufReader::new(stream);
+        if read_request(&mut stream).await.is_none() {
+            return;
+        }
+        let header = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n";
+        stream.write_all(header.as_bytes()).await.unwrap();
+        for delta in deltas {
+            tokio::time::sleep(delay).await;
+            let event = format!(
+                "data: {{\"choices\":[{{\"finish_reason\":null,\"delta\":{{\"content\":\"{delta}\"}}}}]}}\n\n"
+            );
+            stream.write_all(http_chunk(&event).as_bytes()).await.unwrap();
+            stream.flush().await.unwrap();
+        }
+        stream.write_all(b"0\r\n\r\n").await.unwrap();
+        stream.shutdown().await.unwrap();
+    })
+    .await;
+    format!("http://127.0.0.1:{port}")
+}
+
+#[tokio::test]
+async fn stream_chat_measures_token_timing() {
+    // Given a server streaming three deltas with a delay before each of them
+    let delay = Duration::from_millis(50);
+    let base = start_delayed_chat_stream_server(&["Hello", ",", " world"], delay).await;
+    let client = Client::with_auth(base, "dummy-token").unwrap();
+    let how = How {
+        measure_token_timing: true,
+        ..Default::default()
+    };
+
+    // When
+    let task = TaskChat::with_message(Message::user("Hi"));
+    let chunks: Vec<_> = client
+        .stream_chat(&task, "pharia-1-llm-7b-control", &how)
+        .await
+        .unwrap()
+        .map(|chunk| chunk.unwrap())
+        .collect()
+        .await;
+
+    // Then every delta has been timed and the elapsed times add up monotonically
+    assert_eq!(chunks.len(), 3);
+    let timings: Vec<_> = chunks
+        .iter()
+        .map(|chunk| chunk.time_since_previous.unwrap())
+        .collect();
+    assert!(timings.iter().all(|timing| *timing >= delay / 2));
+    let elapsed: Vec<_> = timings
+        .iter()
+        .scan(Duration::ZERO, |total, timing| {
+            *total += *timing;
+            Some(*total)
+        })
+        .collect();
+    assert!(elapsed.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[tokio::test]
+async fn stream_chat_yields_every_choice_of_an_event() {
+    // Given an event with two choices
+    let mock_server = MockServer::start().await;
+    let events = "data: {\"choices\":[\
+        {\"index\":0,\"finish_reason\":null,\"delta\":{\"content\":\"Hello\"}},\
+        {\"index\":1,\"finish_reason\":null,\"delta\":{\"content\":\"Hi\"}}]}\n\n";
+    Mock::given(method("POST"))
+        .and(path("/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(events, "text/event-stream"))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+
+    // When
+    let task = TaskChat::with_message(Message::user("Hi"));
+    let chunks: Vec<_> = client
+        .stream_chat(&task, "pharia-1-llm-7b-control", &How::default())
+        .await
+        .unwrap()
+        .map(|chunk| chunk.unwrap())
+        .collect()
+        .await;
+
+    // Then
+    let choices: Vec<_> = chunks
+        .iter()
+        .map(|chunk| (chunk.index, chunk.delta.content.as_str()))
+        .collect();
+    assert_eq!(choices, [(0, "Hello"), (1, "Hi")]);
+}
+
+#[tokio::test]
+async fn stream_chat_skips_events_without_choices() {
+    // Given a stream with an event without any choice, e.g. because it has been filtered
+    let mock_server = MockServer::start().await;
+    let events = "data: {\"choices\":[]}\n\n\
+        data: {\"choices\":[{\"finish_reason\":\"stop\",\"delta\":{\"content\":\"Hello\"}}]}\n\n";
+    Mock::given(method("POST"))
+        .and(path("/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(events, "text/event-stream"))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+
+    // When
+    let task = TaskChat::with_message(Message::user("Hi"));
+    let chunks: Vec<_> = client
+        .stream_chat(&task, "pharia-1-llm-7b-control", &How::default())
+        .await
+        .unwrap()
+        .collect()
+        .await;
+
+    // Then only the chunk of the second event is yielded
+    assert_eq!(chunks.len(), 1);
+    assert_eq!(chunks[0].as_ref().unwrap().delta.content, "Hello");
+}
+
+#[tokio::test]
+async fn token_timing_is_not_measured_by_default() {
+    // Given
+    let mock_server = MockServer::start().await;
+    let events =
+        "data: {\"choices\":[{\"finish_reason\":null,\"delta\":{\"content\":\"Hello\"}}]}\n\n";
+    Mock::given(method("POST"))
+        .and(path("/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(events, "text/event-stream"))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+
+    // When
+    let task = TaskChat::with_message(Message::user("Hi"));
+    let chunks: Vec<_> = client
+        .stream_chat(&task, "pharia-1-llm-7b-control", &How::default())
+        .await
+        .unwrap()
+        .map(|chunk| chunk.unwrap())
+        .collect()
+        .await;
+
+    // Then
+    assert_eq!(chunks.len(), 1);
+    assert!(chunks[0].time_since_previous.is_none());
+}
+
+#[tokio::test]
+async fn output_of_with_raw_returns_raw_body() {
+    // Given
+    let mock_server = MockServer::start().await;
+    let answer = serde_json::json!({
+        "model_version": "2021-12",
+        "completions": [{"completion": "\n", "finish_reason": "maximum_tokens"}],
+        "num_tokens_prompt_total": 5,
+        "num_tokens_generated": 1,
+        "unknown_field": "only visible in the raw body"
+    });
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&answer))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+
+    // When
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    let (output, raw) = client
+        .output_of_with_raw(&task.with_model("luminous-base"), &How::default())
+        .await
+        .unwrap();
+
+    // Then
+    assert_eq!(output.completion, "\n");
+    assert_eq!(raw.body, answer);
+}
+
+#[tokio::test]
+async fn output_of_with_raw_reports_raw_body_of_invalid_response() {
+    // Given
+    let mock_server = MockServer::start().await;
+    let answer = serde_json::json!({ "model_version": "2021-12" });
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&answer))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+
+    // When
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    let error = client
+        .output_of_with_raw(&task.with_model("luminous-base"), &How::default())
+        .await
+        .unwrap_err();
+
+    // Then
+    assert!(matches!(error, Error::InvalidResponse { body, .. } if body == answer));
+}
+
+#[tokio::test]
+async fn request_id_is_sent_and_captured() {
+    // Given a server echoing the request id
+    let mock_server = MockServer::start().await;
+    let answer = r#"{"model_version":"2021-12","completions":[{"completion":"\n","finish_reason":"maximum_tokens"}],"num_tokens_prompt_total":5,"num_tokens_generated":1}"#;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .and(header("X-Request-Id", "my-request-id"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("X-Request-Id", "my-request-id")
+                .set_body_raw(answer, "application/json"),
+        )
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let how = How {
+        request_id: Some("my-request-id".to_owned()),
+        ..Default::default()
+    };
+
+    // When
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    let (_, raw) = client
+        .output_of_with_raw(&task.with_model("luminous-base"), &how)
+        .await
+        .unwrap();
+
+    // Then
+    assert_eq!(raw.request_id.as_deref(), Some("my-request-id"));
+}
+
+#[tokio::test]
+async fn response_headers_are_captured() {
+    // Given a server setting a custom header
+    let mock_server = MockServer::start().await;
+    let answer = r#"{"model_version":"2021-12","completions":[{"completion":"\n","finish_reason":"maximum_tokens"}],"num_tokens_prompt_total":5,"num_tokens_generated":1}"#;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("X-RateLimit-Remaining", "41")
+                .set_body_raw(answer, "application/json"),
+        )
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+
+    // When
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    let (output, headers) = client
+        .output_of_with_headers(&task.with_model("luminous-base"), &How::default())
+        .await
+        .unwrap();
+
+    // Then
+    assert_eq!(output.completion, "\n");
+    assert_eq!(headers["x-ratelimit-remaining"], "41");
+}
+
+#[tokio::test]
+async fn cache_key_is_sent_as_header() {
+    // Given
+    let mock_server = MockServer::start().await;
+    let answer = r#"{"model_version":"2021-12","completions":[{"completion":"\n","finish_reason":"maximum_tokens"}],"num_tokens_prompt_total":5,"num_tokens_generated":1}"#;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .and(header("X-Prompt-Cache-Key", "support-bot-v2"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(answer, "application/json"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let how = How {
+        cache_key: Some("support-bot-v2".to_owned()),
+        ..Default::default()
+    };
+
+    // When
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    let result = client.completion(&task, "luminous-base", &how).await;
+
+    // Then
+    assert!(result.is_ok());
+}
+
+#[tokio::test]
+async fn batch_detokenize_two_sequences() {
+    // Given
+    let mock_server = MockServer::start().await;
+    for (token_ids, result) in [(vec![556, 48741], "An apple"), (vec![247, 2983], " a day")] {
+        Mock::given(method("POST"))
+            .and(path("/detokenize"))
+            .and(body_json_string(
+                serde_json::json!({ "model": "luminous-base", "token_ids": token_ids }).to_string(),
+            ))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({ "result": result })),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+    }
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+
+    // When
+    let task = TaskBatchDetokenization {
+        sequences: vec![&[556, 48741], &[247, 2983]],
+    };
+    let outputs = client
+        .batch_detokenize(&task, "luminous-base", &How::default())
+        .await
+        .unwrap();
+
+    // Then
+    assert_eq!(
+        outputs,
+        vec![
+            DetokenizationOutput {
+                result: "An apple".to_owned()
+            },
+            DetokenizationOutput {
+                result: " a day".to_owned()
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn stream_completion_with_echo_starts_with_prompt() {
+    // Given
+    let mock_server = MockServer::start().await;
+    let events = concat!(
+        "data: {\"type\":\"stream_chunk\",\"index\":0,\"completion\":\"An apple a day keeps\"}\n\n",
+        "data: {\"type\":\"stream_chunk\",\"index\":0,\"completion\":\" the doctor\"}\n\n",
+    );
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .and(body_partial_json(
+            serde_json::json!({ "echo": true, "stream": true }),
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(events, "text/event-stream"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+
+    // When
+    let task = TaskCompletion::from_text("An apple a day")
+        .with_maximum_tokens(3)
+        .with_echo();
+    let chunks: Vec<_> = client
+        .stream_completion(&task, "luminous-base", &How::default())
+        .await
+        .unwrap()
+        .filter_map(|event| async move {
+            match event.unwrap() {
+                CompletionEvent::StreamChunk(chunk) => Some(chunk.completion),
+                _ => None,
+            }
+        })
+        .collect()
+        .await;
+
+    // Then
+    assert!(chunks[0].starts_with("An apple a day"));
+}
+
+#[tokio::test]
+async fn batch_explanation_sends_one_request_per_target() {
+    // Given a server explaining each target with a score of its own
+    let mock_server = MockServer::start().await;
+    for (target, score) in [(" keeps the doctor away", 0.5), (" is healthy", 0.25)] {
+        Mock::given(method("POST"))
+            .and(path("/explain"))
+            .and(body_partial_json(serde_json::json!({ "target": target })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "explanations": [{
+                    "items": [{"type": "target", "scores": [{"start": 0, "length": 3, "score": score}]}]
+                }]
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+    }
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let task = TaskBatchExplanation {
+        prompt: Prompt::from_text("An apple a day"),
+        targets: vec![" keeps the doctor away", " is healthy"],
+        granularity: Granularity::default(),
+    };
+
+    // When
+    let outputs = client
+        .batch_explanation(&task, "luminous-base", &How::default())
+        .await
+        .unwrap();
+
+    // Then each target is explained on its own, in the order of the targets
+    let scores: Vec<_> = outputs
+        .iter()
+        .map(|output| match &output.explanations[..] {
+            [explanation] => match &explanation.items[..] {
+                [ItemExplanation::Target { scores }] => scores[0].score,
+                _ => panic!("Expected a single target explanation"),
+            },
+            _ => panic!("Expected one explanation per target"),
+        })
+        .collect();
+    assert_eq!(scores, [0.5, 0.25]);
+}
+
+#[tokio::test]
+async fn invalid_best_of_is_rejected_on_every_path() {
+    // Given a task requesting fewer candidates than completions
+    let mock_server = MockServer::start().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let mut task = TaskCompletion::from_text("Hello").with_maximum_tokens(1);
+    task.sampling = Sampling {
+        best_of: Some(0),
+        ..Sampling::MOST_LIKELY
+    };
+
+    // When
+    let output = client
+        .output_of(&task.with_model("luminous-base"), &How::default())
+        .await
+        .map(drop);
+    let stream = client
+        .stream_completion(&task, "luminous-base", &How::default())
+        .await
+        .map(drop);
+
+    // Then no request is sent
+    assert!(matches!(output, Err(Error::InvalidSampling { .. })));
+    assert!(matches!(stream, Err(Error::InvalidSampling { .. })));
+}
+
+#[tokio::test]
+async fn stream_completion_marks_echoed_prompt() {
+    // Given a stream echoing the prompt in front of the first generated token
+    let mock_server = MockServer::start().await;
+    let events = concat!(
+        "data: {\"type\":\"stream_chunk\",\"index\":0,\"completion\":\"An apple a day keeps\"}\n\n",
+        "data: {\"type\":\"stream_chunk\",\"index\":0,\"completion\":\" the doctor\"}\n\n",
+    );
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(events, "text/event-stream"))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+
+    // When
+    let task = TaskCompletion::from_text("An apple a day")
+        .with_maximum_tokens(3)
+        .with_echo();
+    let chunks: Vec<_> = client
+        .stream_completion(&task, "luminous-base", &How::default())
+        .await
+        .unwrap()
+        .filter_map(|event| async move {
+            match event.unwrap() {
+                CompletionEvent::StreamChunk(chunk) => Some((chunk.completion, chunk.is_echo)),
+                _ => None,
+            }
+        })
+        .collect()
+        .await;
+
+    // Then the prompt is delivered in a chunk of its own
+    assert_eq!(
+        chunks,
+        [
+            ("An apple a day".to_owned(), true),
+            (" keeps".to_owned(), false),
+            (" the doctor".to_owned(), false),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn stream_completion_stops_at_regex() {
+    // Given a list which goes on after the second item
+    let mock_server = MockServer::start().await;
+    let events = concat!(
+        "data: {\"type\":\"stream_chunk\",\"index\":0,\"completion\":\"1. Apples\"}\n\n",
+        "data: {\"type\":\"stream_chunk\",\"index\":0,\"completion\":\"\\n2. Pears\"}\n\n",
+        "data: {\"type\":\"stream_chunk\",\"index\":0,\"completion\":\"\\n3. Plums\"}\n\n",
+        "data: {\"type\":\"stream_summary\",\"index\":0,\"model_version\":\"2022-04\",\"finish_reason\":\"maximum_tokens\"}\n\n",
+    );
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(events, "text/event-stream"))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let task = TaskCompletion::from_text("Fruits:\n").with_maximum_tokens(20);
+
+    // When stopping at the third item
+    let stop = regex::Regex::new(r"\n3\.").unwrap();
+    let events: Vec<_> = client
+        .stream_completion_until(&task, "luminous-base", stop, &How::default())
+        .await
+        .unwrap()
+        .map(Result::unwrap)
+        .collect()
+        .await;
+
+    // Then the completion is cut off in front of the match
+    let completion: String = events
+        .iter()
+        .filter_map(|event| match event {
+            CompletionEvent::StreamChunk(chunk) => Some(chunk.completion.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(completion, "1. Apples\n2. Pears");
+    assert_eq!(events.len(), 3);
+    match events.last().unwrap() {
+        CompletionEvent::StreamSummary(summary) => {
+            assert_eq!(summary.model_version, None);
+            assert_eq!(summary.finish_reason, "stop_sequence_reached");
+            assert_eq!(summary.stop_sequence.as_deref(), Some("\n3."));
+        }
+        _ => panic!("Expected a stream summary"),
+    }
+}
+
+#[tokio::test]
+async fn stream_completion_until_ignores_echoed_prompt() {
+    // Given a stream echoing a prompt which contains the stop pattern
+    let mock_server = MockServer::start().await;
+    let events = concat!(
+        "data: {\"type\":\"stream_chunk\",\"index\":0,\"completion\":\"Fruits:\\n1. Apples\"}\n\n",
+        "data: {\"type\":\"stream_chunk\",\"index\":0,\"completion\":\"\\n2. Pears\"}\n\n",
+        "data: {\"type\":\"stream_summary\",\"index\":0,\"model_version\":\"2022-04\",\"finish_reason\":\"maximum_tokens\"}\n\n",
+    );
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(events, "text/event-stream"))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let task = TaskCompletion::from_text("Fruits:\n")
+        .with_maximum_tokens(20)
+        .with_echo();
+
+    // When stopping at a list item
+    let stop = regex::Regex::new(r"\n\d\.").unwrap();
+    let events: Vec<_> = client
+        .stream_completion_until(&task, "luminous-base", stop, &How::default())
+        .await
+        .unwrap()
+        .map(Result::unwrap)
+        .collect()
+        .await;
+
+    // Then only the generated text is matched
+    let chunks: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            CompletionEvent::StreamChunk(chunk) => Some((chunk.completion.as_str(), chunk.is_echo)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(chunks, [("Fruits:\n", true), ("1. Apples", false)]);
+    match events.last().unwrap() {
+        CompletionEvent::StreamSummary(summary) => {
+            assert_eq!(summary.stop_sequence.as_deref(), Some("\n2."));
+        }
+        _ => panic!("Expected a stream summary"),
+    }
+}
+
+#[tokio::test]
+async fn stream_event_exceeding_limit_is_an_error() {
+    // Given a server sending a huge line without ever completing the event
+    let mock_server = MockServer::start().await;
+    let events = format!("data: {}", "x".repeat(4096));
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(events, "text/event-stream"))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let how = How {
+        max_stream_event_size: 1024,
+        ..Default::default()
+    };
+
+    // When
+    let task = TaskCompletion::from_text("An apple a day");
+    let events: Vec<_> = client
+        .stream_completion(&task, "luminous-base", &how)
+        .await
+        .unwrap()
+        .collect()
+        .await;
+
+    // Then the stream ends with a single error
+    assert_eq!(events.len(), 1);
+    assert!(matches!(events[0], Err(Error::InvalidStream { .. })));
+}
+
+#[tokio::test]
+async fn default_system_prompt_is_prepended() {
+    // Given a model whose prompt template sets a default system message
+    let template = "{%- set default_system_message = 'Be brief.' %}\
+        {%- for message in messages %}{{ message.content }}{% endfor %}";
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/model-settings"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+            {"name": "luminous-base"},
+            {"name": "pharia-1-llm-7b-control", "prompt_template": template}
+        ])))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let how = How::default();
+
+    // When
+    let with_system = TaskChat::with_message(Message::user("Hi"))
+        .with_default_system(&client, "pharia-1-llm-7b-control", &how)
+        .await
+        .unwrap();
+    let without_recommendation = TaskChat::with_message(Message::user("Hi"))
+        .with_default_system(&client, "luminous-base", &how)
+        .await
+        .unwrap();
+    let with_own_system =
+        TaskChat::with_messages(vec![Message::system("Be verbose."), Message::user("Hi")])
+            .with_default_system(&client, "pharia-1-llm-7b-control", &how)
+            .await
+            .unwrap();
+
+    // Then
+    assert_eq!(
+        with_system.messages,
+        vec![Message::system("Be brief."), Message::user("Hi")]
+    );
+    assert_eq!(without_recommendation.messages, vec![Message::user("Hi")]);
+    assert_eq!(
+        with_own_system.messages,
+        vec![Message::system("Be verbose."), Message::user("Hi")]
+    );
+}
+
+#[tokio::test]
+async fn additional_query_params_are_sent() {
+    // Given
+    let mock_server = MockServer::start().await;
+    let answer = r#"{"model_version":"2021-12","completions":[{"completion":"\n","finish_reason":"maximum_tokens"}],"num_tokens_prompt_total":5,"num_tokens_generated":1}"#;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .and(query_param("nice", "true"))
+        .and(query_param("gateway_feature", "on"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(answer, "application/json"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let how = How {
+        be_nice: true,
+        query_params: vec![("gateway_feature".to_owned(), "on".to_owned())],
+        ..Default::default()
+    };
+
+    // When
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    client
+        .completion(&task, "luminous-base", &how)
+        .await
+        .unwrap();
+
+    // Then the mock server verifies the query parameters on drop
+}
+
+/// Serves completions over keep alive connections. Returns the base URL and the number of
+/// connections accepted so far.
+async fn start_keep_alive_completion_server() -> (String, Arc<AtomicUsize>) {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    let port = start_raw_server(move |stream| {
+        accepted.fetch_add(1, Ordering::SeqCst);
+        async move {
+            let mut stream = BufReader::new(stream);
+            // Answer every request on this connection, until the client closes it
+            while read_request(&mut stream).await.is_some() {
+                // Give concurrent requests the chance to open connections of their own
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                let body = r#"{"model_version":"2021-12","completions":[{"completion":"\n","finish_reason":"maximum_tokens"}],"num_tokens_prompt_total":5,"num_tokens_generated":1}"#;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        }
+    })
+    .await;
+    (format!("http://127.0.0.1:{port}"), connections)
+}
+
+/// Sends three waves of eight concurrent completions and returns the number of connections used.
+async fn connections_for_concurrent_completions(
+    client: Client,
+    connections: &AtomicUsize,
+) -> usize {
+    let tasks: Vec<_> = (0..8)
+        .map(|_| TaskCompletion::from_text("Hello,").with_maximum_tokens(1))
+        .collect();
+    for _ in 0..3 {
+        let results = client
+            .completions_batch(&tasks, "luminous-base", &How::default(), 8)
+            .await;
+        assert!(results.iter().all(Result::is_ok));
+    }
+    connections.load(Ordering::SeqCst)
+}
+
+#[tokio::test]
+async fn concurrent_requests_reuse_connections() {
+    // Given
+    let (base, connections) = start_keep_alive_completion_server().await;
+    let client = Client::with_auth(base, "dummy-token")
+        .unwrap()
+        .with_pool_max_idle_per_host(8)
+        .unwrap();
+
+    // When
+    let used = connections_for_concurrent_completions(client, &connections).await;
+
+    // Then later waves reuse the connections of the first one
+    assert!(used <= 8, "{used} connections used for 24 requests");
+}
+
+#[tokio::test]
+async fn connections_are_not_reused_without_idle_pool() {
+    // Given
+    let (base, connections) = start_keep_alive_completion_server().await;
+    let client = Client::with_auth(base, "dummy-token")
+        .unwrap()
+        .with_pool_max_idle_per_host(0)
+        .unwrap();
+
+    // When
+    let used = connections_for_concurrent_completions(client, &connections).await;
+
+    // Then
+    assert_eq!(used, 24);
+}
+
+async fn model_settings_server() -> MockServer {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/model-settings"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+            {"name": "luminous-base", "multimodal": false},
+            {"name": "luminous-extended", "multimodal": true}
+        ])))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    mock_server
+}
+
+fn prompt_with_images(images: usize) -> Prompt<'static> {
+    let mut items = vec![Modality::from_text("Describe:")];
+    items.extend((0..images).map(|_| Modality::Image {
+        data: "aW1hZ2U=".into(),
+    }));
+    Prompt::from_vec(items)
+}
+
+#[tokio::test]
+async fn image_prompt_is_rejected_for_text_only_model() {
+    // Given
+    let mock_server = model_settings_server().await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let how = How::default();
+
+    // When
+    let text_only = client
+        .validate_prompt("luminous-base", &prompt_with_images(0), None, &how)
+        .await;
+    let with_image = client
+        .validate_prompt("luminous-base", &prompt_with_images(1), None, &how)
+        .await;
+
+    // Then the settings are fetched only once
+    assert!(text_only.is_ok());
+    assert!(matches!(with_image, Err(Error::InvalidPrompt { .. })));
+}
+
+#[tokio::test]
+async fn prompt_exceeding_image_limit_is_rejected() {
+    // Given
+    let mock_server = model_settings_server().await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let how = How::default();
+
+    // When validating against a limit of one image
+    let one_image = client
+        .validate_prompt("luminous-extended", &prompt_with_images(1), Some(1), &how)
+        .await;
+    let two_images = client
+        .validate_prompt("luminous-extended", &prompt_with_images(2), Some(1), &how)
+        .await;
+
+    // Then
+    assert!(one_image.is_ok());
+    assert!(matches!(two_images, Err(Error::InvalidPrompt { .. })));
+}
+
+#[tokio::test]
+async fn disabled_timeout_waits_for_long_running_request() {
+    // Given a client with a default timeout shorter than the response time
+    let mock_server = MockServer::start().await;
+    let response_time = Duration::from_millis(100);
+    let answer = r#"{"model_version":"2021-12","completions":[{"completion":"\n","finish_reason":"maximum_tokens"}],"num_tokens_prompt_total":5,"num_tokens_generated":1}"#;
+    Mock::given(any())
+        .respond_with(
+            ResponseTemplate::new(StatusCode::OK)
+                .set_body_string(answer)
+                .set_delay(response_time),
+        )
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token")
+        .unwrap()
+        .with_timeout(response_time / 10);
+
+    // When disabling the timeout for the request
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    let how = How {
+        client_timeout: ClientTimeout::None,
+        ..Default::default()
+    };
+    let response = client.completion(&task, "luminous-base", &how).await;
+
+    // Then
+    assert_eq!(response.unwrap().completion, "\n");
+}
+
+#[tokio::test]
+async fn html_success_body_is_reported_with_its_text() {
+    // Given a gateway answering with its login page
+    let mock_server = MockServer::start().await;
+    let login_page = "<html><body>Please log in</body></html>";
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(login_page, "text/html"))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+
+    // When
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    let error = client
+        .completion(&task, "luminous-base", &How::default())
+        .await
+        .unwrap_err();
+
+    // Then
+    assert!(matches!(error, Error::Decode { body, .. } if body == login_page));
+}
+
+#[tokio::test]
+async fn stalled_stream_yields_idle_timeout() {
+    // Given a server opening the stream, but stalling before the first delta
+    let base = start_delayed_chat_stream_server(&["Hello"], Duration::from_secs(10)).await;
+    let client = Client::with_auth(base, "dummy-token").unwrap();
+    let idle_timeout = Duration::from_millis(100);
+    let how = How {
+        stream_idle_timeout: Some(idle_timeout),
+        ..Default::default()
+    };
+
+    // When
+    let task = TaskChat::with_message(Message::user("Hi"));
+    let mut stream = client
+        .stream_chat(&task, "pharia-1-llm-7b-control", &how)
+        .await
+        .unwrap();
+    let first = stream.next().await;
+    let second = stream.next().await;
+
+    // Then
+    assert!(
+        matches!(first, Some(Err(Error::StreamIdleTimeout(timeout))) if timeout == idle_timeout)
+    );
+    assert!(second.is_none());
+}
+
+#[tokio::test]
+async fn chat_with_completion_only_model() {
+    // Given a model which only offers completions
+    let mock_server = MockServer::start().await;
+    // Chat template of Llama 3, as found in its tokenizer config
+    let template = "{% set loop_messages = messages %}{% for message in loop_messages %}{% set content = '<|start_header_id|>' + message['role'] + '<|end_header_id|>\n\n'+ message['content'] | trim + '<|eot_id|>' %}{% if loop.index0 == 0 %}{% set content = bos_token + content %}{% endif %}{{ content }}{% endfor %}{% if add_generation_prompt %}{{ '<|start_header_id|>assistant<|end_header_id|>\n\n' }}{% endif %}";
+    Mock::given(method("GET"))
+        .and(path("/model-settings"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+            {"name": "luminous-base", "prompt_template": template}
+        ])))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .and(body_partial_json(serde_json::json!({
+            "prompt": [{"type": "text", "data": "<|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n"}],
+            "maximum_tokens": 5
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "model_version": "2021-12",
+            "completions": [{"completion": "Hello!", "finish_reason": "end_of_text"}],
+            "num_tokens_prompt_total": 6,
+            "num_tokens_generated": 2
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+
+    // When
+    let task = TaskChat::with_message(Message::user("Hi")).with_maximum_tokens(5);
+    let output = client
+        .chat_via_completion("luminous-base", &task, &How::default())
+        .await
+        .unwrap();
+
+    // Then
+    assert_eq!(output.message, Message::assistant("Hello!"));
+    assert_eq!(output.finish_reason, "stop");
+    assert_eq!(output.usage.prompt_tokens, 6);
+    assert_eq!(output.usage.completion_tokens, 2);
+}
+
+/// Streams "Hello there" as first chat delta and drops the connection. Once reconnected, streams
+/// " world" and completes the stream. Serves a tokenizer treating each word as one token, too.
+/// Returns the base URL and the bodies of the chat requests received.
+async fn start_disconnecting_chat_stream_server() -> (String, Arc<Mutex<Vec<String>>>) {
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let received = requests.clone();
+    let port = start_raw_server(move |stream| {
+        let received = received.clone();
+        async move {
+            let mut stream = BufReader::new(stream);
+            let Some((request_line, body)) = read_request(&mut stream).await else {
+                return;
+            };
+            if request_line.starts_with("GET") {
+                let tokenizer = word_level_tokenizer_json(&["Hello", "there", "world"]);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{tokenizer}",
+                    tokenizer.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+                return;
+            }
+            let reconnected = {
+                let mut received = received.lock().unwrap();
+                received.push(body);
+                received.len() > 1
+            };
+            let header = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n";
+            stream.write_all(header.as_bytes()).await.unwrap();
+            let delta = if reconnected { " world" } else { "Hello there" };
+            let event = format!(
+                "data: {{\"choices\":[{{\"finish_reason\":null,\"delta\":{{\"content\":\"{delta}\"}}}}]}}\n\n"
+            );
+            stream.write_all(http_chunk(&event).as_bytes()).await.unwrap();
+            if reconnected {
+                stream.write_all(b"0\r\n\r\n").await.unwrap();
+            }
+            // Without the last chunk, closing the connection interrupts the stream
+            stream.shutdown().await.unwrap();
+        }
+    })
+    .await;
+    (format!("http://127.0.0.1:{port}"), requests)
+}
+
+#[tokio::test]
+async fn stream_chat_reconnects_after_disconnect() {
+    // Given
+    let (base, requests) = start_disconnecting_chat_stream_server().await;
+    let client = Client::with_auth(base, "dummy-token").unwrap();
+
+    // When
+    let task = TaskChat::with_message(Message::user("Hi"));
+    let how = How::default();
+    let chunks: Vec<_> = client
+        .stream_chat_with_reconnect(&task, "pharia-1-llm-7b-control", &how, 1)
+        .collect()
+        .await;
+
+    // Then the stream continues after the disconnect
+    let content: String = chunks
+        .into_iter()
+        .map(|chunk| chunk.unwrap().delta.content)
+        .collect();
+    assert_eq!(content, "Hello there world");
+    // And the answer received so far is sent as context
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    let reconnect: serde_json::Value = serde_json::from_str(&requests[1]).unwrap();
+    assert_eq!(
+        reconnect["messages"][1],
+        serde_json::json!({"role": "assistant", "content": "Hello there"})
+    );
+}
+
+#[tokio::test]
+async fn stream_chat_reports_disconnect_without_reconnects() {
+    // Given
+    let (base, _requests) = start_disconnecting_chat_stream_server().await;
+    let client = Client::with_auth(base, "dummy-token").unwrap();
+
+    // When
+    let task = TaskChat::with_message(Message::user("Hi"));
+    let how = How::default();
+    let chunks: Vec<_> = client
+        .stream_chat_with_reconnect(&task, "pharia-1-llm-7b-control", &how, 0)
+        .collect()
+        .await;
+
+    // Then
+    assert_eq!(chunks.len(), 2);
+    assert!(chunks[0].is_ok());
+    assert!(matches!(chunks[1], Err(Error::Other(_))));
+}
+
+#[tokio::test]
+async fn stream_chat_reconnect_keeps_tool_results() {
+    // Given
+    let (base, requests) = start_disconnecting_chat_stream_server().await;
+    let client = Client::with_auth(base, "dummy-token").unwrap();
+
+    // When reconnecting a chat which answers a tool call
+    let task = TaskChat::with_messages(vec![
+        Message::user("How warm is it in Berlin?"),
+        Message::tool_result("call_42", r#"{"temperature": 21}"#),
+    ]);
+    let how = How::default();
+    let _: Vec<_> = client
+        .stream_chat_with_reconnect(&task, "pharia-1-llm-7b-control", &how, 1)
+        .collect()
+        .await;
+
+    // Then the tool result still refers to its call
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    let reconnect: serde_json::Value = serde_json::from_str(&requests[1]).unwrap();
+    assert_eq!(
+        reconnect["messages"][1],
+        serde_json::json!({
+            "role": "tool",
+            "content": r#"{"temperature": 21}"#,
+            "tool_call_id": "call_42"
+        })
+    );
+    assert_eq!(
+        reconnect["messages"][2],
+        serde_json::json!({"role": "assistant", "content": "Hello there"})
+    );
+}
+
+#[tokio::test]
+async fn stream_chat_reconnect_deducts_received_tokens_from_maximum() {
+    // Given
+    let (base, requests) = start_disconnecting_chat_stream_server().await;
+    let client = Client::with_auth(base, "dummy-token").unwrap();
+
+    // When
+    let task = TaskChat::with_message(Message::user("Hi")).with_maximum_tokens(10);
+    let how = How::default();
+    let _: Vec<_> = client
+        .stream_chat_with_reconnect(&task, "pharia-1-llm-7b-control", &how, 1)
+        .collect()
+        .await;
+
+    // Then both tokens of the chunk received before the disconnect are no longer available
+    let requests = requests.lock().unwrap();
+    let reconnect: serde_json::Value = serde_json::from_str(&requests[1]).unwrap();
+    assert_eq!(reconnect["max_tokens"], 8);
+}
+
+#[tokio::test]
+async fn stream_chat_does_not_reconnect_without_tokens_left() {
+    // Given
+    let (base, requests) = start_disconnecting_chat_stream_server().await;
+    let client = Client::with_auth(base, "dummy-token").unwrap();
+
+    // When the answer received before the disconnect exhausts the maximum tokens
+    let task = TaskChat::with_message(Message::user("Hi")).with_maximum_tokens(2);
+    let how = How::default();
+    let chunks: Vec<_> = client
+        .stream_chat_with_reconnect(&task, "pharia-1-llm-7b-control", &how, 1)
+        .collect()
+        .await;
+
+    // Then the disconnect is reported instead of asking for more tokens
+    assert_eq!(requests.lock().unwrap().len(), 1);
+    assert_eq!(chunks.len(), 2);
+    assert!(matches!(chunks[1], Err(Error::Other(_))));
+}
+
+#[tokio::test]
+async fn stream_chat_does_not_reconnect_on_invalid_event() {
+    // Given a stream with an event which can not be parsed
+    let mock_server = MockServer::start().await;
+    let events =
+        "data: {\"choices\":[{\"finish_reason\":null,\"delta\":{\"content\":\"Hello\"}}]}\n\n\
+        data: {\"garbage\n\n";
+    Mock::given(method("POST"))
+        .and(path("/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(events, "text/event-stream"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+
+    // When
+    let task = TaskChat::with_message(Message::user("Hi"));
+    let how = How::default();
+    let chunks: Vec<_> = client
+        .stream_chat_with_reconnect(&task, "pharia-1-llm-7b-control", &how, 3)
+        .collect()
+        .await;
+
+    // Then the error is reported instead of sending the chat again
+    assert_eq!(chunks.len(), 2);
+    assert!(matches!(chunks[1], Err(Error::InvalidStream { .. })));
+}
+
+/// Matches requests whose gzip compressed body decompresses to the expected JSON.
+struct GzipJsonBody(serde_json::Value);
+
+impl Match for GzipJsonBody {
+    fn matches(&self, request: &Request) -> bool {
+        let mut body = String::new();
+        if GzDecoder::new(request.body.as_slice())
+            .read_to_string(&mut body)
+            .is_err()
+        {
+            return false;
+        }
+        serde_json::from_str::<serde_json::Value>(&body).is_ok_and(|body| body == self.0)
+    }
+}
+
+#[tokio::test]
+async fn request_body_is_compressed_if_requested() {
+    // Given
+    let mock_server = MockServer::start().await;
+    let answer = r#"{"model_version":"2021-12","completions":[{"completion":"\n","finish_reason":"maximum_tokens"}],"num_tokens_prompt_total":5,"num_tokens_generated":1}"#;
+    let expected_body = serde_json::json!({
+        "model": "luminous-base",
+        "prompt": [{"type": "text", "data": "Hello,"}],
+        "maximum_tokens": 1
+    });
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .and(header("Content-Encoding", "gzip"))
+        .and(GzipJsonBody(expected_body))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(answer, "application/json"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let how = How {
+        compress_request: true,
+        ..Default::default()
+    };
+
+    // When
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    let response = client.completion(&task, "luminous-base", &how).await;
+
+    // Then
+    assert_eq!(response.unwrap().completion, "\n");
+}
+
+#[tokio::test]
+async fn continue_truncated_completion() {
+    // Given a completion which has been cut off by the maximum number of tokens
+    let mock_server = MockServer::start().await;
+    let first_answer = r#"{"model_version":"2021-12","completions":[{"completion":" keeps the","finish_reason":"maximum_tokens"}],"num_tokens_prompt_total":4,"num_tokens_generated":2}"#;
+    let second_answer = r#"{"model_version":"2021-12","completions":[{"completion":" doctor away","finish_reason":"end_of_text"}],"num_tokens_prompt_total":6,"num_tokens_generated":2}"#;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .and(body_partial_json(serde_json::json!({
+            "prompt": [{"type": "text", "data": "An apple a day"}]
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(first_answer, "application/json"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .and(body_partial_json(serde_json::json!({
+            "prompt": [{"type": "text", "data": "An apple a day keeps the"}],
+            "maximum_tokens": 2
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(second_answer, "application/json"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let task = TaskCompletion::from_text("An apple a day").with_maximum_tokens(2);
+    let how = How::default();
+    let prev = client
+        .completion(&task, "luminous-base", &how)
+        .await
+        .unwrap();
+    assert!(prev.is_truncated());
+
+    // When
+    let output = client
+        .continue_completion(&prev, &task, "luminous-base", &how)
+        .await
+        .unwrap();
+
+    // Then
+    assert_eq!(output.completion, " keeps the doctor away");
+    assert!(!output.is_truncated());
+    assert_eq!(output.usage.prompt_tokens, 10);
+    assert_eq!(output.usage.completion_tokens, 4);
+}
+
+#[tokio::test]
+async fn continue_echoed_completion_does_not_repeat_prompt() {
+    // Given a truncated completion which echoes the prompt
+    let mock_server = MockServer::start().await;
+    let answer = r#"{"model_version":"2021-12","completions":[{"completion":" doctor away","finish_reason":"end_of_text"}],"num_tokens_prompt_total":6,"num_tokens_generated":2}"#;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .and(body_partial_json(serde_json::json!({
+            "prompt": [{"type": "text", "data": "An apple a day keeps the"}]
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(answer, "application/json"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let task = TaskCompletion::from_text("An apple a day")
+        .with_maximum_tokens(2)
+        .with_echo();
+    let prev = CompletionOutput {
+        completion: "An apple a day keeps the".to_owned(),
+        finish_reason: "maximum_tokens".to_owned(),
+        logprobs: Vec::new(),
+        token_ids: Vec::new(),
+        usage: Usage {
+            prompt_tokens: 4,
+            completion_tokens: 2,
+        },
+    };
+
+    // When
+    let output = client
+        .continue_completion(&prev, &task, "luminous-base", &How::default())
+        .await
+        .unwrap();
+
+    // Then
+    assert_eq!(output.completion, "An apple a day keeps the doctor away");
+}
+
+#[tokio::test]
+async fn continue_echoed_completion_of_image_prompt_is_rejected() {
+    // Given
+    let client = Client::with_auth("http://127.0.0.1:1", "dummy-token").unwrap();
+    let task = TaskCompletion {
+        prompt: Prompt::from_vec(vec![
+            Modality::Image {
+                data: "aW1hZ2U=".into(),
+            },
+            Modality::from_text("A picture of"),
+        ]),
+        ..TaskCompletion::from_text("").with_echo()
+    };
+    let prev = CompletionOutput {
+        completion: "<image>A picture of a".to_owned(),
+        finish_reason: "maximum_tokens".to_owned(),
+        logprobs: Vec::new(),
+        token_ids: Vec::new(),
+        usage: Usage {
+            prompt_tokens: 4,
+            completion_tokens: 2,
+        },
+    };
+
+    // When
+    let result = client
+        .continue_completion(&prev, &task, "luminous-base", &How::default())
+        .await;
+
+    // Then
+    assert!(matches!(result, Err(Error::InvalidPrompt { .. })));
+}
+
+#[tokio::test]
+async fn trace_context_is_propagated() {
+    // Given
+    let mock_server = MockServer::start().await;
+    let answer = r#"{"model_version":"2021-12","completions":[{"completion":"\n","finish_reason":"maximum_tokens"}],"num_tokens_prompt_total":5,"num_tokens_generated":1}"#;
+    Mock::given(method("POST"))
+        .and(path("/complete"))
+        .and(header(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ))
+        .and(header("tracestate", "congo=t61rcWkgMzE"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(answer, "application/json"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let how = How {
+        trace_context: Some(
+            TraceContext::new(0x4bf92f3577b34da6a3ce929d0e0e4736, 0xf067aa0ba902b7, true)
+                .with_state("congo=t61rcWkgMzE"),
+        ),
+        ..Default::default()
+    };
+
+    // When
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    let response = client.completion(&task, "luminous-base", &how).await;
+
+    // Then
+    assert!(response.is_ok());
+}
+
+#[tokio::test]
+async fn new_trace_sends_traceparent() {
+    // Given
+    let mock_server = MockServer::start().await;
+    let answer = r#"{"model_version":"2021-12","completions":[{"completion":"\n","finish_reason":"maximum_tokens"}],"num_tokens_prompt_total":5,"num_tokens_generated":1}"#;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(answer, "application/json"))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let how = How::default().with_new_trace();
+
+    // When
+    let task = TaskCompletion::from_text("Hello,").with_maximum_tokens(1);
+    client
+        .completion(&task, "luminous-base", &how)
+        .await
+        .unwrap();
+
+    // Then
+    let requests = mock_server.received_requests().await.unwrap();
+    let traceparent = requests[0].headers.get("traceparent").unwrap();
+    assert_eq!(
+        traceparent.to_str().unwrap(),
+        how.trace_context.unwrap().traceparent()
+    );
+}
+
+#[tokio::test]
+async fn chat_stream_ends_cleanly_with_and_without_done_sentinel() {
+    let deltas = concat!(
+        "data: {\"choices\":[{\"finish_reason\":null,\"delta\":{\"role\":\"assistant\",\"content\":\"Hello\"}}]}\n\n",
+        "data: {\"choices\":[{\"finish_reason\":\"stop\",\"delta\":{\"content\":\"!\"}}]}\n\n",
+    );
+    for events in [
+        format!("{deltas}data: [DONE]\n\n"),
+        // Without trailing boundary
+        format!("{deltas}data: [DONE]"),
+        deltas.to_owned(),
+    ] {
+        // Given
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(events, "text/event-stream"))
+            .mount(&mock_server)
+            .await;
+        let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+        let task = TaskChat::with_message(Message::user("Hello"));
+
+        // When
+        let chunks = client
+            .stream_chat(&task, "pharia-1-llm-7b-control", &How::default())
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        // Then every delta arrives and there is no error
+        let contents: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| chunk.unwrap().delta.content)
+            .collect();
+        assert_eq!(contents, ["Hello", "!"]);
+    }
+}
+
+#[tokio::test]
+async fn stream_chat_with_final_assembles_deltas() {
+    // Given
+    let mock_server = MockServer::start().await;
+    let events = concat!(
+        "data: {\"choices\":[{\"finish_reason\":null,\"delta\":{\"role\":\"assistant\",\"content\":\"\"}}]}\n\n",
+        "data: {\"choices\":[{\"finish_reason\":null,\"delta\":{\"content\":\"Hello\"}}]}\n\n",
+        "data: {\"choices\":[{\"finish_reason\":null,\"delta\":{\"content\":\", world\"}}]}\n\n",
+        "data: {\"choices\":[{\"finish_reason\":\"stop\",\"delta\":{\"content\":\"!\"}}]}\n\n",
+    );
+    Mock::given(method("POST"))
+        .and(path("/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(events, "text/event-stream"))
+        .mount(&mock_server)
+        .await;
+    let client = Client::with_auth(mock_server.uri(), "dummy-token").unwrap();
+    let task = TaskChat::with_message(Message::user("Hello"));
+
+    // When
+    let (stream, output) = client
+        .stream_chat_with_final(&task, "pharia-1-llm-7b-control", &How::default())
+        .await
+        .unwrap();
+    let deltas: String = stream
+        .map(|chunk| chunk.unwrap().delta.content)
+        .collect::<Vec<_>>()
+        .await
+        .concat();
+    let output = output.await.unwrap();
+
+    // Then
+    assert_eq!(deltas, "Hello, world!");
+    assert_eq!(output.message.content, deltas);
+    assert_eq!(output.message.role, "assistant");
+    assert_eq!(output.finish_reason, "stop");
+}